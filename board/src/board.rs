@@ -1,18 +1,30 @@
+use controller::board::rp::persist::{self, PersistSignal, PositionFlash};
 use controller::board::rp::utils::counted_sqr_wav_pio::{CountedSqrWav, CountedSqrWavProgram};
-use controller::board::rp::{bind_endstops, Board, DriverPins};
-use controller::board::ControlLoopInvoke;
+#[cfg(feature = "supply-sense")]
+use controller::board::rp::AdcSupply;
+use controller::board::rp::{Board, DriverBus, DriverPins, MotionHost, SharedAdc};
+use controller::board::tmc2209_uart::Tmc2209;
+#[cfg(feature = "supply-sense")]
+use controller::board::SupplyThresholds;
+use controller::board::{
+    BoardSensors, ControlLoopInvoke, DriverAddress, EndstopConfig, PowerBudget, SensorReading,
+    SensorUnit, ThermalThresholds, Thermometer, MAX_SENSORS,
+};
 #[cfg(feature = "host-uart")]
 use controller::rpc::SerialRpcHandle;
 #[cfg(feature = "host-usb")]
 use controller::rpc::UsbRpcHandle;
 use controller::static_buffer;
-use defmt::{debug, error};
+use core::cell::RefCell;
+use defmt::error;
 use embassy_executor::Spawner;
 use embassy_rp::adc::{self, Adc};
 use embassy_rp::bind_interrupts;
 use embassy_rp::clocks::ClockConfig;
 use embassy_rp::config::Config as McuConfig;
-use embassy_rp::gpio::{Input, Level, Output, Pull};
+use embassy_rp::gpio::{Flex, Level, Output, Pull};
+#[cfg(feature = "dual-core")]
+use embassy_rp::peripherals::CORE1;
 use embassy_rp::peripherals::{PIO0, UART0, UART1, USB};
 use embassy_rp::pio::{InterruptHandler as PioInterruptHandler, Pio};
 use embassy_rp::uart::{self, BufferedInterruptHandler, BufferedUart};
@@ -20,12 +32,17 @@ use embassy_rp::uart::{self, BufferedInterruptHandler, BufferedUart};
 use embassy_rp::usb::Driver;
 use embassy_rp::usb::InterruptHandler as UsbInterruptHandler;
 use embassy_rp::watchdog::Watchdog;
+#[cfg(feature = "dual-core")]
+use embassy_rp::Peri;
 use embassy_rp::Peripherals;
 use embassy_time::{Duration, Instant};
 #[cfg(feature = "host-usb")]
 use embassy_usb::UsbDevice;
+use heapless::Vec;
 use static_cell::StaticCell;
-use thermistor::NtcThermistor;
+use thermistor::{
+    oversample, Divider, FixedTable, Plausibility, Smoothing, TemperatureFilter, Q16_ONE,
+};
 
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => PioInterruptHandler<PIO0>;
@@ -44,9 +61,24 @@ static_buffer!(HOST_BUFFER_RX: 2048);
 static PERIPHERALS: StaticCell<Peripherals> = StaticCell::new();
 static PIO0: StaticCell<Pio<PIO0>> = StaticCell::new();
 static PROG: StaticCell<CountedSqrWavProgram<PIO0>> = StaticCell::new();
+/// Shared by the thermistor, the MCU's temperature sensor and the supply sense
+static ADC: StaticCell<SharedAdc<'static>> = StaticCell::new();
+static PERSIST: PersistSignal<4> = PersistSignal::new();
 
-pub trait BoardInitialize {
-    fn init(spawner: Spawner) -> Self;
+/// The board's 2MB flash, the last sector of which `memory.x` sets aside for the positions
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+pub trait BoardInitialize: Sized {
+    /// Initializes the board, setting aside what belongs to the core running the motion task
+    fn init(spawner: Spawner) -> (Self, MotionCore);
+}
+
+/// Peripherals for whichever core runs the motion task, and the endstops it binds on its executor
+pub struct MotionCore {
+    #[cfg(feature = "dual-core")]
+    pub core1: Peri<'static, CORE1>,
+    pub endstops: [Flex<'static>; 4],
+    pub endstop_configs: [EndstopConfig; 4],
 }
 
 #[cfg(feature = "host-uart")]
@@ -55,14 +87,17 @@ pub type HD = SerialRpcHandle<512, BufferedUart>;
 pub type HD = UsbRpcHandle<2048, Driver<'static, USB>>;
 
 pub struct BttSkrPicoV1_0 {
-    thermistor: NtcThermistor,
-    adc: Adc<'static, adc::Blocking>,
+    adc: &'static SharedAdc<'static>,
     thermistor_pin: adc::Channel<'static>,
-    last_thermal: Instant,
+    thermistor_filter: TemperatureFilter<5>,
+    last_thermistor_sample: Instant,
+    mcu_temp_sensor: adc::Channel<'static>,
 }
 
-impl BoardInitialize for Board<'static, 4, BufferedUart, HD, BttSkrPicoV1_0> {
-    fn init(spawner: Spawner) -> Self {
+pub type SkrPico = Board<'static, 4, [BufferedUart; 1], HD, BttSkrPicoV1_0, Tmc2209>;
+
+impl BoardInitialize for SkrPico {
+    fn init(spawner: Spawner) -> (Self, MotionCore) {
         // Explicitly set to 120MHz so the clock division for PIO works correctly
         let mut config = McuConfig::default();
         config.clocks = ClockConfig::system_freq(120_000_000).unwrap();
@@ -114,15 +149,18 @@ impl BoardInitialize for Board<'static, 4, BufferedUart, HD, BttSkrPicoV1_0> {
             host_rpc
         };
 
-        bind_endstops(
-            spawner,
-            [
-                Input::new(p.PIN_4.reborrow(), Pull::Down),
-                Input::new(p.PIN_25.reborrow(), Pull::Down),
-                Input::new(p.PIN_3.reborrow(), Pull::Down),
-                Input::new(p.PIN_16.reborrow(), Pull::Down),
+        let motion_core = MotionCore {
+            #[cfg(feature = "dual-core")]
+            core1: p.CORE1.reborrow(),
+            endstops: [
+                Flex::new(p.PIN_4.reborrow()),
+                Flex::new(p.PIN_25.reborrow()),
+                Flex::new(p.PIN_3.reborrow()),
+                Flex::new(p.PIN_16.reborrow()),
             ],
-        );
+            // Active-high switches, pulled down on the board
+            endstop_configs: [EndstopConfig::default(); 4],
+        };
         let drivers = [
             DriverPins {
                 enable: Output::new(p.PIN_12.reborrow(), Level::High),
@@ -146,79 +184,179 @@ impl BoardInitialize for Board<'static, 4, BufferedUart, HD, BttSkrPicoV1_0> {
             },
         ];
 
+        // Taken before core1 is started, so it isn't running from flash as the sector is erased
+        let mut flash = PositionFlash::<FLASH_SIZE>::new(p.FLASH.reborrow());
+        let persisted = flash.take();
+        let _ = spawner.spawn(persist_task(flash).unwrap());
+
+        let adc: &SharedAdc = ADC.init(SharedAdc::new(RefCell::new(Adc::new_blocking(
+            p.ADC.reborrow(),
+            adc::Config::default(),
+        ))));
+
+        // VIN divided down 100K over 10K into the THB port, in place of a bed thermistor.
+        // The port's own 4.7K pull-up skews the divider, so it has to be removed from the board first.
+        #[cfg(feature = "supply-sense")]
+        let _ = spawner.spawn(
+            supply_task(AdcSupply {
+                adc,
+                channel: adc::Channel::new_pin(p.PIN_26.reborrow(), Pull::None),
+                r_top: 100_000,
+                r_bottom: 10_000,
+                thresholds: SupplyThresholds::for_nominal(24_000),
+            })
+            .unwrap(),
+        );
+
         let mut wdr = Watchdog::new(p.WATCHDOG.reborrow());
         #[cfg(test)]
         wdr.pause_on_debug(true);
         wdr.start(Duration::from_secs(2));
 
-        Self {
-            drivers,
-            driver_serial,
+        let board = Self {
+            motion: MotionHost {
+                drivers,
+                wdr,
+                pio0_0: Some(pio0_0),
+                pio0_1: Some(pio0_1),
+                pio0_2: Some(pio0_2),
+                pio0_3: Some(pio0_3),
+                // Nothing is known of the supply, so motor starts are merely staggered
+                power_budget: PowerBudget::default(),
+                persisted,
+                persist: Some(&PERSIST),
+            },
+            // All four drivers share UART1, addressed through their MS1/MS2 straps.
+            //
+            // Boards with a UART per driver would list each bus here, wrapping hardware UARTs and
+            // `PioUartBus`es in `EitherSerial` where they're mixed, and map them with
+            // `DriverAddress::dedicated_buses()`
+            driver_bus: DriverBus {
+                buses: [driver_serial],
+                addresses: DriverAddress::shared_bus(),
+                family: Tmc2209,
+            },
             host_rpc,
-            wdr,
             board_state: BttSkrPicoV1_0 {
-                thermistor: thermistor::ERT_J1VGXXA, // ERT-J1VG103FA from PBLS-1.0/27 EDLC (Supercapacitor)
-                adc: Adc::new_blocking(p.ADC.reborrow(), adc::Config::default()),
+                adc,
                 thermistor_pin: adc::Channel::new_pin(p.PIN_27.reborrow(), Pull::None),
-                last_thermal: Instant::now(),
+                thermistor_filter: TemperatureFilter::new(
+                    Smoothing::Median,
+                    THERMISTOR_PLAUSIBILITY,
+                ),
+                last_thermistor_sample: Instant::now(),
+                mcu_temp_sensor: adc::Channel::new_temp_sensor(p.ADC_TEMP_SENSOR.reborrow()),
             },
-            pio0_0: Some(pio0_0),
-            pio0_1: Some(pio0_1),
-            pio0_2: Some(pio0_2),
-            pio0_3: Some(pio0_3),
-        }
+        };
+
+        (board, motion_core)
     }
 }
 
-const THERMAL_REPORT_THRESHOLD: Duration = Duration::from_secs(60);
-
 impl ControlLoopInvoke for BttSkrPicoV1_0 {
-    async fn invoke(&mut self, _spawner: &mut Spawner) {
-        if Instant::now().duration_since(self.last_thermal) > THERMAL_REPORT_THRESHOLD {
-            self.last_thermal += THERMAL_REPORT_THRESHOLD;
+    async fn invoke(&mut self, _spawner: &mut Spawner) {}
+}
+
+impl Thermometer for BttSkrPicoV1_0 {
+    fn read_temp_celsius(&mut self) -> Option<f32> {
+        self.measure_temp()
+    }
 
-            let temp = self.measure_temp();
-            debug!("Thermistor is at {}C", temp);
+    /// Kept well clear of the supercapacitor's rating, as it ages quickly when run hot
+    fn thermal_thresholds(&self) -> ThermalThresholds {
+        ThermalThresholds {
+            warn_c: 45,
+            derate_c: 55,
+            shutdown_c: 65,
+            hysteresis_c: 5,
+            derate_percent: 50,
         }
     }
 }
 
+impl BoardSensors for BttSkrPicoV1_0 {
+    fn read_sensors(&mut self, readings: &mut Vec<SensorReading, MAX_SENSORS>) {
+        if let Some(temp) = self.measure_temp() {
+            let _ = readings.push(SensorReading {
+                name: "supercap",
+                value: temp,
+                unit: SensorUnit::Celsius,
+            });
+        }
+
+        let sensor = &mut self.mcu_temp_sensor;
+        if let Some(raw) = self
+            .adc
+            .lock(|adc| adc.borrow_mut().blocking_read(sensor).ok())
+        {
+            // From the RP2040 datasheet, the sensor reads 0.706V at 27C, falling 1.721mV per degree
+            let volts = raw as f32 * 3.3 / 4096.0;
+            let _ = readings.push(SensorReading {
+                name: "mcu",
+                value: 27.0 - (volts - 0.706) / 0.001721,
+                unit: SensorUnit::Celsius,
+            });
+        }
+    }
+}
+
+/// The thermistor port is pulled up by 4.7KOhms to the ADC's 3.3V reference,
+/// based on <https://github.com/bigtreetech/SKR-Pico/blob/master/Hardware/BTT%20SKR%20Pico%20V1.0-SCH.pdf>
+///
+/// From the RP2040 specifications, the ADC is 12-bit
+const THERMISTOR_DIVIDER: Divider = Divider::pull_up(4700., 12);
+/// ERT-J1VG103FA from PBLS-1.0/27 EDLC (Supercapacitor), tabulated so it's cheap enough to sample often
+static THERMISTOR: FixedTable<256> = FixedTable::new(&thermistor::ERT_J1VGXXA, &THERMISTOR_DIVIDER);
+
+/// The supercapacitor's thermal mass can't swing faster than this, anything more is noise on the ADC
+const THERMISTOR_PLAUSIBILITY: Plausibility = Plausibility {
+    min_celsius: -20.,
+    max_celsius: 100.,
+    max_rate_celsius_per_s: 1.,
+    noise_celsius: 0.5,
+    fault_after: 5,
+};
+
 impl BttSkrPicoV1_0 {
-    fn read_thermistor_voltage(&mut self) -> u16 {
-        match self.adc.blocking_read(&mut self.thermistor_pin) {
-            Ok(thermistor) => thermistor,
+    fn measure_temp(&mut self) -> Option<f32> {
+        let adc = self.adc;
+        let pin = &mut self.thermistor_pin;
+        let reading = match oversample(8, || {
+            adc.lock(|adc| adc.borrow_mut().blocking_read(pin).ok())
+        }) {
+            Some(reading) => reading,
+            None => {
+                error!("Failed to read thermistor");
+                return None;
+            }
+        };
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_thermistor_sample).as_millis() as u32;
+        self.last_thermistor_sample = now;
+
+        let temp = THERMISTOR
+            .get_temp_celsius_q16(reading)
+            .map(|temp| temp as f32 / Q16_ONE as f32);
+        match self.thermistor_filter.update(temp, elapsed) {
+            Ok(temp) => Some(temp),
             Err(e) => {
-                error!("Failed to read thermistor: {}", e);
-                1
+                error!("Thermistor is faulty: {}", e);
+                None
             }
         }
     }
+}
 
-    /// The standard voltage divider formula is $V_{R_2} = \frac{V_{src}R_2}{R_1 + R_2}$. Transposed,
-    /// $$
-    /// R_2 = \frac{V_{R_2}R_1}{V_{src} - V_{R_2}}
-    /// $$
-    ///
-    /// ADC measures voltage, and we want the resistance for our thermistor calculation, so let's move a few things...
-    ///
-    /// Mapping the thermistor ports to the standard voltage divider formula,
-    /// you'll see that the thermistor is in the position of $R_2$, and we read $V_{R_2}$ from ADC
-    /// based on <https://github.com/bigtreetech/SKR-Pico/blob/master/Hardware/BTT%20SKR%20Pico%20V1.0-SCH.pdf>
-    ///
-    /// Also from the same datasheet, $R_1$ is 4.7KOhms
-    ///
-    /// From the RP2040 specifications, the ADC is 12-bit (&therefore; value range $[0, 4096)$), which makes $V_{src} = 4095$
-    // The intermediates for the $R_2$ value should be on the scale of ~24 bit, scaling up to u32 to prevent overflows
-    // u32 maths is a lot faster than f32 non-hf maths
-    fn measure_temp(&mut self) -> f32 {
-        let v_src: u32 = 4095;
-        let r_1: u32 = 4700;
-        let v_r_2 = self.read_thermistor_voltage() as u32;
-
-        let r_2 = (v_r_2 * r_1) / (v_src - v_r_2);
-
-        self.thermistor.get_temp_celsius(r_2 as f32)
-    }
+#[embassy_executor::task]
+async fn persist_task(mut flash: PositionFlash<'static, FLASH_SIZE>) {
+    persist::run(&mut flash, &PERSIST).await;
+}
+
+#[cfg(feature = "supply-sense")]
+#[embassy_executor::task]
+async fn supply_task(mut sensor: AdcSupply<'static>) {
+    controller::supply::run(&mut sensor).await;
 }
 
 #[cfg(feature = "host-usb")]
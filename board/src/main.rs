@@ -3,16 +3,66 @@
 
 mod board;
 
-use crate::board::BoardInitialize;
-use controller::board::rp::Board;
-use controller::board::ConfigurableStepStickDriver;
+use crate::board::{BoardInitialize, MotionCore, SkrPico};
+use controller::board::rp::bind_endstops;
+use controller::board::SplitBoard;
+use controller::{DriverMutex, SharedDrivers};
+#[cfg(feature = "dual-core")]
+use embassy_executor::Executor;
 use embassy_executor::Spawner;
+#[cfg(feature = "dual-core")]
+use embassy_rp::multicore::{spawn_core1, Stack};
+#[cfg(feature = "dual-core")]
+use static_cell::ConstStaticCell;
+use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
+type Motion = <SkrPico as SplitBoard>::Motion;
+type Host = <SkrPico as SplitBoard>::Host;
+type Drivers = <SkrPico as SplitBoard>::Drivers;
+
+static BOARD: StaticCell<SkrPico> = StaticCell::new();
+static DRIVER_BUS: StaticCell<DriverMutex<'static, Drivers>> = StaticCell::new();
+#[cfg(feature = "dual-core")]
+static CORE1_STACK: ConstStaticCell<Stack<4096>> = ConstStaticCell::new(Stack::new());
+#[cfg(feature = "dual-core")]
+static CORE1_EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
 #[embassy_executor::main]
-async fn main(spawner: Spawner) {
-    let mut board = Board::init(spawner);
-    board.configure_driver().await;
+async fn main(mut spawner: Spawner) {
+    let (board, motion_core) = SkrPico::init(spawner);
+    let board = BOARD.init(board);
+    let (motion, host, drivers, board_state) = board.split();
+    let drivers = DRIVER_BUS.init(DriverMutex::new(SharedDrivers::new(drivers)));
+
+    // Core0 is left with JSON parsing, the driver UART and thermal measurement,
+    // so neither a long packet nor a slow bus can delay a stop
+    #[cfg(feature = "dual-core")]
+    spawn_core1(motion_core.core1, CORE1_STACK.take(), move || {
+        let executor = CORE1_EXECUTOR.init(Executor::new());
+        executor.run(|spawner| start_motion(spawner, motion, motion_core))
+    });
+    #[cfg(not(feature = "dual-core"))]
+    start_motion(spawner, motion, motion_core);
+
+    let _ = spawner.spawn(host_task(host, drivers).unwrap());
+
+    controller::diagnostics::run(drivers, board_state, &mut spawner).await;
+}
+
+/// Binds the endstops and spawns the motion task, both on the executor of the core running the time-critical path
+fn start_motion(spawner: Spawner, motion: &'static mut Motion, motion_core: MotionCore) {
+    let halts = motion.step_halts();
+    bind_endstops(spawner, motion_core.endstops, motion_core.endstop_configs, halts);
+    let _ = spawner.spawn(motion_task(motion).unwrap());
+}
+
+#[embassy_executor::task]
+async fn motion_task(motion: &'static mut Motion) {
+    controller::motion::run(motion).await;
+}
 
-    controller::run(spawner, board).await;
+#[embassy_executor::task]
+async fn host_task(host: &'static mut Host, drivers: &'static DriverMutex<'static, Drivers>) {
+    controller::host::run(host, drivers).await;
 }
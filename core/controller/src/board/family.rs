@@ -0,0 +1,212 @@
+use crate::board::{
+    ConfigurableStepStickDriver, ConfigurableStepStickHost, DriverConfig,
+    DriverDiagnostics, DriverError, DriverRegisterAccess, DriverStatus,
+};
+#[cfg(feature = "stallguard")]
+use crate::board::StallGuard;
+use crate::UNCONFIGURED;
+use core::sync::atomic::Ordering;
+use defmt::*;
+
+/// Register-level behaviour of a line of stepper drivers, over the bus type `S`.
+///
+/// Implementations are stateless and selected per board through [`ConfigurableStepStickHost::Family`],
+/// the host traits are implemented on top of them for any board.
+#[allow(async_fn_in_trait)]
+pub trait DriverFamily<S> {
+    /// Programs and verifies the driver at `node` on the bus.
+    async fn configure(serial: &mut S, node: u8, config: &DriverConfig) -> Result<(), DriverError>;
+    async fn read_status(serial: &mut S, node: u8) -> DriverStatus;
+    async fn read_register(serial: &mut S, node: u8, reg: u8) -> Result<u32, DriverError>;
+    async fn write_register(
+        serial: &mut S,
+        node: u8,
+        reg: u8,
+        value: u32,
+        force: bool,
+    ) -> Result<(), DriverError>;
+
+    /// StallGuard threshold on the 8-bit scale of the TMC2209's SGTHRS, where higher is more sensitive
+    async fn set_sg_threshold(serial: &mut S, node: u8, sgthrs: u8);
+    /// Starts a StallGuard query, to be completed by [`DriverFamily::await_sg_result_halved`].
+    ///
+    /// Returns false if the request could not be sent.
+    async fn request_sg_result(serial: &mut S, node: u8) -> bool;
+    /// StallGuard result normalised to the 8-bit scale of the TMC2209's halved SG_RESULT,
+    /// `None` for drivers without StallGuard
+    async fn await_sg_result_halved(serial: &mut S, node: u8) -> Option<u8>;
+    /// Completes a query started by [`DriverFamily::request_sg_result`] with whether the driver sees a stall,
+    /// given the threshold `sgthrs` it was programmed with. `None` for drivers without StallGuard
+    async fn await_stall(serial: &mut S, node: u8, sgthrs: u8) -> Option<bool>;
+}
+
+impl<B, S, const N: usize> ConfigurableStepStickDriver<S, N> for B
+where
+    B: ConfigurableStepStickHost<N, DriverSerial = S>,
+{
+    async fn configure_driver(&mut self) {
+        for channel in 0..N as u8 {
+            if let Err(e) = self.configure_channel(channel, &DriverConfig::default()).await {
+                error!("Driver on channel {} could not be configured: {:?}", channel, e);
+            }
+        }
+    }
+
+    async fn configure_channel(
+        &mut self,
+        channel: u8,
+        config: &DriverConfig,
+    ) -> Result<(), DriverError> {
+        UNCONFIGURED.bit_set(channel as u32, Ordering::AcqRel);
+
+        let (serial, node) = self.driver_serial(channel);
+        B::Family::configure(serial, node, config).await?;
+
+        UNCONFIGURED.bit_clear(channel as u32, Ordering::AcqRel);
+        debug!("Driver on channel {} configured", channel);
+
+        Ok(())
+    }
+
+    async fn update_channel(
+        &mut self,
+        channel: u8,
+        config: &DriverConfig,
+    ) -> Result<(), DriverError> {
+        let (serial, node) = self.driver_serial(channel);
+        if let Err(e) = B::Family::configure(serial, node, config).await {
+            UNCONFIGURED.bit_set(channel as u32, Ordering::AcqRel);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
+
+impl<B, S, const N: usize> DriverDiagnostics<S, N> for B
+where
+    B: ConfigurableStepStickHost<N, DriverSerial = S>,
+{
+    async fn get_driver_status(&mut self, channel: u8) -> DriverStatus {
+        let (serial, node) = self.driver_serial(channel);
+        B::Family::read_status(serial, node).await
+    }
+}
+
+impl<B, S, const N: usize> DriverRegisterAccess<S, N> for B
+where
+    B: ConfigurableStepStickHost<N, DriverSerial = S>,
+{
+    async fn read_register(&mut self, channel: u8, reg: u8) -> Result<u32, DriverError> {
+        let (serial, node) = self.driver_serial(channel);
+        B::Family::read_register(serial, node, reg).await
+    }
+
+    async fn write_register(
+        &mut self,
+        channel: u8,
+        reg: u8,
+        value: u32,
+        force: bool,
+    ) -> Result<(), DriverError> {
+        let (serial, node) = self.driver_serial(channel);
+        B::Family::write_register(serial, node, reg, value, force).await
+    }
+}
+
+#[cfg(feature = "stallguard")]
+impl<B, S, const N: usize> StallGuard<S, N> for B
+where
+    B: ConfigurableStepStickHost<N, DriverSerial = S>,
+{
+    async fn set_sg_threshold(&mut self, channel: u8, sgthrs: u8) {
+        let (serial, node) = self.driver_serial(channel);
+        B::Family::set_sg_threshold(serial, node, sgthrs).await
+    }
+
+    async fn get_sg_result_halved(&mut self, channel: u8) -> Option<u8> {
+        let (serial, node) = self.driver_serial(channel);
+        if !B::Family::request_sg_result(serial, node).await {
+            return None;
+        }
+        B::Family::await_sg_result_halved(serial, node).await
+    }
+
+    /// Requests are still sent and read back one at a time, but a request is sent to every bus
+    /// before any reply is read, so drivers on other buses prepare their replies in the meantime
+    async fn get_sg_results_halved(&mut self, mut channels: u16) -> [Option<u8>; N] {
+        let mut results = [None; N];
+
+        while channels != 0 {
+            let in_flight = request_sg_results(self, &mut channels).await;
+            for channel in 0..N as u8 {
+                if (in_flight >> channel) & 0b1 == 0 {
+                    continue;
+                }
+
+                let (serial, node) = self.driver_serial(channel);
+                results[channel as usize] = B::Family::await_sg_result_halved(serial, node).await;
+            }
+        }
+
+        results
+    }
+
+    /// Pipelined across buses like [`StallGuard::get_sg_results_halved`]
+    async fn get_stalls(&mut self, mut channels: u16, sg_thresholds: &[u8]) -> u16 {
+        let mut stalls = 0u16;
+
+        while channels != 0 {
+            let in_flight = request_sg_results(self, &mut channels).await;
+            for channel in 0..N as u8 {
+                if (in_flight >> channel) & 0b1 == 0 {
+                    continue;
+                }
+
+                let (serial, node) = self.driver_serial(channel);
+                let sgthrs = sg_thresholds[channel as usize];
+                if B::Family::await_stall(serial, node, sgthrs).await == Some(true) {
+                    stalls |= 1 << channel;
+                }
+            }
+        }
+
+        stalls
+    }
+}
+
+/// Sends a StallGuard request to a channel from `channels` on every bus, taking them out of `channels`.
+///
+/// Returns the channels whose request was sent, to be read back in turn.
+#[cfg(feature = "stallguard")]
+async fn request_sg_results<B, const N: usize>(board: &mut B, channels: &mut u16) -> u16
+where
+    B: ConfigurableStepStickHost<N>,
+{
+    let mut in_flight = 0u16;
+    let mut sent_to = 0u16;
+
+    for channel in 0..N as u8 {
+        if (*channels >> channel) & 0b1 == 0 {
+            continue;
+        }
+
+        // A bus only has a single request in flight, as the replies would collide
+        let bus = board.driver_address(channel).bus;
+        let busy = (0..N as u8).any(|other| {
+            (sent_to >> other) & 0b1 == 1 && board.driver_address(other).bus == bus
+        });
+        if busy {
+            continue;
+        }
+        *channels &= !(1 << channel);
+        sent_to |= 1 << channel;
+
+        let (serial, node) = board.driver_serial(channel);
+        if B::Family::request_sg_result(serial, node).await {
+            in_flight |= 1 << channel;
+        }
+    }
+
+    in_flight
+}
@@ -1,11 +1,18 @@
+#[cfg(feature = "configurable_driver")]
+pub mod family;
 #[cfg(feature = "rp")]
 pub mod rp;
 #[cfg(feature = "tmc2209_async")]
 pub mod tmc2209_uart;
+#[cfg(feature = "tmc5160_spi")]
+pub mod tmc5160_spi;
 
-use crate::rpc::AsyncRpc;
 use embassy_executor::Spawner;
-use embedded_io_async::{Read, Write};
+use embassy_time::Duration;
+use embedded_io_async::{Error, ErrorKind, ErrorType, Read, Write};
+use heapless::Vec;
+use sequencer::WindowDressingState;
+use serde::{Deserialize, Serialize};
 
 #[macro_export]
 macro_rules! static_buffer {
@@ -23,13 +30,134 @@ pub trait StepStickHost {
     fn get_ready_for_steps(&mut self, channel: usize) -> bool;
     fn add_steps(&mut self, channel: usize, steps: u32) -> Option<bool>;
     fn clear_steps(&mut self, channel: usize);
+    /// Freezes the state machine with its steps still queued, or lets it carry on from where it was frozen
+    fn hold_steps(&mut self, channel: usize, hold: bool);
+    /// Clocks the channel's steps at `hz`, which scales with its microstepping
+    fn set_step_frequency(&mut self, channel: usize, hz: u32);
+    /// What the supply can deliver to the motors, against which motor starts are admitted
+    fn power_budget(&self) -> PowerBudget {
+        PowerBudget::default()
+    }
+    /// Keeps the channels' positions through a loss of power, e.g. in flash, by channel with `None` for
+    /// those which aren't set up. Called once every channel is halted and wound back to where it stopped.
+    ///
+    /// Boards without anywhere to keep them leave it to the host to pass the position back as `init`.
+    fn persist_positions(&mut self, _positions: &[Option<WindowDressingState>]) {}
+    /// The position persisted for the channel, taken by its first `Setup` without an `init`
+    fn take_persisted_position(&mut self, _channel: usize) -> Option<WindowDressingState> {
+        None
+    }
 }
 
-pub trait ControllableBoard {
-    type Rpc: AsyncRpc;
+/// Current the supply can deliver to the motors, and how long a motor draws its inrush current after starting.
+///
+/// Channels declare their own draw in `Setup`, those which don't are assumed to take the whole supply
+/// while starting, and nothing once running. Only enforced with `brownout-protection`.
+#[derive(Clone, Copy)]
+pub struct PowerBudget {
+    pub supply_ma: u32,
+    pub inrush: Duration,
+}
+
+impl Default for PowerBudget {
+    /// Staggers motor starts by two seconds, as nothing is known about the supply
+    ///
+    /// From my experience, 3x1.65A steppers starting up are enough to brown a laptop
+    /// charger enough that the last stepper to start up will stall with StallGuard.
+    fn default() -> Self {
+        PowerBudget {
+            supply_ma: 0,
+            inrush: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Boards with their motor supply wired to an ADC channel, for the [`crate::supply`] monitor
+pub trait SupplyVoltage {
+    /// Supply voltage in mV, or `None` should the ADC fail to read
+    fn read_supply_mv(&mut self) -> Option<u16>;
+    fn supply_thresholds(&self) -> SupplyThresholds;
+}
+
+/// Supply voltages at which motion is paused, resumed, and abandoned ahead of losing power
+#[derive(Clone, Copy)]
+pub struct SupplyThresholds {
+    /// Motion is held below this
+    pub undervoltage_mv: u16,
+    /// Held motion carries on once back above this
+    pub recovery_mv: u16,
+    /// Power is about to be lost below this, so every channel is stopped and its position reported
+    pub power_fail_mv: u16,
+}
+
+impl SupplyThresholds {
+    /// Pauses at 85% of the nominal voltage, resumes at 92%, and gives up at 70%
+    pub const fn for_nominal(nominal_mv: u16) -> Self {
+        let nominal = nominal_mv as u32;
+        SupplyThresholds {
+            undervoltage_mv: (nominal * 85 / 100) as u16,
+            recovery_mv: (nominal * 92 / 100) as u16,
+            power_fail_mv: (nominal * 70 / 100) as u16,
+        }
+    }
+}
+
+/// Boards with a thermistor on anything that must not overheat, e.g. the drivers or a supercapacitor
+pub trait Thermometer {
+    /// Hottest of the board's sensors in °C, or `None` should they fail to read
+    fn read_temp_celsius(&mut self) -> Option<f32>;
+    /// Limits for the sensed parts, which the host may replace
+    fn thermal_thresholds(&self) -> ThermalThresholds;
+}
+
+/// Temperatures at which the thermal state escalates, each dropping back only once
+/// the temperature is `hysteresis_c` below it
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize, defmt::Format)]
+#[serde(deny_unknown_fields)]
+pub struct ThermalThresholds {
+    /// Only reported to the host
+    pub warn_c: i16,
+    /// Run current is scaled down to `derate_percent`
+    pub derate_c: i16,
+    /// Motion is stopped, and new moves are refused
+    pub shutdown_c: i16,
+    pub hysteresis_c: u8,
+    pub derate_percent: u8,
+}
+
+/// Most readings a board may declare in a `Sensors` packet, besides those of the controller's own monitors.
+///
+/// Bounded so a full packet still fits in the 512 byte write buffer of a UART host, see [`MAX_SENSORS_PACKET`].
+///
+/// [`MAX_SENSORS_PACKET`]: crate::rpc::MAX_SENSORS_PACKET
+pub const MAX_SENSORS: usize = 5;
+/// Longest name a reading may have, readings with longer ones are left out of the `Sensors` packet
+pub const MAX_SENSOR_NAME: usize = 16;
+
+/// Boards with readings worth graphing on the host, e.g. temperatures and spare ADC channels
+pub trait BoardSensors {
+    /// Appends the board's current readings, leaving out any that failed to read
+    fn read_sensors(&mut self, readings: &mut Vec<SensorReading, MAX_SENSORS>);
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct SensorReading {
+    /// Plain ASCII of at most [`MAX_SENSOR_NAME`] characters, as it's sent without escaping taken into account
+    pub name: &'static str,
+    pub value: f32,
+    pub unit: SensorUnit,
+}
 
-    fn get_host_rpc(&mut self) -> &mut Self::Rpc;
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorUnit {
+    Celsius,
+    Millivolts,
+    /// Raw ADC reading, for channels the board doesn't know the meaning of
+    Counts,
+}
 
+pub trait ControllableBoard {
     fn reset(&mut self);
 
     fn enter_bootloader(&mut self);
@@ -39,30 +167,349 @@ pub trait ControllableBoard {
     fn watchdog_feed(&mut self) {}
 }
 
+/// Boards hand out their peripherals to the concurrently running parts of the controller,
+/// so that driver bus transactions and host traffic never hold up the step generation.
+pub trait SplitBoard {
+    /// Step generation, driver enables and the watchdog, owned by the motion task
+    type Motion;
+    /// Link to the host, owned by the host task
+    type Host;
+    /// Configuration bus(es) of the drivers, shared by the host and diagnostics tasks
+    type Drivers;
+    /// Implementer defined, invoked by the diagnostics task
+    type State;
+
+    fn split(
+        &mut self,
+    ) -> (
+        &mut Self::Motion,
+        &mut Self::Host,
+        &mut Self::Drivers,
+        &mut Self::State,
+    );
+}
+
+/// Electrical behaviour of a channel's endstop input
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize, defmt::Format)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndstopConfig {
+    /// For normally-closed switches, and sensors which pull their output low when triggered
+    pub active_low: bool,
+    pub pull: EndstopPull,
+    /// How long the input must hold its level before a trigger or release is accepted
+    pub debounce_ms: u16,
+    /// Dead time after a release before the endstop can trigger again
+    pub rearm_ms: u16,
+}
+
+impl Default for EndstopConfig {
+    /// An active-high switch pulled down, matching the wiring of most boards' endstop headers
+    fn default() -> Self {
+        EndstopConfig {
+            active_low: false,
+            pull: EndstopPull::Down,
+            debounce_ms: 0,
+            rearm_ms: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize, defmt::Format)]
+#[serde(rename_all = "snake_case")]
+pub enum EndstopPull {
+    None,
+    Up,
+    Down,
+}
+
 #[allow(async_fn_in_trait)]
 pub trait ControlLoopInvoke {
     async fn invoke(&mut self, _spawner: &mut Spawner);
 }
 
-#[cfg(feature = "uart_configurable_driver")]
+#[cfg(feature = "configurable_driver")]
 pub trait ConfigurableStepStickHost<const N: usize> {
-    type DriverSerial: Read + Write;
+    /// A UART for the TMC22xx drivers, or an SPI device for the TMC5160
+    type DriverSerial;
+    type Family: family::DriverFamily<Self::DriverSerial>;
+
+    fn driver_address(&self, channel: u8) -> DriverAddress;
+    fn driver_bus(&mut self, bus: u8) -> &mut Self::DriverSerial;
+
+    /// The bus the channel's driver is attached to, and its node address on that bus
+    fn driver_serial(&mut self, channel: u8) -> (&mut Self::DriverSerial, u8) {
+        let DriverAddress { bus, node } = self.driver_address(channel);
+        (self.driver_bus(bus), node)
+    }
+}
+
+/// Location of a channel's driver, as a bus index and the node address on that bus
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct DriverAddress {
+    pub bus: u8,
+    pub node: u8,
+}
+
+impl DriverAddress {
+    /// Every driver shares a single bus, addressed by their channel number
+    pub const fn shared_bus<const N: usize>() -> [DriverAddress; N] {
+        let mut addresses = [DriverAddress { bus: 0, node: 0 }; N];
+        let mut i = 0;
+        while i < N {
+            addresses[i].node = i as u8;
+            i += 1;
+        }
+        addresses
+    }
+
+    /// Every driver has a bus of its own, e.g. a dedicated hardware or PIO UART, at node address 0
+    pub const fn dedicated_buses<const N: usize>() -> [DriverAddress; N] {
+        let mut addresses = [DriverAddress { bus: 0, node: 0 }; N];
+        let mut i = 0;
+        while i < N {
+            addresses[i].bus = i as u8;
+            i += 1;
+        }
+        addresses
+    }
+}
 
-    fn driver_serial(&mut self, addr: u8) -> &mut Self::DriverSerial;
+/// Collection of driver buses, indexed by [`DriverAddress::bus`]
+#[cfg(feature = "configurable_driver")]
+pub trait DriverBuses {
+    type Serial;
+
+    fn bus(&mut self, bus: u8) -> &mut Self::Serial;
+}
+
+#[cfg(feature = "configurable_driver")]
+impl<S, const B: usize> DriverBuses for [S; B] {
+    type Serial = S;
+
+    fn bus(&mut self, bus: u8) -> &mut S {
+        &mut self[bus as usize]
+    }
+}
+
+/// Joins separate transmit and receive halves into a single bus,
+/// such as the PIO UART programs for boards that run out of hardware UARTs.
+///
+/// The TX pin is expected to be wired to the driver through a resistor like the hardware UARTs,
+/// so the transmitted bytes are read back and `uart_soft_half_duplex` applies.
+pub struct SplitSerial<TX, RX> {
+    pub tx: TX,
+    pub rx: RX,
+}
+
+impl<TX, RX> ErrorType for SplitSerial<TX, RX> {
+    type Error = ErrorKind;
+}
+
+impl<TX: Write, RX> Write for SplitSerial<TX, RX> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.tx.write(buf).await.map_err(|e| e.kind())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.tx.flush().await.map_err(|e| e.kind())
+    }
+}
+
+impl<TX, RX: Read> Read for SplitSerial<TX, RX> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.rx.read(buf).await.map_err(|e| e.kind())
+    }
 }
 
 #[allow(async_fn_in_trait)]
 pub trait ConfigurableStepStickDriver<S, const N: usize> {
+    /// Configures every driver with the default [`DriverConfig`],
+    /// leaving the channels which failed verification flagged as unconfigured.
     async fn configure_driver(&mut self);
+    /// Configures a single driver, verifying each write and retrying with backoff on failure.
+    async fn configure_channel(
+        &mut self,
+        channel: u8,
+        config: &DriverConfig,
+    ) -> Result<(), DriverError>;
+    /// Reprograms a driver in place, e.g. to derate its current mid-move, only holding the channel off
+    /// should verification fail.
+    async fn update_channel(
+        &mut self,
+        channel: u8,
+        config: &DriverConfig,
+    ) -> Result<(), DriverError>;
+}
+
+/// Electrical configuration of a single stepper driver
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct DriverConfig {
+    /// Motor current while running, in 32nds of the full scale current set by the sense resistors
+    pub run_current: u8,
+    /// Motor current while standing still, in 32nds of the full scale current
+    pub hold_current: u8,
+    /// Microsteps per full step, a power of two up to 256
+    pub microsteps: u16,
+    /// Quiet voltage chopper when true, SpreadCycle otherwise
+    pub stealthchop: bool,
+}
+
+impl Default for DriverConfig {
+    /// Matches the driver's power-on defaults, except for full-step mode
+    fn default() -> Self {
+        Self {
+            run_current: 31,
+            hold_current: 16,
+            microsteps: 1,
+            stealthchop: true,
+        }
+    }
+}
+
+impl DriverConfig {
+    pub const MAX_CURRENT: u8 = 31;
+    pub const MAX_MICROSTEPS: u16 = 256;
+
+    /// Run current scaled down to `percent`, e.g. while the board is running warm
+    pub fn derated(&self, percent: u8) -> DriverConfig {
+        DriverConfig {
+            run_current: (self.run_current as u16 * percent.min(100) as u16 / 100) as u8,
+            ..*self
+        }
+    }
+
+    /// $\log_2$ of the microstep count
+    pub fn microstep_exponent(&self) -> u8 {
+        self.microsteps.trailing_zeros() as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug, defmt::Format, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriverError {
+    /// No reply, or a corrupt reply, was received from the driver
+    NotResponding,
+    /// The driver replied, but didn't count the write as received
+    NotAcknowledged,
+    /// The register does not exist, or is not exposed for raw access
+    Unsupported,
+    /// Writing the register could damage the hardware, and must be forced
+    Guarded,
 }
 
-#[cfg(all(feature = "stallguard", feature = "uart_configurable_driver"))]
+/// Raw register access for field debugging
+#[cfg(feature = "configurable_driver")]
+#[allow(async_fn_in_trait)]
+pub trait DriverRegisterAccess<S, const N: usize> {
+    async fn read_register(&mut self, channel: u8, reg: u8) -> Result<u32, DriverError>;
+    /// Registers which set the motor current, the chopper or bus addressing,
+    /// or which move the motor on their own, are refused unless `force` is set.
+    async fn write_register(
+        &mut self,
+        channel: u8,
+        reg: u8,
+        value: u32,
+        force: bool,
+    ) -> Result<(), DriverError>;
+}
+
+#[cfg(all(feature = "stallguard", feature = "configurable_driver"))]
 #[allow(async_fn_in_trait)]
 pub trait StallGuard<S, const N: usize> {
     /// StallGuard Threshold, scaled back to 8 bits
     async fn set_sg_threshold(&mut self, channel: u8, sgthrs: u8);
     /// StallGuard result, scaled back to 8 bits
     async fn get_sg_result_halved(&mut self, channel: u8) -> Option<u8>;
+    /// StallGuard results of every channel flagged in the `channels` bitmask, scaled back to 8 bits
+    async fn get_sg_results_halved(&mut self, channels: u16) -> [Option<u8>; N];
+    /// Channels flagged in the `channels` bitmask whose driver sees a stall, as a bitmask,
+    /// given the threshold each channel was programmed with in `sg_thresholds`
+    async fn get_stalls(&mut self, channels: u16, sg_thresholds: &[u8]) -> u16;
+}
+
+#[cfg(feature = "configurable_driver")]
+#[allow(async_fn_in_trait)]
+pub trait DriverDiagnostics<S, const N: usize> {
+    /// Polls the driver's status registers, clearing any latched global status flags.
+    ///
+    /// Should the driver fail to respond, the returned status has `comms_error` set.
+    async fn get_driver_status(&mut self, channel: u8) -> DriverStatus;
+}
+
+/// Snapshot of the fault flags reported by a stepper driver
+#[derive(Clone, Copy, Default, Eq, PartialEq, Serialize)]
+pub struct DriverStatus {
+    /// Over-temperature pre-warning
+    pub otpw: bool,
+    /// Over-temperature shutdown
+    pub ot: bool,
+    /// Short to ground on phase A or B
+    pub s2ga: bool,
+    pub s2gb: bool,
+    /// Short to supply (low side) on phase A or B
+    pub s2vsa: bool,
+    pub s2vsb: bool,
+    /// Open load on phase A or B, only meaningful while the motor is moving
+    pub ola: bool,
+    pub olb: bool,
+    /// The driver has been reset since the last poll, and its configuration is lost
+    pub reset: bool,
+    /// The driver has shut down due to over-temperature or a short
+    pub drv_err: bool,
+    /// Charge pump undervoltage
+    pub uv_cp: bool,
+    /// The driver did not respond, or responded with a corrupt datagram
+    pub comms_error: bool,
+    /// The driver's configuration could not be verified, so the channel will not be run
+    pub unconfigured: bool,
+    /// Count of successful UART writes the driver has received, wrapping at 255
+    #[serde(skip)]
+    pub ifcnt: u8,
+}
+
+impl DriverStatus {
+    /// Whether any of the reported flags differ, ignoring counters
+    pub fn flags_changed(&self, other: &DriverStatus) -> bool {
+        DriverStatus {
+            ifcnt: other.ifcnt,
+            ..*self
+        } != *other
+    }
+
+    /// Faults which warrant disabling the driver to protect the motor and the board.
+    ///
+    /// Open load is only considered when the motor is moving, as it's falsely flagged at standstill.
+    pub fn is_hard_fault(&self, moving: bool) -> bool {
+        self.ot
+            || self.s2ga
+            || self.s2gb
+            || self.s2vsa
+            || self.s2vsb
+            || self.drv_err
+            || (moving && (self.ola || self.olb))
+    }
+}
+
+impl defmt::Format for DriverStatus {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "{{ otpw: {}, ot: {}, s2g: {}/{}, s2vs: {}/{}, ol: {}/{}, reset: {}, drv_err: {}, uv_cp: {}, comms_error: {}, unconfigured: {} }}",
+            self.otpw,
+            self.ot,
+            self.s2ga,
+            self.s2gb,
+            self.s2vsa,
+            self.s2vsb,
+            self.ola,
+            self.olb,
+            self.reset,
+            self.drv_err,
+            self.uv_cp,
+            self.comms_error,
+            self.unconfigured
+        )
+    }
 }
 
 #[cfg(feature = "uart_soft_half_duplex")]
@@ -87,7 +534,7 @@ where
         use embassy_time::Timer;
 
         Timer::after_millis(50).await;
-        let _ = self.flush();
-        let _ = self.read_exact(&mut [0u8; N]);
+        let _ = self.flush().await;
+        let _ = self.read_exact(&mut [0u8; N]).await;
     }
 }
@@ -1,43 +1,61 @@
-use crate::board::rp::utils::counted_sqr_wav_pio::CountedSqrWav;
+use crate::board::rp::persist::PersistSignal;
+use crate::board::rp::utils::counted_sqr_wav_pio::{CountedSqrWav, SqrWavHalt};
+#[cfg(feature = "configurable_driver")]
+use crate::board::family::DriverFamily;
 use crate::board::{
-    ConfigurableStepStickHost, ControlLoopInvoke, ControllableBoard, StepStickHost,
+    ConfigurableStepStickHost, ControllableBoard, DriverAddress, DriverBuses, EndstopConfig,
+    EndstopPull, PowerBudget, SplitBoard, SplitSerial, StepStickHost, SupplyThresholds,
+    SupplyVoltage,
 };
-#[cfg(feature = "host-uart")]
-use crate::rpc::SerialRpcHandle;
-#[cfg(feature = "host-usb")]
-use crate::rpc::UsbRpcHandle;
-use crate::{DRIVERS, STOPS};
+use crate::{is_flagged, DRIVERS, ENDSTOPS, ENDSTOP_CONFIG, MOVING, RELEASES, STOPS, WAKE};
+use core::cell::RefCell;
 use core::sync::atomic::Ordering;
 use defmt::*;
 use embassy_executor::Spawner;
-use embassy_rp::gpio::{Input, Level, Output};
+use embassy_futures::select::{select, Either};
+use embassy_rp::adc::{self, Adc};
+use embassy_rp::gpio::{Flex, Level, Output, Pull};
+use embassy_rp::pio_programs::uart::{PioUartRx, PioUartTx};
 use embassy_rp::peripherals::PIO0;
 #[cfg(any(feature = "driver-qty-5", feature = "driver-qty-8"))]
 use embassy_rp::peripherals::PIO1;
 use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
 use embassy_time::Timer;
-#[cfg(feature = "host-usb")]
-use embassy_usb::driver::Driver;
-#[cfg(feature = "host-uart")]
-use embedded_io_async::{ErrorType, ReadReady};
-use embedded_io_async::{Read, Write};
+use sequencer::WindowDressingState;
 
+pub mod persist;
 pub mod utils;
 
+/// A driver bus on a pair of state machines running the PIO UART programs, for boards which run out of hardware UARTs
+pub type PioUartBus<'d, PIO, const TX: usize, const RX: usize> =
+    SplitSerial<PioUartTx<'d, PIO, TX>, PioUartRx<'d, PIO, RX>>;
+
 pub struct DriverPins<'a> {
     pub enable: Output<'a>,
     // pub step: Output<'a>,
     pub dir: Output<'a>,
 }
 
-pub struct Board<'a, const N: usize, D, H, T> {
-    pub drivers: [DriverPins<'a>; N],
-    pub driver_serial: D,
+pub struct Board<'a, const N: usize, D, H, T, F> {
+    pub motion: MotionHost<'a, N>,
+    pub driver_bus: DriverBus<N, D, F>,
     pub host_rpc: H,
-    pub wdr: Watchdog,
     // Implementer defined, useful for debugging or carrying any information that
     // the controller does not care about
     pub board_state: T,
+}
+
+/// Step generation and the watchdog, handed to the motion task
+pub struct MotionHost<'a, const N: usize> {
+    pub drivers: [DriverPins<'a>; N],
+    pub wdr: Watchdog,
+    pub power_budget: PowerBudget,
+    /// Positions read back from before the last loss of power, each taken by its channel's first `Setup`
+    pub persisted: [Option<WindowDressingState>; N],
+    /// Hands halted positions over to [`persist::run`], on boards which keep them in flash
+    pub persist: Option<&'a PersistSignal<N>>,
     // State machines - alternative to an ACT timer on STM controllers
     pub pio0_0: Option<CountedSqrWav<'a, PIO0, 0>>,
     pub pio0_1: Option<CountedSqrWav<'a, PIO0, 1>>,
@@ -53,19 +71,65 @@ pub struct Board<'a, const N: usize, D, H, T> {
     pub pio1_3: Option<CountedSqrWav<'a, PIO1, 3>>,
 }
 
-#[cfg(feature = "host-uart")]
-impl<'a, const N: usize, const BS: usize, D, IO, T> ControllableBoard
-    for Board<'a, N, D, SerialRpcHandle<BS, IO>, T>
-where
-    IO: Read + ReadReady + Write,
-    <IO as ErrorType>::Error: defmt::Format,
-{
-    type Rpc = SerialRpcHandle<BS, IO>;
+/// The drivers' configuration buses, shared by the host and diagnostics tasks
+pub struct DriverBus<const N: usize, D, F> {
+    pub buses: D,
+    pub addresses: [DriverAddress; N],
+    /// Marker selecting the register-level implementation of the drivers, e.g. `Tmc2209`
+    pub family: F,
+}
+
+impl<'a, const N: usize, D, H, T, F> SplitBoard for Board<'a, N, D, H, T, F> {
+    type Motion = MotionHost<'a, N>;
+    type Host = H;
+    type Drivers = DriverBus<N, D, F>;
+    type State = T;
 
-    fn get_host_rpc(&mut self) -> &mut Self::Rpc {
-        &mut self.host_rpc
+    fn split(
+        &mut self,
+    ) -> (
+        &mut Self::Motion,
+        &mut Self::Host,
+        &mut Self::Drivers,
+        &mut Self::State,
+    ) {
+        (
+            &mut self.motion,
+            &mut self.host_rpc,
+            &mut self.driver_bus,
+            &mut self.board_state,
+        )
     }
+}
+
+impl<'a, const N: usize> MotionHost<'a, N> {
+    /// Stops for each channel's state machine, to hand to [`bind_endstops`]
+    pub fn step_halts(&self) -> [Option<SqrWavHalt>; N] {
+        let mut halts = [None; N];
+        let mut bind = |channel: usize, halt: Option<SqrWavHalt>| {
+            if let Some(slot) = halts.get_mut(channel) {
+                *slot = halt;
+            }
+        };
 
+        bind(0, self.pio0_0.as_ref().map(|p| p.halt_handle()));
+        bind(1, self.pio0_1.as_ref().map(|p| p.halt_handle()));
+        bind(2, self.pio0_2.as_ref().map(|p| p.halt_handle()));
+        bind(3, self.pio0_3.as_ref().map(|p| p.halt_handle()));
+        #[cfg(any(feature = "driver-qty-5", feature = "driver-qty-8"))]
+        bind(4, self.pio1_0.as_ref().map(|p| p.halt_handle()));
+        #[cfg(feature = "driver-qty-8")]
+        bind(5, self.pio1_1.as_ref().map(|p| p.halt_handle()));
+        #[cfg(feature = "driver-qty-8")]
+        bind(6, self.pio1_2.as_ref().map(|p| p.halt_handle()));
+        #[cfg(feature = "driver-qty-8")]
+        bind(7, self.pio1_3.as_ref().map(|p| p.halt_handle()));
+
+        halts
+    }
+}
+
+impl<'a, const N: usize> ControllableBoard for MotionHost<'a, N> {
     fn reset(&mut self) {
         self.wdr.trigger_reset();
     }
@@ -81,28 +145,7 @@ where
     }
 }
 
-#[cfg(feature = "host-usb")]
-impl<const N: usize, const BS: usize, D, HD, T> ControllableBoard
-    for Board<'static, N, D, UsbRpcHandle<BS, HD>, T>
-where
-    HD: Driver<'static>,
-{
-    type Rpc = UsbRpcHandle<BS, HD>;
-
-    fn get_host_rpc(&mut self) -> &mut Self::Rpc {
-        &mut self.host_rpc
-    }
-
-    fn reset(&mut self) {
-        self.wdr.trigger_reset();
-    }
-
-    fn enter_bootloader(&mut self) {
-        embassy_rp::rom_data::reset_to_usb_boot(0, 0);
-    }
-}
-
-impl<'a, const N: usize, D, H, T> StepStickHost for Board<'a, N, D, H, T> {
+impl<'a, const N: usize> StepStickHost for MotionHost<'a, N> {
     fn get_enabled(&mut self, channel: usize) -> bool {
         self.drivers[channel].enable.is_set_low()
     }
@@ -194,49 +237,218 @@ impl<'a, const N: usize, D, H, T> StepStickHost for Board<'a, N, D, H, T> {
             _ => None,
         };
     }
+
+    fn hold_steps(&mut self, channel: usize, hold: bool) {
+        match channel {
+            0 => self.pio0_0.as_mut().map(|p| p.hold(hold)),
+            1 => self.pio0_1.as_mut().map(|p| p.hold(hold)),
+            2 => self.pio0_2.as_mut().map(|p| p.hold(hold)),
+            3 => self.pio0_3.as_mut().map(|p| p.hold(hold)),
+            #[cfg(any(feature = "driver-qty-5", feature = "driver-qty-8"))]
+            4 => self.pio1_0.as_mut().map(|p| p.hold(hold)),
+            #[cfg(feature = "driver-qty-8")]
+            5 => self.pio1_1.as_mut().map(|p| p.hold(hold)),
+            #[cfg(feature = "driver-qty-8")]
+            6 => self.pio1_2.as_mut().map(|p| p.hold(hold)),
+            #[cfg(feature = "driver-qty-8")]
+            7 => self.pio1_3.as_mut().map(|p| p.hold(hold)),
+            _ => None,
+        };
+    }
+
+    fn set_step_frequency(&mut self, channel: usize, hz: u32) {
+        match channel {
+            0 => self.pio0_0.as_mut().map(|p| p.set_frequency(hz)),
+            1 => self.pio0_1.as_mut().map(|p| p.set_frequency(hz)),
+            2 => self.pio0_2.as_mut().map(|p| p.set_frequency(hz)),
+            3 => self.pio0_3.as_mut().map(|p| p.set_frequency(hz)),
+            #[cfg(any(feature = "driver-qty-5", feature = "driver-qty-8"))]
+            4 => self.pio1_0.as_mut().map(|p| p.set_frequency(hz)),
+            #[cfg(feature = "driver-qty-8")]
+            5 => self.pio1_1.as_mut().map(|p| p.set_frequency(hz)),
+            #[cfg(feature = "driver-qty-8")]
+            6 => self.pio1_2.as_mut().map(|p| p.set_frequency(hz)),
+            #[cfg(feature = "driver-qty-8")]
+            7 => self.pio1_3.as_mut().map(|p| p.set_frequency(hz)),
+            _ => None,
+        };
+    }
+
+    fn power_budget(&self) -> PowerBudget {
+        self.power_budget
+    }
+
+    fn persist_positions(&mut self, positions: &[Option<WindowDressingState>]) {
+        if let Some(persist) = self.persist {
+            let mut out = [None; N];
+            for (out, position) in out.iter_mut().zip(positions) {
+                *out = *position;
+            }
+            persist.signal(out);
+        }
+    }
+
+    fn take_persisted_position(&mut self, channel: usize) -> Option<WindowDressingState> {
+        self.persisted.get_mut(channel)?.take()
+    }
+}
+
+/// The RP2040 has a single ADC, which boards share between the thermistor, supply and whatever else they sample.
+///
+/// Reads are blocking, so they can't interleave on the one core that samples.
+pub type SharedAdc<'d> = BlockingMutex<CriticalSectionRawMutex, RefCell<Adc<'d, adc::Blocking>>>;
+
+/// Supply voltage sensed through a resistor divider on one of the RP2040's ADC channels
+pub struct AdcSupply<'d> {
+    pub adc: &'d SharedAdc<'d>,
+    pub channel: adc::Channel<'d>,
+    /// Divider resistance between the supply and the ADC pin, in ohms
+    pub r_top: u32,
+    /// Divider resistance between the ADC pin and ground, in ohms
+    pub r_bottom: u32,
+    pub thresholds: SupplyThresholds,
+}
+
+impl<'d> SupplyVoltage for AdcSupply<'d> {
+    fn read_supply_mv(&mut self) -> Option<u16> {
+        let channel = &mut self.channel;
+        let raw = self
+            .adc
+            .lock(|adc| adc.borrow_mut().blocking_read(channel).ok())? as u64;
+        let pin_mv = raw * 3300 / 4096;
+        let supply_mv = pin_mv * (self.r_top + self.r_bottom) as u64 / self.r_bottom as u64;
+
+        Some(supply_mv.min(u16::MAX as u64) as u16)
+    }
+
+    fn supply_thresholds(&self) -> SupplyThresholds {
+        self.thresholds
+    }
 }
 
-#[cfg(feature = "uart_configurable_driver")]
-impl<'a, const N: usize, D, H, T> ConfigurableStepStickHost<N> for Board<'a, N, D, H, T>
+#[cfg(feature = "configurable_driver")]
+impl<const N: usize, D, F> ConfigurableStepStickHost<N> for DriverBus<N, D, F>
 where
-    D: Read + Write,
+    D: DriverBuses,
+    F: DriverFamily<D::Serial>,
 {
-    type DriverSerial = D;
+    type DriverSerial = D::Serial;
+    type Family = F;
+
+    fn driver_address(&self, channel: u8) -> DriverAddress {
+        self.addresses[channel as usize]
+    }
 
-    fn driver_serial(&mut self, _addr: u8) -> &mut Self::DriverSerial {
-        &mut self.driver_serial
+    fn driver_bus(&mut self, bus: u8) -> &mut Self::DriverSerial {
+        self.buses.bus(bus)
     }
 }
 
-pub fn bind_endstops<const N: usize>(spawner: Spawner, inputs: [Input<'static>; N]) {
+/// Binds endstop inputs with their initial configuration, which `Setup` may later replace.
+///
+/// The detectors stop the state machines themselves through [`MotionHost::step_halts`], so they
+/// should be spawned on the executor running the motion task, i.e. on core1 where the board has moved it there.
+///
+/// A TMC2209's DIAG output also pulses HIGH on a stall, so it can be bound in place of a switch
+/// where the board routes it to a GPIO, as an alternative to polling with `sensorless` channels.
+pub fn bind_endstops<const N: usize>(
+    spawner: Spawner,
+    inputs: [Flex<'static>; N],
+    configs: [EndstopConfig; N],
+    halts: [Option<SqrWavHalt>; N],
+) {
     let mut i = 0;
-    for stop in inputs {
-        let _ = spawner.spawn(stop_detector(i, stop).unwrap());
+    for ((stop, config), halt) in inputs.into_iter().zip(configs).zip(halts) {
+        let _ = spawner.spawn(stop_detector(i, stop, config, halt).unwrap());
         i += 1;
     }
 }
 
 /// Not universally compatible
 ///
-/// See: https://docs.embassy.dev/embassy-rp/git/rp2040/gpio/struct.Input.html
+/// See: https://docs.embassy.dev/embassy-rp/git/rp2040/gpio/struct.Flex.html
 #[embassy_executor::task(pool_size = DRIVERS)]
-async fn stop_detector(i: usize, mut input: Input<'static>) {
+async fn stop_detector(
+    i: usize,
+    mut input: Flex<'static>,
+    mut config: EndstopConfig,
+    halt: Option<SqrWavHalt>,
+) {
+    input.set_as_input();
     loop {
-        debug!("Waiting for endstop event on {}", i);
-        input.wait_for_high().await;
-        debug!("Endstop HIGH detected for channel {}", i);
-        STOPS.bit_set(i as u32, Ordering::Release);
-        input.wait_for_low().await;
-        debug!("Endstop LOW detected for channel {}", i);
-        Timer::after_secs(1).await; // Dead Time Insertion
+        input.set_pull(match config.pull {
+            EndstopPull::None => Pull::None,
+            EndstopPull::Up => Pull::Up,
+            EndstopPull::Down => Pull::Down,
+        });
+
+        // A new configuration restarts detection, as the input may have been waiting on the wrong level
+        if let Either::Second(new) = select(
+            detect_endstop(i, &mut input, &config, halt),
+            ENDSTOP_CONFIG[i].wait(),
+        )
+        .await
+        {
+            debug!("Endstop on channel {} reconfigured: {}", i, new);
+            ENDSTOPS.bit_clear(i as u32, Ordering::Release);
+            config = new;
+        }
     }
 }
 
-impl<'a, const N: usize, D, H, T> ControlLoopInvoke for Board<'a, N, D, H, T>
-where
-    T: ControlLoopInvoke,
-{
-    async fn invoke(&mut self, spawner: &mut Spawner) {
-        self.board_state.invoke(spawner).await
+/// Runs a single trigger and release of the endstop, followed by its dead time
+async fn detect_endstop(
+    i: usize,
+    input: &mut Flex<'static>,
+    config: &EndstopConfig,
+    halt: Option<SqrWavHalt>,
+) {
+    debug!("Waiting for endstop event on {}", i);
+    wait_for_level(input, config, true).await;
+    if let Some(halt) = halt {
+        halt.halt();
+    }
+    debug!("Endstop triggered for channel {}", i);
+    STOPS.bit_set(i as u32, Ordering::Release);
+    ENDSTOPS.bit_set(i as u32, Ordering::Release);
+    WAKE.signal(());
+
+    wait_for_level(input, config, false).await;
+    ENDSTOPS.bit_clear(i as u32, Ordering::Release);
+    debug!("Endstop released for channel {}", i);
+    // The channel's own moves release its endstop too, only a release at rest can be a tug
+    if !is_flagged(&MOVING, i) {
+        RELEASES.bit_set(i as u32, Ordering::Release);
+        WAKE.signal(());
+    }
+    Timer::after_millis(config.rearm_ms as u64).await; // Dead Time Insertion
+}
+
+/// Waits until the endstop is `active` (or not), and holds that level for the debounce period
+async fn wait_for_level(input: &mut Flex<'static>, config: &EndstopConfig, active: bool) {
+    let high = active != config.active_low;
+    loop {
+        if high {
+            input.wait_for_high().await;
+        } else {
+            input.wait_for_low().await;
+        }
+
+        if config.debounce_ms == 0 {
+            return;
+        }
+
+        // Every edge restarts the period, so the level is sampled once the contact stops bouncing
+        let debounce = embassy_time::Duration::from_millis(config.debounce_ms as u64);
+        loop {
+            let edge = select(input.wait_for_any_edge(), Timer::after(debounce)).await;
+            if let Either::Second(_) = edge {
+                break;
+            }
+        }
+
+        if input.is_high() == high {
+            return;
+        }
     }
 }
@@ -0,0 +1,100 @@
+use defmt::*;
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_rp::Peri;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use sequencer::WindowDressingState;
+
+/// Positions handed over by the motion task, for [`run`] to write out on core0
+pub type PersistSignal<const N: usize> =
+    Signal<CriticalSectionRawMutex, [Option<WindowDressingState>; N]>;
+
+/// Marks a sector holding positions, as opposed to erased or foreign flash
+const MAGIC: [u8; 4] = *b"WDP1";
+/// Marks a channel which was set up when its position was persisted
+const SET_UP: u8 = 0xA5;
+/// The ROM programs whole pages, so the positions are padded out to one
+const PAGE_SIZE: usize = 256;
+
+/// Channels' positions kept in the last sector of flash, which the board's `memory.x` must leave out of `FLASH`
+pub struct PositionFlash<'d, const FLASH_SIZE: usize> {
+    flash: Flash<'d, FLASH, Blocking, FLASH_SIZE>,
+}
+
+impl<'d, const FLASH_SIZE: usize> PositionFlash<'d, FLASH_SIZE> {
+    const OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+
+    pub fn new(flash: Peri<'d, FLASH>) -> Self {
+        PositionFlash {
+            flash: Flash::new_blocking(flash),
+        }
+    }
+
+    /// Reads back the positions persisted before the last loss of power, erasing them so they're restored once.
+    ///
+    /// Flash can only be written from core0, so this must be called before core1 is started.
+    pub fn take<const N: usize>(&mut self) -> [Option<WindowDressingState>; N] {
+        let mut positions = [None; N];
+        let mut page = [0u8; PAGE_SIZE];
+        if let Err(e) = self.flash.blocking_read(Self::OFFSET, &mut page) {
+            error!("Failed to read persisted positions: {:?}", e);
+            return positions;
+        }
+        if page[..MAGIC.len()] != MAGIC {
+            return positions;
+        }
+
+        let slots = page[MAGIC.len()..].chunks_exact(3);
+        for (position, slot) in positions.iter_mut().zip(slots) {
+            if slot[0] == SET_UP {
+                *position = Some(WindowDressingState {
+                    position: slot[1],
+                    tilt: slot[2] as i8,
+                });
+            }
+        }
+
+        if let Err(e) = self.erase() {
+            error!("Failed to erase persisted positions: {:?}", e);
+        }
+        positions
+    }
+
+    fn write<const N: usize>(
+        &mut self,
+        positions: &[Option<WindowDressingState>; N],
+    ) -> Result<(), embassy_rp::flash::Error> {
+        let mut page = [0xFFu8; PAGE_SIZE];
+        page[..MAGIC.len()].copy_from_slice(&MAGIC);
+        for (slot, position) in page[MAGIC.len()..].chunks_exact_mut(3).zip(positions) {
+            if let Some(position) = position {
+                slot.copy_from_slice(&[SET_UP, position.position, position.tilt as u8]);
+            }
+        }
+
+        self.erase()?;
+        self.flash.blocking_write(Self::OFFSET, &page)
+    }
+
+    fn erase(&mut self) -> Result<(), embassy_rp::flash::Error> {
+        self.flash
+            .blocking_erase(Self::OFFSET, Self::OFFSET + ERASE_SIZE as u32)
+    }
+}
+
+/// Writes out the positions the motion task persists as it halts every channel.
+///
+/// Spawned on core0, as flash can't be written from core1 where the board runs the motion task there.
+pub async fn run<const FLASH_SIZE: usize, const N: usize>(
+    flash: &mut PositionFlash<'_, FLASH_SIZE>,
+    persist: &PersistSignal<N>,
+) -> ! {
+    loop {
+        let positions = persist.wait().await;
+        match flash.write(&positions) {
+            Ok(()) => info!("Persisted positions {}", positions),
+            Err(e) => error!("Failed to persist positions: {:?}", e),
+        }
+    }
+}
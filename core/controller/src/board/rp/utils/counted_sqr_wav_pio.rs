@@ -2,6 +2,8 @@ use crate::FREQUENCY;
 use defmt::debug;
 use embassy_rp::clocks::clk_sys_freq;
 use embassy_rp::gpio::Level;
+use embassy_rp::pac;
+use embassy_rp::peripherals::{PIO0, PIO1};
 use embassy_rp::pio::{
     Common, Config, Direction, FifoJoin, Instance, LoadedProgram, PioPin, StateMachine,
 };
@@ -35,6 +37,40 @@ impl<'a, PIO: Instance> CountedSqrWavProgram<'a, PIO> {
     }
 }
 
+/// Register block of a PIO instance, for stopping its state machines without borrowing them
+pub trait PioRegisters: Instance {
+    fn regs() -> pac::pio::Pio;
+}
+
+impl PioRegisters for PIO0 {
+    fn regs() -> pac::pio::Pio {
+        pac::PIO0
+    }
+}
+
+impl PioRegisters for PIO1 {
+    fn regs() -> pac::pio::Pio {
+        pac::PIO1
+    }
+}
+
+/// Stops a [`CountedSqrWav`] from outside of its owner, e.g. straight from an endstop interrupt,
+/// ahead of the control loop clearing its FIFO and updating the sequencer.
+#[derive(Clone, Copy)]
+pub struct SqrWavHalt {
+    pio: pac::pio::Pio,
+    sm: usize,
+}
+
+impl SqrWavHalt {
+    pub fn halt(&self) {
+        let sm = self.sm;
+        self.pio
+            .ctrl()
+            .modify(|w| w.set_sm_enable(w.sm_enable() & !(1 << sm)));
+    }
+}
+
 pub struct CountedSqrWav<'a, PIO: Instance, const SM: usize> {
     sm: &'a mut StateMachine<'a, PIO, SM>,
 }
@@ -64,12 +100,24 @@ impl<'a, PIO: Instance, const SM: usize> CountedSqrWav<'a, PIO, SM> {
         Self { sm }
     }
 
+    /// Rescales the clock divider so the output runs at `hz`
+    pub fn set_frequency(&mut self, hz: u32) {
+        self.sm
+            .set_clock_divider((clk_sys_freq() / (hz.max(1) * 24)).to_fixed());
+        self.sm.clkdiv_restart();
+    }
+
     pub fn clear(&mut self) {
         self.sm.set_enable(false);
         self.sm.clear_fifos();
         self.sm.restart();
     }
 
+    /// Disabling the state machine keeps its FIFO and step counter, so it resumes mid-word
+    pub fn hold(&mut self, hold: bool) {
+        self.sm.set_enable(!hold);
+    }
+
     pub fn stopped(&mut self) -> bool {
         self.sm.tx().stalled() || !self.sm.is_enabled()
     }
@@ -108,3 +156,12 @@ impl<'a, PIO: Instance, const SM: usize> CountedSqrWav<'a, PIO, SM> {
         self.sm.tx().try_push(value)
     }
 }
+
+impl<'a, PIO: PioRegisters, const SM: usize> CountedSqrWav<'a, PIO, SM> {
+    pub fn halt_handle(&self) -> SqrWavHalt {
+        SqrWavHalt {
+            pio: PIO::regs(),
+            sm: SM,
+        }
+    }
+}
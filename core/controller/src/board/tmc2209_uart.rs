@@ -1,70 +1,292 @@
-use crate::board::{ConfigurableStepStickDriver, ConfigurableStepStickHost};
+use crate::board::family::DriverFamily;
+use crate::board::{DriverConfig, DriverError, DriverStatus};
 use defmt::*;
+use embassy_time::{Duration, Timer};
 use embedded_io_async::{ErrorType, Read, Write};
 use tmc2209_async::data::MicroStepResolution;
-use tmc2209_async::reg::{CHOPCONF, COOLCONF, GCONF, SLAVECONF, TCOOLTHRS, TPWMTHRS};
-#[cfg(feature = "stallguard")]
-use tmc2209_async::reg::{SGTHRS, SG_RESULT};
+use tmc2209_async::reg::{
+    CHOPCONF, COOLCONF, DRV_STATUS, GCONF, GSTAT, IFCNT, IHOLD_IRUN, IOIN, MSCNT, MSCURACT,
+    PWMCONF, PWM_AUTO, PWM_SCALE, SGTHRS, SG_RESULT, SLAVECONF, TCOOLTHRS, TPOWERDOWN, TPWMTHRS,
+    TSTEP, VACTUAL,
+};
 use tmc2209_async::{ReadableRegister, WritableRegister};
 
 #[cfg(feature = "uart_soft_half_duplex")]
-#[allow(unused)]
 const DATAGRAM_SIZE_READ_REQ: usize = 4;
 #[cfg(feature = "uart_soft_half_duplex")]
 const DATAGRAM_SIZE_WRITE_REQ: usize = 8;
+const WRITE_ATTEMPTS: u32 = 3;
+const WRITE_BACKOFF: Duration = Duration::from_millis(10);
+
+/// TMC2209 over single-wire UART, with StallGuard4
+pub struct Tmc2209;
+
+/// Register compatible with the TMC2209 for everything this controller uses
+pub type Tmc2226 = Tmc2209;
 
-impl<B, S, const N: usize> ConfigurableStepStickDriver<S, N> for B
+/// TMC2208 over single-wire UART, which shares the TMC2209's datagrams but has no StallGuard or CoolStep
+pub struct Tmc2208;
+
+impl<S> DriverFamily<S> for Tmc2209
 where
-    B: ConfigurableStepStickHost<N, DriverSerial = S>,
     S: Read + Write,
     <S as ErrorType>::Error: Format,
 {
-    async fn configure_driver(&mut self) {
-        let mut gconf = GCONF::default();
-        gconf.set_mstep_reg_select(true); // Must be written prior to setting MRES in CHOPCONF
-        let mut chop = CHOPCONF::default();
-        chop.set_vsense(false); // Essential for using the 0R11 external sense resistors on the board, which will program the driver to run at approximately ~1.7A
-        chop.set_mres(MicroStepResolution::new(0)); // Full-step mode (no grinding with PIO SqrWav Generator
-        let tcoolthrs = TCOOLTHRS(0xFFFFF);
-        let tpwmthrs = TPWMTHRS(0);
-        let slaveconf = SLAVECONF(2 << 8); // Apply minimum SENDDELAY for a multi-driver system
-        let coolconf = COOLCONF(0); // Disable CoolStep
-        #[cfg(feature = "stallguard")]
-        let sgthrs = SGTHRS(100);
+    async fn configure(serial: &mut S, node: u8, config: &DriverConfig) -> Result<(), DriverError> {
+        configure::<S, true>(serial, node, config).await
+    }
 
-        for addr in 0..N as u8 {
-            let ser = self.driver_serial(addr);
-            #[cfg(not(feature = "uart_driver_shared_bus"))]
-            let addr = 0;
+    async fn read_status(serial: &mut S, node: u8) -> DriverStatus {
+        read_status(serial, node).await
+    }
 
-            if let Err(e) = send_write_request_safe(addr, gconf, &mut *ser).await {
-                warn!("Failed to program GCONF on addr {}: {:?}", addr, e);
-            }
-            if let Err(e) = send_write_request_safe(addr, chop, &mut *ser).await {
-                warn!("Failed to program CHOPCONF on addr {}: {:?}", addr, e);
-            }
-            if let Err(e) = send_write_request_safe(addr, tcoolthrs, &mut *ser).await {
-                warn!("Failed to program TCOOLTHRS on addr {}: {:?}", addr, e);
-            }
-            if let Err(e) = send_write_request_safe(addr, tpwmthrs, &mut *ser).await {
-                warn!("Failed to program TPWMTHRS on addr {}: {:?}", addr, e);
+    async fn read_register(serial: &mut S, node: u8, reg: u8) -> Result<u32, DriverError> {
+        read_register_raw::<S, true>(serial, node, reg).await
+    }
+
+    async fn write_register(
+        serial: &mut S,
+        node: u8,
+        reg: u8,
+        value: u32,
+        force: bool,
+    ) -> Result<(), DriverError> {
+        write_register_raw::<S, true>(serial, node, reg, value, force).await
+    }
+
+    async fn set_sg_threshold(serial: &mut S, node: u8, sgthrs: u8) {
+        let sgthrs = SGTHRS(sgthrs as u32);
+        if let Err(e) = send_write_request_safe(node, sgthrs, &mut *serial).await {
+            warn!("Failed to program SGTHRS on addr {}: {:?}", node, e);
+        }
+    }
+
+    async fn request_sg_result(serial: &mut S, node: u8) -> bool {
+        if let Err(e) = send_read_request_safe::<SG_RESULT, _>(node, &mut *serial).await {
+            warn!("Failed to request SG_RESULT on addr {}: {:?}", node, e);
+            return false;
+        }
+        true
+    }
+
+    /// For API-compatibility with other StallGuard drivers, this function returns a halved SG_RESULT value
+    async fn await_sg_result_halved(serial: &mut S, node: u8) -> Option<u8> {
+        match tmc2209_async::await_read::<SG_RESULT, _>(serial).await {
+            Ok(sg_result) => Some((sg_result.get() / 2) as u8),
+            Err(_) => {
+                warn!("Failed to read SG_RESULT on addr {}", node);
+                None
             }
-            if let Err(e) = send_write_request_safe(addr, slaveconf, &mut *ser).await {
-                warn!("Failed to program SLAVECONF on addr {}: {:?}", addr, e);
+        }
+    }
+
+    /// StallGuard4 flags a stall at SG_RESULT <= 2 * SGTHRS, which holds for the halved result
+    async fn await_stall(serial: &mut S, node: u8, sgthrs: u8) -> Option<bool> {
+        let sg_result = Self::await_sg_result_halved(serial, node).await?;
+        if sg_result <= sgthrs {
+            debug!("Stall on addr {} with SG_RESULT/2 = {}", node, sg_result);
+        }
+        Some(sg_result <= sgthrs)
+    }
+}
+
+impl<S> DriverFamily<S> for Tmc2208
+where
+    S: Read + Write,
+    <S as ErrorType>::Error: Format,
+{
+    async fn configure(serial: &mut S, node: u8, config: &DriverConfig) -> Result<(), DriverError> {
+        configure::<S, false>(serial, node, config).await
+    }
+
+    async fn read_status(serial: &mut S, node: u8) -> DriverStatus {
+        read_status(serial, node).await
+    }
+
+    async fn read_register(serial: &mut S, node: u8, reg: u8) -> Result<u32, DriverError> {
+        read_register_raw::<S, false>(serial, node, reg).await
+    }
+
+    async fn write_register(
+        serial: &mut S,
+        node: u8,
+        reg: u8,
+        value: u32,
+        force: bool,
+    ) -> Result<(), DriverError> {
+        write_register_raw::<S, false>(serial, node, reg, value, force).await
+    }
+
+    async fn set_sg_threshold(_serial: &mut S, node: u8, _sgthrs: u8) {
+        warn!("TMC2208 on addr {} has no StallGuard, ignoring threshold", node);
+    }
+
+    async fn request_sg_result(_serial: &mut S, _node: u8) -> bool {
+        false
+    }
+
+    async fn await_sg_result_halved(_serial: &mut S, _node: u8) -> Option<u8> {
+        None
+    }
+
+    async fn await_stall(_serial: &mut S, _node: u8, _sgthrs: u8) -> Option<bool> {
+        None
+    }
+}
+
+/// `STALLGUARD` selects between the TMC2209 and the TMC2208, which lacks the StallGuard and CoolStep registers
+async fn configure<S, const STALLGUARD: bool>(
+    ser: &mut S,
+    addr: u8,
+    config: &DriverConfig,
+) -> Result<(), DriverError>
+where
+    S: Read + Write,
+    <S as ErrorType>::Error: Format,
+{
+    let mut gconf = GCONF::default();
+    gconf.set_mstep_reg_select(true); // Must be written prior to setting MRES in CHOPCONF
+    gconf.set_en_spread_cycle(!config.stealthchop);
+    let mut chop = CHOPCONF::default();
+    chop.set_vsense(false); // Essential for using the 0R11 external sense resistors on the board, which will program the driver to run at approximately ~1.7A at full scale
+    chop.set_mres(MicroStepResolution::new(config.microstep_exponent())); // Full-step mode by default (no grinding with PIO SqrWav Generator
+    let mut ihold_irun = IHOLD_IRUN::default();
+    ihold_irun.set_irun(config.run_current);
+    ihold_irun.set_ihold(config.hold_current);
+    ihold_irun.set_ihold_delay(1); // Power-on default, gradual reduction to hold current
+    let tcoolthrs = TCOOLTHRS(0xFFFFF);
+    let tpwmthrs = TPWMTHRS(0);
+    let slaveconf = SLAVECONF(2 << 8); // Apply minimum SENDDELAY for a multi-driver system
+    let coolconf = COOLCONF(0); // Disable CoolStep
+    #[cfg(feature = "stallguard")]
+    let sgthrs = SGTHRS(100);
+
+    let mut ifcnt = read_ifcnt(addr, &mut *ser).await;
+
+    write_verified(addr, gconf, &mut *ser, &mut ifcnt).await?;
+    write_verified(addr, chop, &mut *ser, &mut ifcnt).await?;
+    write_verified(addr, ihold_irun, &mut *ser, &mut ifcnt).await?;
+    write_verified(addr, tpwmthrs, &mut *ser, &mut ifcnt).await?;
+    write_verified(addr, slaveconf, &mut *ser, &mut ifcnt).await?;
+    if STALLGUARD {
+        write_verified(addr, tcoolthrs, &mut *ser, &mut ifcnt).await?;
+        write_verified(addr, coolconf, &mut *ser, &mut ifcnt).await?;
+        #[cfg(feature = "stallguard")]
+        write_verified(addr, sgthrs, &mut *ser, &mut ifcnt).await?;
+    }
+
+    Ok(())
+}
+
+async fn read_status<S>(serial: &mut S, addr: u8) -> DriverStatus
+where
+    S: Read + Write,
+    <S as ErrorType>::Error: Format,
+{
+    let mut status = DriverStatus::default();
+
+    match read_register::<DRV_STATUS, _>(addr, &mut *serial).await {
+        Some(drv_status) => {
+            status.otpw = drv_status.otpw();
+            status.ot = drv_status.ot();
+            status.s2ga = drv_status.s2ga();
+            status.s2gb = drv_status.s2gb();
+            status.s2vsa = drv_status.s2vsa();
+            status.s2vsb = drv_status.s2vsb();
+            status.ola = drv_status.ola();
+            status.olb = drv_status.olb();
+        }
+        None => status.comms_error = true,
+    }
+
+    match read_register::<GSTAT, _>(addr, &mut *serial).await {
+        Some(gstat) => {
+            status.reset = gstat.reset();
+            status.drv_err = gstat.drv_err();
+            status.uv_cp = gstat.uv_cp();
+
+            // GSTAT flags are latched until a 1 is written back to them
+            if status.reset || status.drv_err || status.uv_cp {
+                if let Err(e) = send_write_request_safe(addr, gstat, &mut *serial).await {
+                    warn!("Failed to clear GSTAT on addr {}: {:?}", addr, e);
+                }
             }
-            if let Err(e) = send_write_request_safe(addr, coolconf, &mut *ser).await {
-                warn!("Failed to program COOLCONF on addr {}: {:?}", addr, e);
+        }
+        None => status.comms_error = true,
+    }
+
+    match read_ifcnt(addr, &mut *serial).await {
+        Some(ifcnt) => status.ifcnt = ifcnt,
+        None => status.comms_error = true,
+    }
+
+    status
+}
+
+async fn read_ifcnt<U>(addr: u8, serial: &mut U) -> Option<u8>
+where
+    U: Read + Write,
+    <U as ErrorType>::Error: Format,
+{
+    read_register::<IFCNT, _>(addr, serial)
+        .await
+        .map(|ifcnt| ifcnt.get() as u8)
+}
+
+/// Writes a register, confirming that the driver's interface transmission counter was incremented.
+///
+/// `ifcnt` carries the last known counter value between consecutive writes to save a round trip,
+/// it's refreshed from the driver when unknown or after a failed attempt.
+async fn write_verified<R, U>(
+    addr: u8,
+    reg: R,
+    serial: &mut U,
+    ifcnt: &mut Option<u8>,
+) -> Result<(), DriverError>
+where
+    R: WritableRegister + Copy,
+    U: Read + Write,
+    <U as ErrorType>::Error: Format,
+{
+    let mut err = DriverError::NotResponding;
+
+    for attempt in 0..WRITE_ATTEMPTS {
+        if attempt > 0 {
+            Timer::after(WRITE_BACKOFF * (1 << attempt)).await;
+        }
+
+        let before = if let Some(before) = *ifcnt {
+            before
+        } else if let Some(before) = read_ifcnt(addr, &mut *serial).await {
+            before
+        } else {
+            err = DriverError::NotResponding;
+            continue;
+        };
+
+        if let Err(e) = send_write_request_safe(addr, reg, &mut *serial).await {
+            warn!("Failed to write register on addr {}: {:?}", addr, e);
+        }
+
+        *ifcnt = read_ifcnt(addr, &mut *serial).await;
+        match *ifcnt {
+            Some(after) if after == before.wrapping_add(1) => return Ok(()),
+            Some(_) => {
+                warn!("Write to addr {} was not acknowledged, retrying...", addr);
+                err = DriverError::NotAcknowledged;
             }
-            #[cfg(feature = "stallguard")]
-            if let Err(e) = send_write_request_safe(addr, sgthrs, &mut *ser).await {
-                warn!("Failed to program SGTHRS on addr {}: {:?}", addr, e);
+            None => {
+                warn!("Driver on addr {} did not respond, retrying...", addr);
+                err = DriverError::NotResponding;
             }
         }
+        *ifcnt = None;
     }
+
+    Err(err)
 }
 
 #[cfg(feature = "uart_soft_half_duplex")]
-#[allow(unused)]
 async fn send_read_request_safe<R, U>(
     addr: u8,
     mut tx: U,
@@ -98,45 +320,120 @@ where
 }
 
 #[cfg(not(feature = "uart_soft_half_duplex"))]
-#[allow(unused)]
 use tmc2209_async::{
     send_read_request as send_read_request_safe, send_write_request as send_write_request_safe,
 };
 
-#[cfg(feature = "stallguard")]
-impl<B, S, const N: usize> crate::board::StallGuard<S, N> for B
+/// Requests a register and awaits the reply, logging and discarding any failure
+async fn read_register<R, U>(addr: u8, serial: &mut U) -> Option<R>
 where
-    B: ConfigurableStepStickHost<N, DriverSerial = S>,
-    S: Read + Write,
-    <S as ErrorType>::Error: Format,
+    R: ReadableRegister,
+    U: Read + Write,
+    <U as ErrorType>::Error: Format,
 {
-    async fn set_sg_threshold(&mut self, addr: u8, sgthrs: u8) {
-        let serial = self.driver_serial(addr);
-        #[cfg(not(feature = "uart_driver_shared_bus"))]
-        let addr = 0;
-
-        let sgthrs = SGTHRS(sgthrs as u32);
-        if let Err(e) = send_write_request_safe(addr, sgthrs, &mut *serial).await {
-            warn!("Failed to program SGTHRS on addr {}: {:?}", addr, e);
+    if let Err(e) = send_read_request_safe::<R, _>(addr, &mut *serial).await {
+        warn!("Failed to request register on addr {}: {:?}", addr, e);
+        return None;
+    }
+    match tmc2209_async::await_read::<R, _>(serial).await {
+        Ok(reg) => Some(reg),
+        Err(_) => {
+            warn!("Failed to read register on addr {}", addr);
+            None
         }
     }
+}
 
-    /// For API-compatibility with other StallGuard drivers, this function returns a halved SG_RESULT value
-    async fn get_sg_result_halved(&mut self, addr: u8) -> Option<u8> {
-        let serial = self.driver_serial(addr);
-        #[cfg(not(feature = "uart_driver_shared_bus"))]
-        let addr = 0;
-
-        if let Err(e) = send_read_request_safe::<SG_RESULT, _>(addr, &mut *serial).await {
-            defmt::warn!("Failed to request SG_RESULT on addr {}: {:?}", addr, e);
-            return None;
-        }
-        match tmc2209_async::await_read::<SG_RESULT, _>(serial).await {
-            Ok(sg_result) => Some((sg_result.get() / 2) as u8),
-            Err(_) => {
-                defmt::warn!("Failed to read SG_RESULT on addr {}", addr);
-                None
-            }
+/// Dispatches a raw register address to its typed register, as the driver library is strongly typed.
+macro_rules! dispatch_register {
+    ($reg:expr, { $($addr:literal $(if $cond:ident)? => $body:expr),* $(,)? }) => {
+        match $reg {
+            $($addr $(if $cond)? => $body,)*
+            _ => Err(DriverError::Unsupported),
         }
+    };
+}
+
+/// Registers which set the motor current, the chopper or bus addressing, or which move the motor on their own
+const GUARDED_REGISTERS: [u8; 6] = [0x00, 0x03, 0x10, 0x22, 0x6C, 0x70];
+
+async fn read_raw<R, U>(addr: u8, serial: &mut U) -> Result<u32, DriverError>
+where
+    R: ReadableRegister + Into<u32>,
+    U: Read + Write,
+    <U as ErrorType>::Error: Format,
+{
+    read_register::<R, _>(addr, serial)
+        .await
+        .map(Into::into)
+        .ok_or(DriverError::NotResponding)
+}
+
+async fn write_raw<R, U>(addr: u8, value: u32, serial: &mut U) -> Result<(), DriverError>
+where
+    R: WritableRegister + Copy + From<u32>,
+    U: Read + Write,
+    <U as ErrorType>::Error: Format,
+{
+    write_verified(addr, R::from(value), serial, &mut None).await
+}
+
+/// `STALLGUARD` exposes the registers the TMC2208 lacks
+async fn read_register_raw<S, const STALLGUARD: bool>(
+    ser: &mut S,
+    addr: u8,
+    reg: u8,
+) -> Result<u32, DriverError>
+where
+    S: Read + Write,
+    <S as ErrorType>::Error: Format,
+{
+    dispatch_register!(reg, {
+        0x00 => read_raw::<GCONF, _>(addr, ser).await,
+        0x01 => read_raw::<GSTAT, _>(addr, ser).await,
+        0x02 => read_raw::<IFCNT, _>(addr, ser).await,
+        0x06 => read_raw::<IOIN, _>(addr, ser).await,
+        0x12 => read_raw::<TSTEP, _>(addr, ser).await,
+        0x41 if STALLGUARD => read_raw::<SG_RESULT, _>(addr, ser).await,
+        0x6A => read_raw::<MSCNT, _>(addr, ser).await,
+        0x6B => read_raw::<MSCURACT, _>(addr, ser).await,
+        0x6C => read_raw::<CHOPCONF, _>(addr, ser).await,
+        0x6F => read_raw::<DRV_STATUS, _>(addr, ser).await,
+        0x70 => read_raw::<PWMCONF, _>(addr, ser).await,
+        0x71 => read_raw::<PWM_SCALE, _>(addr, ser).await,
+        0x72 => read_raw::<PWM_AUTO, _>(addr, ser).await,
+    })
+}
+
+/// `STALLGUARD` exposes the registers the TMC2208 lacks
+async fn write_register_raw<S, const STALLGUARD: bool>(
+    ser: &mut S,
+    addr: u8,
+    reg: u8,
+    value: u32,
+    force: bool,
+) -> Result<(), DriverError>
+where
+    S: Read + Write,
+    <S as ErrorType>::Error: Format,
+{
+    if !force && GUARDED_REGISTERS.contains(&reg) {
+        return Err(DriverError::Guarded);
     }
+
+    // OTP_PROG and FACTORY_CONF are deliberately absent, as OTP programming is irreversible
+    dispatch_register!(reg, {
+        0x00 => write_raw::<GCONF, _>(addr, value, ser).await,
+        0x01 => write_raw::<GSTAT, _>(addr, value, ser).await,
+        0x03 => write_raw::<SLAVECONF, _>(addr, value, ser).await,
+        0x10 => write_raw::<IHOLD_IRUN, _>(addr, value, ser).await,
+        0x11 => write_raw::<TPOWERDOWN, _>(addr, value, ser).await,
+        0x13 => write_raw::<TPWMTHRS, _>(addr, value, ser).await,
+        0x14 if STALLGUARD => write_raw::<TCOOLTHRS, _>(addr, value, ser).await,
+        0x22 => write_raw::<VACTUAL, _>(addr, value, ser).await,
+        0x40 if STALLGUARD => write_raw::<SGTHRS, _>(addr, value, ser).await,
+        0x42 if STALLGUARD => write_raw::<COOLCONF, _>(addr, value, ser).await,
+        0x6C => write_raw::<CHOPCONF, _>(addr, value, ser).await,
+        0x70 => write_raw::<PWMCONF, _>(addr, value, ser).await,
+    })
 }
@@ -0,0 +1,200 @@
+use crate::board::family::DriverFamily;
+use crate::board::{DriverConfig, DriverError, DriverStatus};
+use defmt::*;
+use embedded_hal_async::spi::SpiDevice;
+
+/// TMC5160 in STEP/DIR mode over SPI, with StallGuard2.
+///
+/// Each driver is expected to be its own [`SpiDevice`] (i.e. its own chip select),
+/// so the node address is ignored and boards should use [`crate::board::DriverAddress::dedicated_buses`].
+///
+/// StallGuard2 is only valid in SpreadCycle, so channels relying on it must disable `stealthchop`.
+pub struct Tmc5160;
+
+const WRITE: u8 = 0x80;
+
+const GCONF: u8 = 0x00;
+const GSTAT: u8 = 0x01;
+const IHOLD_IRUN: u8 = 0x10;
+const TPWMTHRS: u8 = 0x13;
+const TCOOLTHRS: u8 = 0x14;
+const CHOPCONF: u8 = 0x6C;
+const COOLCONF: u8 = 0x6D;
+const DRV_STATUS: u8 = 0x6F;
+
+/// Readable registers, excluding the motion controller which is unused in STEP/DIR mode
+const READABLE_REGISTERS: [u8; 13] = [
+    0x00, 0x01, 0x04, 0x07, 0x0C, 0x12, 0x6A, 0x6B, 0x6C, 0x6F, 0x71, 0x72, 0x73,
+];
+/// Writable registers, OTP_PROG and FACTORY_CONF are deliberately absent, as OTP programming is irreversible
+const WRITABLE_REGISTERS: [u8; 14] = [
+    0x00, 0x01, 0x09, 0x0A, 0x0B, 0x10, 0x11, 0x13, 0x14, 0x15, 0x6C, 0x6D, 0x6E, 0x70,
+];
+/// Registers which are safe to write without `force`, i.e. thresholds and timings which can't damage the hardware
+const UNGUARDED_REGISTERS: [u8; 6] = [0x01, 0x11, 0x13, 0x14, 0x15, 0x6D];
+
+/// Set in DRV_STATUS while StallGuard2 sees a stall
+const DRV_STATUS_STALLGUARD: u32 = 1 << 24;
+/// Multistep filtering is on by default, and should be left on for STEP/DIR operation
+const GCONF_MULTISTEP_FILT: u32 = 1 << 3;
+const GCONF_EN_PWM_MODE: u32 = 1 << 2;
+/// TOFF=3, HSTRT=4, HEND=1, TBL=2 as suggested by the datasheet for a first run
+const CHOPCONF_BASE: u32 = 0x0001_00C3;
+
+impl<S> DriverFamily<S> for Tmc5160
+where
+    S: SpiDevice,
+{
+    async fn configure(spi: &mut S, _node: u8, config: &DriverConfig) -> Result<(), DriverError> {
+        let mut gconf = GCONF_MULTISTEP_FILT;
+        if config.stealthchop {
+            gconf |= GCONF_EN_PWM_MODE;
+        }
+        // MRES counts down from 256 microsteps at 0, to full steps at 8
+        let mres = 8 - config.microstep_exponent() as u32;
+        let chopconf = CHOPCONF_BASE | (mres << 24);
+        let ihold_irun = (config.hold_current as u32 & 0x1F)
+            | ((config.run_current as u32 & 0x1F) << 8)
+            | (6 << 16); // Power-on default, gradual reduction to hold current
+
+        write(spi, GCONF, gconf).await?;
+        write(spi, CHOPCONF, chopconf).await?;
+        write(spi, IHOLD_IRUN, ihold_irun).await?;
+        write(spi, TPWMTHRS, 0).await?;
+        write(spi, TCOOLTHRS, 0xFFFFF).await?;
+        #[cfg(feature = "stallguard")]
+        write(spi, COOLCONF, coolconf(100)).await?;
+
+        // There's no transmission counter on SPI, read back what can be read back instead
+        if read(spi, GCONF).await? != gconf || read(spi, CHOPCONF).await? != chopconf {
+            return Err(DriverError::NotAcknowledged);
+        }
+
+        Ok(())
+    }
+
+    async fn read_status(spi: &mut S, _node: u8) -> DriverStatus {
+        let mut status = DriverStatus::default();
+
+        match read(spi, DRV_STATUS).await {
+            Ok(drv_status) => {
+                let bit = |n: u32| (drv_status >> n) & 0b1 == 1;
+                status.s2vsa = bit(12);
+                status.s2vsb = bit(13);
+                status.ot = bit(25);
+                status.otpw = bit(26);
+                status.s2ga = bit(27);
+                status.s2gb = bit(28);
+                status.ola = bit(29);
+                status.olb = bit(30);
+            }
+            Err(_) => status.comms_error = true,
+        }
+
+        match read(spi, GSTAT).await {
+            Ok(gstat) => {
+                status.reset = gstat & 0b001 != 0;
+                status.drv_err = gstat & 0b010 != 0;
+                status.uv_cp = gstat & 0b100 != 0;
+
+                // GSTAT flags are latched until a 1 is written back to them
+                if gstat & 0b111 != 0 && write(spi, GSTAT, gstat).await.is_err() {
+                    warn!("Failed to clear GSTAT");
+                }
+            }
+            Err(_) => status.comms_error = true,
+        }
+
+        status
+    }
+
+    async fn read_register(spi: &mut S, _node: u8, reg: u8) -> Result<u32, DriverError> {
+        if !READABLE_REGISTERS.contains(&reg) {
+            return Err(DriverError::Unsupported);
+        }
+
+        read(spi, reg).await
+    }
+
+    async fn write_register(
+        spi: &mut S,
+        _node: u8,
+        reg: u8,
+        value: u32,
+        force: bool,
+    ) -> Result<(), DriverError> {
+        if !WRITABLE_REGISTERS.contains(&reg) {
+            return Err(DriverError::Unsupported);
+        }
+        if !force && !UNGUARDED_REGISTERS.contains(&reg) {
+            return Err(DriverError::Guarded);
+        }
+
+        write(spi, reg, value).await
+    }
+
+    async fn set_sg_threshold(spi: &mut S, _node: u8, sgthrs: u8) {
+        if write(spi, COOLCONF, coolconf(sgthrs)).await.is_err() {
+            warn!("Failed to program COOLCONF");
+        }
+    }
+
+    /// Reads on SPI are pipelined, so the request is a transfer whose reply is discarded
+    async fn request_sg_result(spi: &mut S, _node: u8) -> bool {
+        transfer(spi, DRV_STATUS, 0).await.is_ok()
+    }
+
+    /// The 10-bit SG_RESULT is quartered to match the halved 8-bit result of the TMC2209
+    async fn await_sg_result_halved(spi: &mut S, _node: u8) -> Option<u8> {
+        match transfer(spi, DRV_STATUS, 0).await {
+            Ok(drv_status) => Some(((drv_status & 0x3FF) >> 2) as u8),
+            Err(_) => {
+                warn!("Failed to read SG_RESULT");
+                None
+            }
+        }
+    }
+
+    /// StallGuard2 results fall towards 0 at a stall relative to the signed SGT, rather than an SGTHRS,
+    /// so the driver's own StallGuard flag is taken instead
+    async fn await_stall(spi: &mut S, _node: u8, _sgthrs: u8) -> Option<bool> {
+        match transfer(spi, DRV_STATUS, 0).await {
+            Ok(drv_status) => Some(drv_status & DRV_STATUS_STALLGUARD != 0),
+            Err(_) => {
+                warn!("Failed to read DRV_STATUS");
+                None
+            }
+        }
+    }
+}
+
+/// COOLCONF with CoolStep disabled and the StallGuard2 threshold set.
+///
+/// SGT is a signed 7-bit value where lower is more sensitive, whereas SGTHRS is the opposite,
+/// so 0 maps onto the least sensitive SGT of 63, and 255 onto the most sensitive of -64.
+fn coolconf(sgthrs: u8) -> u32 {
+    let sgt = 63 - (sgthrs >> 1) as i8;
+    ((sgt as u32) & 0x7F) << 16
+}
+
+/// Sends a 40-bit datagram, returning the data shifted out in reply to the previous datagram
+async fn transfer<S: SpiDevice>(spi: &mut S, addr: u8, value: u32) -> Result<u32, DriverError> {
+    let mut buf = [0u8; 5];
+    buf[0] = addr;
+    buf[1..].copy_from_slice(&value.to_be_bytes());
+
+    spi.transfer_in_place(&mut buf)
+        .await
+        .map_err(|_| DriverError::NotResponding)?;
+
+    Ok(u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]))
+}
+
+async fn read<S: SpiDevice>(spi: &mut S, reg: u8) -> Result<u32, DriverError> {
+    transfer(spi, reg, 0).await?;
+    transfer(spi, reg, 0).await
+}
+
+async fn write<S: SpiDevice>(spi: &mut S, reg: u8, value: u32) -> Result<(), DriverError> {
+    transfer(spi, reg | WRITE, value).await.map(|_| ())
+}
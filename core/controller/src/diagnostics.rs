@@ -0,0 +1,209 @@
+use crate::board::{
+    BoardSensors, ConfigurableStepStickDriver, ControlLoopInvoke, DriverDiagnostics, Thermometer,
+};
+#[cfg(feature = "stallguard")]
+use crate::board::StallGuard;
+use crate::rpc::OutgoingRpcPacket;
+use crate::sensors::SensorReporter;
+use crate::thermal::{derated, ThermalMonitor};
+use crate::*;
+use embassy_executor::Spawner;
+
+/// Configures the drivers, then polls them for faults and StallGuard, alongside the board's own state
+/// temperature and sensors.
+///
+/// Bus transactions are slow, particularly on a soft half duplex UART, but only ever hold up this task
+/// and the host task's driver commands.
+#[cfg(feature = "stallguard")]
+pub async fn run<D, T, S, const N: usize>(
+    drivers: &DriverMutex<'_, D>,
+    board_state: &mut T,
+    spawner: &mut Spawner,
+) where
+    D: ConfigurableStepStickDriver<S, N> + StallGuard<S, N> + DriverDiagnostics<S, N>,
+    T: ControlLoopInvoke + Thermometer + BoardSensors,
+{
+    drivers.lock().await.bus.configure_driver().await;
+
+    let mut next_diagnostics = Instant::now();
+    let mut diagnostics_cursor = 0;
+    let mut moving_since = [None; DRIVERS];
+    let mut thermal = ThermalMonitor::new(board_state);
+    let mut sensors = SensorReporter::new();
+
+    loop {
+        board_state.invoke(spawner).await;
+
+        if thermal.poll(board_state) {
+            reconfigure_set_up(drivers).await;
+        }
+        sensors.poll(board_state).await;
+
+        let now = Instant::now();
+        if now >= next_diagnostics {
+            next_diagnostics = now + DIAGNOSTICS_INTERVAL;
+            poll_driver_status(drivers, &mut diagnostics_cursor).await;
+        }
+        poll_stallguard(drivers, &mut moving_since).await;
+
+        if option_env!("LOG_SG_RESULT").is_some() {
+            print_sg_result(drivers, SET_UP.load(Ordering::Acquire)).await;
+        }
+
+        // Sensorless channels must be polled often enough to catch a stall soon after blanking
+        let sensorless = SENSORLESS.load(Ordering::Relaxed) & SET_UP.load(Ordering::Acquire);
+        let wake_at = if sensorless != 0 {
+            next_diagnostics.min(Instant::now() + SG_POLL_INTERVAL)
+        } else {
+            next_diagnostics.min(Instant::now() + IDLE_WAKE)
+        };
+        Timer::at(wake_at).await;
+    }
+}
+
+#[cfg(feature = "stallguard")]
+async fn print_sg_result<D, S, const N: usize>(drivers: &DriverMutex<'_, D>, channels: u16)
+where
+    D: StallGuard<S, N>,
+{
+    // I do incur a bit of performance penalty querying all channels (used or not)
+    // over a single UART and waiting for a response for every single one.
+    // But at least I'm not creating a race condition in async.
+    //
+    // Also, this class doesn't discriminate for the underlying write protocol.
+    // This is actually the worst case assumption.
+    // I know the Manta doesn't have a single shared serial bus, the octopus doesn't even use UART!
+    //
+    // According to my own measurements this function takes 200-300ms,
+    // which only holds up this task and the host's driver commands.
+    let sgresult2 = drivers
+        .lock()
+        .await
+        .bus
+        .get_sg_results_halved(channels)
+        .await;
+
+    defmt::debug!("SG_RESULT/2 = {}", sgresult2);
+}
+
+/// Raises the endstop of sensorless channels whose driver sees a stall mid-travel.
+///
+/// Only moving channels are queried, past the blanking period after they started moving,
+/// so the bus isn't held up by channels at rest.
+#[cfg(feature = "stallguard")]
+async fn poll_stallguard<D, S, const N: usize>(
+    drivers: &DriverMutex<'_, D>,
+    moving_since: &mut [Option<Instant>; DRIVERS],
+) where
+    D: StallGuard<S, N>,
+{
+    let now = Instant::now();
+    let mut channels = 0u16;
+
+    for i in 0..DRIVERS {
+        if !is_flagged(&SET_UP, i) || !is_flagged(&SENSORLESS, i) || !is_flagged(&MOVING, i) {
+            moving_since[i] = None;
+            continue;
+        }
+
+        let since = *moving_since[i].get_or_insert(now);
+        if now - since >= SG_BLANKING {
+            channels |= 1 << i;
+        }
+    }
+
+    if channels == 0 {
+        return;
+    }
+
+    let mut shared = drivers.lock().await;
+    let sg_thresholds = shared.sg_threshold;
+    let stalls = shared.bus.get_stalls(channels, &sg_thresholds).await;
+    for i in 0..DRIVERS {
+        if (stalls >> i) & 0b1 == 1 {
+            debug!("Stall detected on channel {}", i);
+            STOPS.bit_set(i as u32, Ordering::Release);
+            WAKE.signal(());
+        }
+    }
+}
+
+/// Polls the next set up channel's driver for faults, emitting its status if any flag changed.
+///
+/// Channels with a hard fault are latched off until they're set up again, and the motion task is woken to stop them.
+/// Drivers that were reset or failed configuration are reconfigured, and are held off until verified.
+#[cfg(feature = "stallguard")]
+async fn poll_driver_status<D, S, const N: usize>(drivers: &DriverMutex<'_, D>, cursor: &mut usize)
+where
+    D: ConfigurableStepStickDriver<S, N> + DriverDiagnostics<S, N> + StallGuard<S, N>,
+{
+    let i = if let Some(i) = (1..=DRIVERS)
+        .map(|offset| (*cursor + offset) % DRIVERS)
+        .find(|&i| is_flagged(&SET_UP, i))
+    {
+        i
+    } else {
+        return;
+    };
+    *cursor = i;
+
+    let mut shared = drivers.lock().await;
+    let mut status = shared.bus.get_driver_status(i as u8).await;
+    let moving = is_flagged(&MOVING, i);
+    let was_halted = is_flagged(&FAULTS, i) || is_flagged(&UNCONFIGURED, i);
+
+    if status.is_hard_fault(moving) && !FAULTS.bit_set(i as u32, Ordering::AcqRel) {
+        error!("Hard fault on channel {}, disabling driver: {}", i, status);
+    }
+
+    if status.reset {
+        warn!("Driver on channel {} was reset, reconfiguring...", i);
+        UNCONFIGURED.bit_set(i as u32, Ordering::AcqRel);
+    }
+    if is_flagged(&UNCONFIGURED, i) {
+        let config = derated(&shared.config[i]);
+        match shared.bus.configure_channel(i as u8, &config).await {
+            Ok(()) => shared.restore_sg_threshold(i as u8).await,
+            Err(e) => warn!("Driver on channel {} is not responding: {:?}", i, e),
+        }
+    }
+    status.unconfigured = is_flagged(&UNCONFIGURED, i);
+
+    if !was_halted && (is_flagged(&FAULTS, i) || status.unconfigured) {
+        WAKE.signal(());
+    }
+
+    let changed = status.flags_changed(&shared.status[i]);
+    shared.status[i] = status;
+    drop(shared);
+
+    if changed {
+        warn!("Driver status changed on channel {}: {}", i, status);
+
+        let out = OutgoingRpcPacket::DriverStatus {
+            channel: i as u8,
+            status,
+        };
+        OUTGOING.send(out).await;
+    }
+}
+
+/// Programs every set up channel with its run current derated, or restored, for the board's temperature
+#[cfg(feature = "stallguard")]
+async fn reconfigure_set_up<D, S, const N: usize>(drivers: &DriverMutex<'_, D>)
+where
+    D: ConfigurableStepStickDriver<S, N> + StallGuard<S, N>,
+{
+    let mut shared = drivers.lock().await;
+    for i in 0..DRIVERS {
+        if !is_flagged(&SET_UP, i) {
+            continue;
+        }
+
+        let config = derated(&shared.config[i]);
+        match shared.bus.update_channel(i as u8, &config).await {
+            Ok(()) => shared.restore_sg_threshold(i as u8).await,
+            Err(e) => warn!("Driver on channel {} is not responding: {:?}", i, e),
+        }
+    }
+}
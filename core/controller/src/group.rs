@@ -0,0 +1,479 @@
+use crate::*;
+use serde::Deserialize;
+use sequencer::{
+    CurtainSequencer, Direction, SensingWindowDressingSequencer, TdbuSequencer, TdbuState,
+    VerticalSequencer, WindowDressingState,
+};
+
+/// Single-side curtains drive a single channel, so every channel may be a group of its own
+pub(crate) const MAX_GROUPS: usize = DRIVERS;
+
+/// How set up channels are driven together as one window dressing
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupSetup {
+    /// Top-down/bottom-up shade, whose rails are kept at least `min_gap` percent apart.
+    ///
+    /// Both rails count fully raised as opened. The top rail homes raised into the head rail, and the bottom rail
+    /// homes fully lowered, away from the top rail, so each needs its endstop there.
+    Tdbu { top: u8, bottom: u8, min_gap: u8 },
+    /// Vertical blind, whose vanes are traversed by one channel and rotated by the other.
+    ///
+    /// The vanes are turned to `stack_tilt` ahead of every traverse, square to the window by default.
+    /// The rotation channel is set up with its full tilt steps, and its travel is kept at fully closed.
+    Vertical {
+        traverse: u8,
+        rotation: u8,
+        stack_tilt: Option<i8>,
+    },
+    /// Curtain drawn to one side by `left`, or split in the centre with `right` drawing the other half.
+    ///
+    /// Each side opens by turning its motor the given way, retracting on the left and extending on the right
+    /// by default, which replaces the channels' own reversal.
+    Curtain {
+        left: u8,
+        right: Option<u8>,
+        left_opens: Option<Direction>,
+        right_opens: Option<Direction>,
+        /// Draw the curtain when a side is tugged off its endstop at rest,
+        /// so only a curtain resting against its endstops senses a tug
+        touch_to_move: Option<bool>,
+    },
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupTarget {
+    /// In percent lowered from the head rail, rails left out stay where they are asked to be
+    Tdbu { top: Option<u8>, bottom: Option<u8> },
+    Vertical {
+        position: Option<u8>,
+        tilt: Option<i8>,
+    },
+    Curtain {
+        position: u8,
+    },
+}
+
+/// Combined state of a group, reported along with the position of each of its channels
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupState {
+    Tdbu {
+        group: u8,
+        current: TdbuState,
+        desired: TdbuState,
+    },
+    Vertical {
+        group: u8,
+        current: WindowDressingState,
+        desired: WindowDressingState,
+    },
+    Curtain {
+        group: u8,
+        current: u8,
+        desired: u8,
+    },
+}
+
+/// Channels driven as one window dressing, planned by the motion task on top of their own sequencers
+pub(crate) struct Group {
+    sequencer: GroupSequencer,
+    /// Set while a channel is homing on its own, after which the group takes its channels as they are
+    homing: bool,
+}
+
+enum GroupSequencer {
+    Tdbu {
+        top: u8,
+        bottom: u8,
+        seq: TdbuSequencer,
+    },
+    Vertical {
+        traverse: u8,
+        rotation: u8,
+        seq: VerticalSequencer,
+    },
+    Curtain {
+        left: u8,
+        right: Option<u8>,
+        seq: CurtainSequencer,
+    },
+}
+
+/// Borrows the sequencers of two distinct, set up channels
+fn pair<Q>(seqs: &mut [Option<Q>], a: u8, b: u8) -> Option<(&mut Q, &mut Q)> {
+    match seqs.get_disjoint_mut([a as usize, b as usize]) {
+        Ok([Some(a), Some(b)]) => Some((a, b)),
+        _ => None,
+    }
+}
+
+/// Borrows the sequencers of a curtain's sides, where a single side needs no `right`
+fn sides<Q>(seqs: &mut [Option<Q>], left: u8, right: Option<u8>) -> Option<(&mut Q, Option<&mut Q>)> {
+    if let Some(right) = right {
+        let (left, right) = pair(seqs, left, right)?;
+        Some((left, Some(right)))
+    } else {
+        Some((seqs.get_mut(left as usize)?.as_mut()?, None))
+    }
+}
+
+impl Group {
+    /// Groups the channels, unless any of them is out of range, repeated or not set up
+    pub(crate) fn new<Q>(setup: GroupSetup, seqs: &mut [Option<Q>]) -> Option<Self>
+    where
+        Q: SensingWindowDressingSequencer,
+    {
+        let sequencer = match setup {
+            GroupSetup::Tdbu {
+                top,
+                bottom,
+                min_gap,
+            } => {
+                let (top_seq, bottom_seq) = pair(seqs, top, bottom)?;
+                GroupSequencer::Tdbu {
+                    top,
+                    bottom,
+                    seq: TdbuSequencer::new(min_gap, top_seq, bottom_seq),
+                }
+            }
+            GroupSetup::Vertical {
+                traverse,
+                rotation,
+                stack_tilt,
+            } => {
+                let (traverse_seq, rotation_seq) = pair(seqs, traverse, rotation)?;
+                GroupSequencer::Vertical {
+                    traverse,
+                    rotation,
+                    seq: VerticalSequencer::new(
+                        stack_tilt.unwrap_or(0),
+                        traverse_seq,
+                        rotation_seq,
+                    ),
+                }
+            }
+            GroupSetup::Curtain {
+                left,
+                right,
+                left_opens,
+                right_opens,
+                touch_to_move,
+            } => {
+                let (left_seq, right_seq) = sides(seqs, left, right)?;
+                let seq = CurtainSequencer::new(left_seq, right_seq.as_deref())
+                    .with_open_directions(
+                        left_opens.unwrap_or(Direction::Retract),
+                        right_opens.unwrap_or(Direction::Extend),
+                    )
+                    .with_touch_to_move(touch_to_move.unwrap_or(false));
+                GroupSequencer::Curtain { left, right, seq }
+            }
+        };
+
+        Some(Group {
+            sequencer,
+            homing: false,
+        })
+    }
+
+    /// Channels of the group, as a bitmask
+    pub(crate) fn channels(&self) -> u16 {
+        match self.sequencer {
+            GroupSequencer::Tdbu { top, bottom, .. } => (0b1 << top) | (0b1 << bottom),
+            GroupSequencer::Vertical {
+                traverse, rotation, ..
+            } => (0b1 << traverse) | (0b1 << rotation),
+            GroupSequencer::Curtain { left, right, .. } => {
+                (0b1 << left) | right.map_or(0, |right| 0b1 << right)
+            }
+        }
+    }
+
+    /// Channels whose reversal is set by the group, and which of them are reversed, as bitmasks
+    pub(crate) fn reversals(&self) -> (u16, u16) {
+        match self.sequencer {
+            GroupSequencer::Curtain {
+                left,
+                right,
+                ref seq,
+            } => {
+                let [left_opens, right_opens] = *seq.get_open_directions();
+                let reversed = |channel: u8, opens| {
+                    if opens == Direction::Extend {
+                        0b1 << channel
+                    } else {
+                        0
+                    }
+                };
+                let reversed =
+                    reversed(left, left_opens) | right.map_or(0, |right| reversed(right, right_opens));
+                (self.channels(), reversed)
+            }
+            _ => (0, 0),
+        }
+    }
+
+    /// Channels whose endstop released at rest is taken as a tug, as a bitmask
+    pub(crate) fn touch(&self) -> u16 {
+        match self.sequencer {
+            GroupSequencer::Curtain { ref seq, .. } if seq.is_touch_to_move() => self.channels(),
+            _ => 0,
+        }
+    }
+
+    /// Draws a touch to move curtain which was tugged at rest, returning whether it was sent
+    pub(crate) fn tug<Q>(&mut self, seqs: &mut [Option<Q>]) -> bool
+    where
+        Q: SensingWindowDressingSequencer,
+    {
+        let position = match self.sequencer {
+            GroupSequencer::Curtain {
+                left,
+                right,
+                ref seq,
+            } => sides(seqs, left, right)
+                .and_then(|(left, right)| seq.get_tug_target(&*left, right.as_deref())),
+            _ => None,
+        };
+
+        position.is_some_and(|position| self.set(GroupTarget::Curtain { position }, seqs))
+    }
+
+    /// Homes `channel` on its own, returning false rather than dragging the vanes of a vertical blind
+    pub(crate) fn home<Q>(&mut self, channel: u8, seqs: &mut [Option<Q>]) -> bool
+    where
+        Q: SensingWindowDressingSequencer,
+    {
+        match self.sequencer {
+            GroupSequencer::Tdbu {
+                top,
+                bottom,
+                ref mut seq,
+            } => {
+                if let Some(rail) = seqs.get_mut(channel as usize).and_then(Option::as_mut) {
+                    if channel == bottom {
+                        seq.home_bottom(rail);
+                    } else if channel == top {
+                        seq.home_top(rail);
+                    }
+                }
+                true
+            }
+            GroupSequencer::Vertical {
+                traverse,
+                rotation,
+                ref seq,
+            } => {
+                let stacked = seqs
+                    .get(rotation as usize)
+                    .and_then(Option::as_ref)
+                    .is_some_and(|rotation| seq.is_stacked(rotation));
+                if channel == traverse && !stacked {
+                    return false;
+                }
+
+                if let Some(seq) = seqs.get_mut(channel as usize).and_then(Option::as_mut) {
+                    seq.home_fully_opened();
+                }
+                true
+            }
+            GroupSequencer::Curtain { .. } => {
+                if let Some(seq) = seqs.get_mut(channel as usize).and_then(Option::as_mut) {
+                    seq.home_fully_opened();
+                }
+                true
+            }
+        }
+    }
+
+    /// Returns whether the target was meant for this kind of group
+    pub(crate) fn set<Q>(&mut self, target: GroupTarget, seqs: &mut [Option<Q>]) -> bool
+    where
+        Q: SensingWindowDressingSequencer,
+    {
+        match (&mut self.sequencer, target) {
+            (
+                GroupSequencer::Tdbu { top, bottom, seq },
+                GroupTarget::Tdbu {
+                    top: top_lowered,
+                    bottom: bottom_lowered,
+                },
+            ) => {
+                if let Some((top, bottom)) = pair(seqs, *top, *bottom) {
+                    seq.set_state(top_lowered, bottom_lowered, top, bottom);
+                }
+            }
+            (
+                GroupSequencer::Vertical {
+                    traverse,
+                    rotation,
+                    seq,
+                },
+                GroupTarget::Vertical { position, tilt },
+            ) => {
+                if let Some((traverse, rotation)) = pair(seqs, *traverse, *rotation) {
+                    seq.set_state(position, tilt, traverse, rotation);
+                }
+            }
+            (GroupSequencer::Curtain { left, right, seq }, GroupTarget::Curtain { position }) => {
+                if let Some((left, right)) = sides(seqs, *left, *right) {
+                    if !seq.set_position(position, left, right) {
+                        warn!("Curtain sides won't arrive together, as a side's queue is full");
+                    }
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Takes the channels as they are, dropping any moves held back
+    pub(crate) fn sync<Q>(&mut self, seqs: &mut [Option<Q>])
+    where
+        Q: SensingWindowDressingSequencer,
+    {
+        match self.sequencer {
+            GroupSequencer::Tdbu {
+                top,
+                bottom,
+                ref mut seq,
+            } => {
+                if let Some((top, bottom)) = pair(seqs, top, bottom) {
+                    seq.sync(top, bottom);
+                }
+            }
+            GroupSequencer::Vertical {
+                traverse,
+                rotation,
+                ref mut seq,
+            } => {
+                if let Some((traverse, rotation)) = pair(seqs, traverse, rotation) {
+                    seq.sync(traverse, rotation);
+                }
+            }
+            GroupSequencer::Curtain {
+                left,
+                right,
+                ref mut seq,
+            } => {
+                if let Some((left, right)) = sides(seqs, left, right) {
+                    seq.sync(left, right.as_deref());
+                }
+            }
+        }
+    }
+
+    /// Carries on with moves which had to wait for the other channels, given the channels which are `idle`.
+    ///
+    /// Returns whether any channel was sent.
+    pub(crate) fn advance<Q>(&mut self, seqs: &mut [Option<Q>], idle: u16) -> bool
+    where
+        Q: SensingWindowDressingSequencer,
+    {
+        let channels = self.channels();
+        let homing = (0..seqs.len()).any(|i| {
+            (channels >> i) & 0b1 == 1 && seqs[i].as_ref().is_some_and(|seq| seq.is_homing())
+        });
+        if homing {
+            self.homing = true;
+            return false;
+        } else if self.homing {
+            self.homing = false;
+            self.sync(seqs);
+            return false;
+        }
+
+        match self.sequencer {
+            GroupSequencer::Tdbu {
+                top,
+                bottom,
+                ref mut seq,
+            } => {
+                let top_idle = (idle >> top) & 0b1 == 1;
+                let bottom_idle = (idle >> bottom) & 0b1 == 1;
+                if let Some((top, bottom)) = pair(seqs, top, bottom) {
+                    seq.advance(top, top_idle, bottom, bottom_idle)
+                } else {
+                    false
+                }
+            }
+            GroupSequencer::Vertical {
+                traverse,
+                rotation,
+                ref mut seq,
+            } => {
+                let traverse_idle = (idle >> traverse) & 0b1 == 1;
+                let rotation_idle = (idle >> rotation) & 0b1 == 1;
+                if let Some((traverse, rotation)) = pair(seqs, traverse, rotation) {
+                    seq.advance(traverse, traverse_idle, rotation, rotation_idle)
+                } else {
+                    false
+                }
+            }
+            // Both sides are sent at once, and held back by their own sequencers
+            GroupSequencer::Curtain { .. } => false,
+        }
+    }
+
+    pub(crate) fn state<Q>(&self, group: u8, seqs: &[Option<Q>]) -> Option<GroupState>
+    where
+        Q: SensingWindowDressingSequencer,
+    {
+        match self.sequencer {
+            GroupSequencer::Tdbu {
+                top,
+                bottom,
+                ref seq,
+            } => {
+                let top = seqs.get(top as usize)?.as_ref()?;
+                let bottom = seqs.get(bottom as usize)?.as_ref()?;
+                Some(GroupState::Tdbu {
+                    group,
+                    current: seq.get_current_state(top, bottom),
+                    desired: *seq.get_desired_state(),
+                })
+            }
+            GroupSequencer::Vertical {
+                traverse,
+                rotation,
+                ref seq,
+            } => {
+                let traverse = seqs.get(traverse as usize)?.as_ref()?;
+                let rotation = seqs.get(rotation as usize)?.as_ref()?;
+                Some(GroupState::Vertical {
+                    group,
+                    current: seq.get_current_state(traverse, rotation),
+                    desired: *seq.get_desired_state(),
+                })
+            }
+            GroupSequencer::Curtain {
+                left,
+                right,
+                ref seq,
+            } => {
+                let left = seqs.get(left as usize)?.as_ref()?;
+                let right = match right {
+                    Some(right) => Some(seqs.get(right as usize)?.as_ref()?),
+                    None => None,
+                };
+                Some(GroupState::Curtain {
+                    group,
+                    current: seq.get_current_state(left, right),
+                    desired: seq.get_desired_state(),
+                })
+            }
+        }
+    }
+}
+
+/// The group driving `channel`, if any
+pub(crate) fn group_of(groups: &[Option<Group>], channel: u8) -> Option<usize> {
+    groups.iter().position(|group| {
+        group
+            .as_ref()
+            .is_some_and(|group| (group.channels() >> channel) & 0b1 == 1)
+    })
+}
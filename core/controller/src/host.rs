@@ -0,0 +1,387 @@
+use crate::board::{ConfigurableStepStickDriver, DriverConfig, DriverRegisterAccess};
+#[cfg(feature = "stallguard")]
+use crate::board::StallGuard;
+use crate::rpc::{AsyncRpc, AsyncRpcError, IncomingRpcPacket, OutgoingRpcPacket, SetupError};
+use crate::sensors::{request_sensors, set_sensor_interval};
+use crate::thermal::{derated, THERMAL_LIMITS};
+use crate::*;
+use embassy_futures::select::select4;
+use heapless::Vec;
+
+/// Parses commands from the host, and writes out the packets queued by the other tasks.
+///
+/// Driver bus commands are run here under the [`DriverMutex`], motion commands are passed on to the motion task.
+#[cfg(feature = "stallguard")]
+pub async fn run<H, D, S, const N: usize>(host: &mut H, drivers: &DriverMutex<'_, D>)
+where
+    H: AsyncRpc,
+    D: ConfigurableStepStickDriver<S, N> + StallGuard<S, N> + DriverRegisterAccess<S, N>,
+{
+    await_setup(host).await;
+
+    let mut resetting = false;
+    loop {
+        flush_outgoing(host).await;
+
+        // Limit the consumption of commands so the outgoing queue is flushed in between,
+        // But also make it a bit greedy
+        for _ in 0..DRIVERS {
+            match host.read().await {
+                Ok(Some(packet)) => apply_packet(host, drivers, packet).await,
+                Ok(None) => {
+                    break;
+                }
+                Err(e) => {
+                    warn!("Failed to read from host: {:?}", e);
+                    if e.is_broken_input() && !resetting {
+                        resetting = true;
+                        MOTION_COMMANDS.send(MotionCommand::Reset).await;
+                    }
+                }
+            }
+        }
+
+        select4(
+            host.wait_readable(),
+            OUTGOING.ready_to_receive(),
+            LATEST_READY.wait(),
+            Timer::after(IDLE_WAKE),
+        )
+        .await;
+    }
+}
+
+/// Flags the ready state until the host sends its first `Setup`, draining anything else
+async fn await_setup<H: AsyncRpc>(host: &mut H) {
+    loop {
+        let incoming = host.peek().await.unwrap_or(None);
+
+        match incoming {
+            Some(IncomingRpcPacket::Setup { .. }) => {
+                debug!("Received setup command. Continuing...");
+                break;
+            }
+            Some(_) => {
+                debug!("Received non-setup command. Draining...");
+                let _ = host.read().await;
+                Timer::after_millis(50).await; // Drain should be more eager than the less-intensive waiting for a new command
+                continue;
+            }
+            None => {
+                let _ = host.write(&OutgoingRpcPacket::Ready {}).await;
+                debug!("Flagged ready state.");
+                Timer::after_secs(1).await;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "stallguard")]
+async fn apply_packet<H, D, S, const N: usize>(
+    host: &mut H,
+    drivers: &DriverMutex<'_, D>,
+    packet: IncomingRpcPacket,
+) where
+    H: AsyncRpc,
+    D: ConfigurableStepStickDriver<S, N> + StallGuard<S, N> + DriverRegisterAccess<S, N>,
+{
+    match packet {
+        IncomingRpcPacket::Home { channel } => {
+            MOTION_COMMANDS.send(MotionCommand::Home { channel }).await;
+        }
+        IncomingRpcPacket::Setup {
+            channel,
+            init,
+            full_cycle_steps,
+            reverse,
+            full_tilt_steps,
+            back_off,
+            travel_limit_tolerance,
+            endstop,
+            #[cfg(feature = "stallguard")]
+            sgthrs,
+            #[cfg(feature = "stallguard")]
+            sensorless,
+            run_current,
+            hold_current,
+            microsteps,
+            stealthchop,
+            #[cfg(feature = "brownout-protection")]
+            run_draw_ma,
+            #[cfg(feature = "brownout-protection")]
+            inrush_draw_ma,
+        } => {
+            let mut shared = drivers.lock().await;
+            let config = merge_driver_config(
+                shared.config[channel as usize],
+                run_current,
+                hold_current,
+                microsteps,
+                stealthchop,
+            );
+            let scale = config.microsteps as u32;
+            let steps = (
+                full_cycle_steps.checked_mul(scale),
+                full_tilt_steps.map(|steps| steps.checked_mul(scale)),
+            );
+            let (full_cycle_steps, full_tilt_steps) = match steps {
+                (Some(cycle), None) => (cycle, None),
+                (Some(cycle), Some(Some(tilt))) => (cycle, Some(tilt)),
+                _ => {
+                    warn!(
+                        "Rejecting setup of channel {}: travel overflows at {} microsteps",
+                        channel, scale
+                    );
+                    drop(shared);
+
+                    let out = OutgoingRpcPacket::SetupError {
+                        channel,
+                        error: SetupError::TravelOverflow,
+                    };
+                    if let Err(e) = host.write(&out).await {
+                        error!("Failed to write SetupError: {:?}", e);
+                    }
+                    return;
+                }
+            };
+
+            if FAULTS.bit_clear(channel as u32, Ordering::AcqRel) {
+                info!("Clearing latched driver fault on channel {}", channel);
+            }
+            let reconfigure = is_flagged(&UNCONFIGURED, channel as usize)
+                || config != shared.config[channel as usize];
+            if reconfigure {
+                if let Err(e) = shared.bus.configure_channel(channel, &derated(&config)).await {
+                    warn!("Driver on channel {} is not responding: {:?}", channel, e);
+                }
+            }
+            shared.config[channel as usize] = config;
+
+            // Configuring the driver resets its threshold, so the one set up before is kept unless replaced
+            #[cfg(feature = "stallguard")]
+            if reconfigure || sgthrs.is_some() {
+                if let Some(sgthrs) = sgthrs {
+                    shared.sg_threshold[channel as usize] = sgthrs;
+                }
+                shared.restore_sg_threshold(channel).await;
+            }
+            drop(shared);
+
+            if let Some(endstop) = endstop {
+                ENDSTOP_CONFIG[channel as usize].signal(endstop);
+            }
+
+            if reverse.unwrap_or(false) {
+                SETUP_REVERSALS.bit_set(channel as u32, Ordering::Relaxed);
+            } else {
+                SETUP_REVERSALS.bit_clear(channel as u32, Ordering::Relaxed);
+            }
+
+            #[cfg(feature = "stallguard")]
+            if sensorless.unwrap_or(false) {
+                SENSORLESS.bit_set(channel as u32, Ordering::Relaxed);
+            } else {
+                SENSORLESS.bit_clear(channel as u32, Ordering::Relaxed);
+            }
+
+            let command = MotionCommand::Setup {
+                channel,
+                init,
+                full_cycle_steps,
+                full_tilt_steps,
+                step_frequency: FREQUENCY as u32 * scale,
+                back_off: back_off.unwrap_or(0).min(100),
+                travel_limit_tolerance: travel_limit_tolerance
+                    .unwrap_or(TRAVEL_LIMIT_TOLERANCE)
+                    .min(50),
+                #[cfg(feature = "brownout-protection")]
+                run_draw_ma,
+                #[cfg(feature = "brownout-protection")]
+                inrush_draw_ma,
+            };
+            MOTION_COMMANDS.send(command).await;
+        }
+        IncomingRpcPacket::Set {
+            channel,
+            position,
+            tilt,
+        } => {
+            let command = MotionCommand::Set {
+                channel,
+                position,
+                tilt,
+            };
+            MOTION_COMMANDS.send(command).await;
+        }
+        IncomingRpcPacket::Get { channel } => {
+            MOTION_COMMANDS.send(MotionCommand::Get { channel }).await;
+        }
+        IncomingRpcPacket::SetupGroup { group, kind } => {
+            MOTION_COMMANDS
+                .send(MotionCommand::SetupGroup { group, kind })
+                .await;
+        }
+        IncomingRpcPacket::SetGroup { group, target } => {
+            MOTION_COMMANDS
+                .send(MotionCommand::SetGroup { group, target })
+                .await;
+        }
+        IncomingRpcPacket::GetSensors {} => request_sensors(),
+        IncomingRpcPacket::SensorReporting { interval_s } => set_sensor_interval(interval_s),
+        IncomingRpcPacket::ThermalLimits { limits } => {
+            THERMAL_LIMITS.signal(limits);
+        }
+        #[cfg(feature = "stallguard")]
+        IncomingRpcPacket::GetStallGuardResult { channel } => {
+            let sg_result = drivers
+                .lock()
+                .await
+                .bus
+                .get_sg_result_halved(channel)
+                .await
+                .unwrap_or(0);
+            let out = OutgoingRpcPacket::StallGuardResult { channel, sg_result };
+
+            if let Err(e) = host.write(&out).await {
+                error!("Failed to write StallGuardResult: {:?}", e);
+            }
+        }
+        IncomingRpcPacket::ReadRegister { channel, reg } => {
+            let result = drivers.lock().await.bus.read_register(channel, reg).await;
+            let out = match result {
+                Ok(value) => OutgoingRpcPacket::Register {
+                    channel,
+                    reg,
+                    value,
+                },
+                Err(error) => OutgoingRpcPacket::RegisterError {
+                    channel,
+                    reg,
+                    error,
+                },
+            };
+
+            if let Err(e) = host.write(&out).await {
+                error!("Failed to write Register: {:?}", e);
+            }
+        }
+        IncomingRpcPacket::WriteRegister {
+            channel,
+            reg,
+            value,
+            force,
+        } => {
+            warn!(
+                "Raw write of {:#x} to register {:#x} on channel {}",
+                value, reg, channel
+            );
+            let result = drivers
+                .lock()
+                .await
+                .bus
+                .write_register(channel, reg, value, force.unwrap_or(false))
+                .await;
+            let out = match result {
+                Ok(()) => OutgoingRpcPacket::Register {
+                    channel,
+                    reg,
+                    value,
+                },
+                Err(error) => OutgoingRpcPacket::RegisterError {
+                    channel,
+                    reg,
+                    error,
+                },
+            };
+
+            if let Err(e) = host.write(&out).await {
+                error!("Failed to write Register: {:?}", e);
+            }
+        }
+        IncomingRpcPacket::Bootloader => {
+            MOTION_COMMANDS.send(MotionCommand::Bootloader).await;
+        }
+    }
+}
+
+/// Writes out what the motion and diagnostics tasks have queued, in a single bulk write.
+///
+/// Faults and positions are taken first, as they're coalesced rather than queued and are never dropped.
+/// `Sensors` packets fill most of a write by themselves, so they're written on their own after the rest.
+async fn flush_outgoing<H: AsyncRpc>(host: &mut H) {
+    let mut packets = Vec::<_, { OUTGOING_DEPTH + 2 * DRIVERS }>::new();
+    LATEST.lock(|latest| {
+        let mut latest = latest.borrow_mut();
+        for (channel, fault) in latest.faults.iter_mut().enumerate() {
+            if let Some(fault) = fault.take() {
+                let channel = channel as u8;
+                let _ = packets.push(OutgoingRpcPacket::MotionFault { channel, fault });
+            }
+        }
+        for position in latest.positions.iter_mut() {
+            if let Some(position) = position.take() {
+                let _ = packets.push(position);
+            }
+        }
+    });
+
+    while !packets.is_full() {
+        match OUTGOING.try_receive() {
+            Ok(packet) => {
+                let _ = packets.push(packet);
+            }
+            Err(_) => break,
+        }
+    }
+
+    if packets.is_empty() {
+        return;
+    }
+
+    let bulk = || packets.iter().filter(|packet| !is_sensors(packet));
+    if let Err(e) = host.write_bulk(bulk()).await {
+        error!("Failed to bulk write packet: {}", e);
+
+        let _ = AsyncRpc::write_bulk(host, bulk())
+            .await
+            .map_err(|e| error!("Failed to individually bulk write packet: {}", e));
+    }
+
+    for sensors in packets.iter().filter(|packet| is_sensors(packet)) {
+        if let Err(e) = host.write(sensors).await {
+            error!("Failed to write Sensors: {:?}", e);
+        }
+    }
+}
+
+fn is_sensors(packet: &OutgoingRpcPacket) -> bool {
+    matches!(packet, OutgoingRpcPacket::Sensors { .. })
+}
+
+/// Applies the overrides from a `Setup` packet, discarding out-of-range values
+fn merge_driver_config(
+    mut config: DriverConfig,
+    run_current: Option<u8>,
+    hold_current: Option<u8>,
+    microsteps: Option<u16>,
+    stealthchop: Option<bool>,
+) -> DriverConfig {
+    if let Some(run_current) = run_current {
+        config.run_current = run_current.min(DriverConfig::MAX_CURRENT);
+    }
+    if let Some(hold_current) = hold_current {
+        config.hold_current = hold_current.min(DriverConfig::MAX_CURRENT);
+    }
+    if let Some(microsteps) = microsteps {
+        if microsteps.is_power_of_two() && microsteps <= DriverConfig::MAX_MICROSTEPS {
+            config.microsteps = microsteps;
+        } else {
+            warn!("Ignoring invalid microstep count {}", microsteps);
+        }
+    }
+    if let Some(stealthchop) = stealthchop {
+        config.stealthchop = stealthchop;
+    }
+
+    config
+}
@@ -1,37 +1,76 @@
 #![no_std]
 
 pub mod board;
+pub mod diagnostics;
+pub mod group;
+pub mod host;
+pub mod motion;
+#[cfg(feature = "brownout-protection")]
+mod power;
 pub mod rpc;
+pub mod sensors;
+pub mod supply;
+pub mod thermal;
 
 use crate::board::*;
-#[cfg(any(feature = "host-uart", feature = "host-usb"))]
-use crate::rpc::{AsyncRpc, AsyncRpcError, IncomingRpcPacket, OutgoingRpcPacket};
-use core::mem;
+use crate::motion::MotionCommand;
+use crate::rpc::OutgoingRpcPacket;
+use core::cell::RefCell;
 use core::sync::atomic::Ordering;
 #[allow(unused)]
 use defmt::*;
-#[allow(unused)]
-use embassy_executor::Spawner;
-#[allow(unused)]
-use embassy_time::Ticker;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
 #[allow(unused)]
 use embassy_time::{Duration, Instant, Timer};
-#[cfg(any(feature = "host-uart", feature = "host-usb"))]
-use heapless::Vec;
 use portable_atomic::AtomicU16;
-use sequencer::{
-    Direction, HaltingWindowDressingInstruction, WindowDressingInstruction, WindowDressingSequencer,
-};
-use sequencer::{HaltingSequencer, SensingWindowDressingSequencer};
+use serde::Serialize;
+use sequencer::HaltingSequencer;
 use static_cell::StaticCell;
 
 pub const DRIVERS: usize = get_driver_count();
 
-#[cfg(feature = "brownout-protection")]
-const BROWNOUT_PROTECTION: Duration = Duration::from_secs(2);
+/// Channels whose motor is reversed, as set up or as overridden by their group
 static REVERSALS: AtomicU16 = AtomicU16::new(0);
+/// Channels reversed by their own `Setup`, combined with the groups' into [`REVERSALS`]
+static SETUP_REVERSALS: AtomicU16 = AtomicU16::new(0);
 static STOPS: AtomicU16 = AtomicU16::new(0);
+/// Endstops currently held active, as opposed to the triggers latched in `STOPS`
+static ENDSTOPS: AtomicU16 = AtomicU16::new(0);
+/// Endstops released while their channel was at rest, taken by the motion task as a tug on a curtain
+static RELEASES: AtomicU16 = AtomicU16::new(0);
+/// Wakes the motion task ahead of its next deadline, e.g. when an endstop is hit
+static WAKE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Endstop configuration changes from `Setup`, picked up by the board's endstop detectors
+static ENDSTOP_CONFIG: [Signal<CriticalSectionRawMutex, EndstopConfig>; DRIVERS] =
+    [const { Signal::new() }; DRIVERS];
+/// Channels disabled due to a hard fault reported by the driver, latched until the channel is set up again
+static FAULTS: AtomicU16 = AtomicU16::new(0);
+/// Channels whose driver configuration could not be verified, which must not be run blindly
+static UNCONFIGURED: AtomicU16 = AtomicU16::new(0);
+/// Channels halted by a [`MotionFault`], latched until the channel is homed again
+static MOTION_FAULTS: AtomicU16 = AtomicU16::new(0);
+/// Channels without endstops, which sense the end of travel by StallGuard instead
+#[cfg(feature = "stallguard")]
+static SENSORLESS: AtomicU16 = AtomicU16::new(0);
+/// Channels the host has set up, i.e. which have a sequencer
+static SET_UP: AtomicU16 = AtomicU16::new(0);
+/// Channels whose state machine is running, as last seen by the motion task
+static MOVING: AtomicU16 = AtomicU16::new(0);
 static SEQUENCERS: StaticCell<[Option<HaltingSequencer<1024>>; DRIVERS]> = StaticCell::new();
+/// Commands from the host task for the motion task, which owns the sequencers
+static MOTION_COMMANDS: Channel<CriticalSectionRawMutex, MotionCommand, 8> = Channel::new();
+/// Packets raised by the motion and diagnostics tasks, written out by the host task
+static OUTGOING: Channel<CriticalSectionRawMutex, OutgoingRpcPacket, OUTGOING_DEPTH> =
+    Channel::new();
+/// Positions and motion faults raised by the motion task, which may not be dropped, nor wait on the host
+static LATEST: BlockingMutex<CriticalSectionRawMutex, RefCell<Latest>> =
+    BlockingMutex::new(RefCell::new(Latest::new()));
+/// Raised whenever [`LATEST`] has something for the host task to write out
+static LATEST_READY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 const fn get_driver_count() -> usize {
     cfg_select! {
@@ -43,398 +82,151 @@ const fn get_driver_count() -> usize {
     }
 }
 
+/// Full steps per second, a channel's step rate is this scaled by its microstepping
 pub const FREQUENCY: u16 = 1000;
-
-struct RunState<const N: usize, I> {
-    #[cfg(feature = "brownout-protection")]
-    brownout_protection: Instant,
-    next_buf: [Option<I>; N],
-    next_resume: [Instant; N],
-    cur_direction: [Direction; N],
-}
-
-impl<const N: usize, I> Default for RunState<N, I> {
-    fn default() -> Self {
-        RunState {
-            #[cfg(feature = "brownout-protection")]
-            brownout_protection: Instant::MIN,
-            next_buf: [const { None }; N],
-            next_resume: [Instant::now(); N],
-            cur_direction: [Direction::Hold; N],
-        }
-    }
+/// Enough for two reports from every channel, ahead of the host task catching up.
+/// Positions and motion faults aren't queued here, see [`LATEST`].
+const OUTGOING_DEPTH: usize = 2 * DRIVERS;
+/// Upper bound on each task's sleep, so the watchdog is fed and the host is checked for timeouts
+const IDLE_WAKE: Duration = Duration::from_millis(250);
+/// Lower bound on the motion task's sleep, so deadlines which have already passed don't spin it
+const MIN_WAKE: Duration = Duration::from_millis(5);
+/// Only a single channel is polled per interval, to avoid hogging the driver bus
+const DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(1);
+/// Stops further than this many percent from the end of travel are treated as obstructions,
+/// unless the channel's `Setup` gives its own tolerance
+const TRAVEL_LIMIT_TOLERANCE: u8 = 5;
+/// An endstop which is still active this long into a move away from it is considered stuck
+const ENDSTOP_RELEASE_TIMEOUT: Duration = Duration::from_secs(5);
+/// StallGuard reads low while the motor is accelerating, so stalls are ignored for a while after starting
+#[cfg(feature = "stallguard")]
+const SG_BLANKING: Duration = Duration::from_millis(500);
+/// How often StallGuard is polled while a sensorless channel is moving
+#[cfg(feature = "stallguard")]
+const SG_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Power-on threshold programmed by the driver configuration
+#[cfg(feature = "stallguard")]
+const DEFAULT_SGTHRS: u8 = 100;
+
+/// How often the board's temperature is read
+const THERMAL_INTERVAL: Duration = Duration::from_secs(1);
+/// Consecutive failed readings of the board's temperature before the drivers are derated as a precaution
+const THERMAL_FAILURES_TOLERATED: u8 = 5;
+/// How often the supply voltage is read, should the board monitor it
+const SUPPLY_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// Consecutive readings needed before the supply state changes
+const SUPPLY_CONFIRMATIONS: u8 = 2;
+
+/// Faults in the motion of a channel, which suggest a broken or stuck endstop
+#[derive(Clone, Copy, Eq, PartialEq, Format, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MotionFault {
+    /// The full travel of a homing move, and its overtravel allowance, was run without reaching the endstop
+    HomingOvertravel,
+    /// The endstop was already active at the start of a move away from the travel limits
+    EndstopActive,
+    /// The endstop did not release while moving away from it
+    EndstopNotReleased,
 }
 
 #[cfg(not(any(feature = "host-uart", feature = "host-usb")))]
 compile_error!("Please select a host communication protocol!");
 
-#[cfg(feature = "stallguard")]
-#[allow(unused)]
-pub async fn run<B, S, const N: usize>(mut spawner: Spawner, mut board: B)
-where
-    B: StepStickHost
-        + ControllableBoard
-        + ConfigurableStepStickHost<N>
-        + StallGuard<S, N>
-        + ControlLoopInvoke,
-{
-    info!("Initializing controller...");
-
-    let seqs = SEQUENCERS.init(
-        [const { None }; cfg_select! {
-            feature = "driver-qty-4" => 4,
-            feature = "driver-qty-5" => 5,
-            feature = "driver-qty-8" => 8,
-            feature = "driver-qty-10" => 10,
-        }],
-    );
-    let mut state = RunState::<DRIVERS, HaltingWindowDressingInstruction>::default();
-
-    loop {
-        board.watchdog_feed();
-        let incoming = board.get_host_rpc().peek().await.unwrap_or(None);
+/// The drivers' configuration bus, along with what has been programmed into and read back from each driver.
+///
+/// Transactions on the bus must not interleave, so it's shared between the host and diagnostics tasks
+/// through a [`DriverMutex`].
+pub struct SharedDrivers<'a, D> {
+    bus: &'a mut D,
+    config: [DriverConfig; DRIVERS],
+    status: [DriverStatus; DRIVERS],
+    #[cfg(feature = "stallguard")]
+    sg_threshold: [u8; DRIVERS],
+}
 
-        match incoming {
-            Some(IncomingRpcPacket::Setup { .. }) => {
-                debug!("Received setup command. Continuing...");
-                break;
-            }
-            Some(_) => {
-                debug!("Received non-setup command. Draining...");
-                let _ = board.get_host_rpc().read().await;
-                Timer::after_millis(50).await; // Drain should be more eager than the less-intensive waiting for a new command
-                continue;
-            }
-            None => {
-                let _ = board
-                    .get_host_rpc()
-                    .write(&OutgoingRpcPacket::Ready {})
-                    .await;
-                debug!("Flagged ready state.");
-                Timer::after_secs(1).await;
-            }
+impl<'a, D> SharedDrivers<'a, D> {
+    pub fn new(bus: &'a mut D) -> Self {
+        SharedDrivers {
+            bus,
+            config: [DriverConfig::default(); DRIVERS],
+            status: [DriverStatus::default(); DRIVERS],
+            #[cfg(feature = "stallguard")]
+            sg_threshold: [DEFAULT_SGTHRS; DRIVERS],
         }
     }
 
-    let mut ticker = Ticker::every(Duration::from_millis(250));
-    loop {
-        board.watchdog_feed();
-        board.invoke(&mut spawner).await;
-        let mut request_pos = 0u16;
-
-        // Limit the consumption of commands so it's not in this loop without checking the PIO,
-        // But also make it a bit greedy
-        for _ in 0..N {
-            match board.get_host_rpc().read().await {
-                Ok(Some(packet)) => match packet {
-                    IncomingRpcPacket::Home { channel } => {
-                        if let Some(ref mut seq) = seqs[channel as usize] {
-                            seq.home_fully_opened();
-                        } else {
-                            emit_absence(&mut board, channel).await;
-                        }
-                    }
-                    IncomingRpcPacket::Setup {
-                        channel,
-                        init,
-                        full_cycle_steps,
-                        reverse,
-                        full_tilt_steps,
-                        #[cfg(feature = "stallguard")]
-                        sgthrs,
-                    } => {
-                        let mut seq = HaltingSequencer::new(full_cycle_steps, full_tilt_steps);
-
-                        if let Some(init) = init {
-                            seq.load_state(&init);
-                        } else if let Some(ref old_seq) = seqs[channel as usize] {
-                            seq.load_state(old_seq.get_current_state());
-                        }
-
-                        if reverse.unwrap_or(false) {
-                            REVERSALS.bit_set(channel as u32, Ordering::Relaxed);
-                        } else {
-                            REVERSALS.bit_clear(channel as u32, Ordering::Relaxed);
-                        }
-
-                        #[cfg(feature = "stallguard")]
-                        if let Some(sgthrs) = sgthrs {
-                            board.set_sg_threshold(channel, sgthrs).await;
-                        }
-
-                        seqs[channel as usize] = Some(seq);
-                        info!("Driver set up on channel {}", channel);
-                    }
-                    IncomingRpcPacket::Set {
-                        channel,
-                        position,
-                        tilt,
-                    } => {
-                        if let Some(ref mut seq) = seqs[channel as usize] {
-                            position.map(|p| seq.set_position(p));
-                            tilt.map(|t| seq.set_tilt(t));
-                        } else {
-                            emit_absence(&mut board, channel).await;
-                        }
-                    }
-                    IncomingRpcPacket::Get { channel } => {
-                        request_pos |= 0b1 << channel;
-                    }
-                    #[cfg(feature = "stallguard")]
-                    IncomingRpcPacket::GetStallGuardResult { channel } => {
-                        let sg_result = board.get_sg_result_halved(channel).await.unwrap_or(0);
-                        let out = OutgoingRpcPacket::StallGuardResult { channel, sg_result };
-
-                        if let Err(e) = board.get_host_rpc().write(&out).await {
-                            error!("Failed to write StallGuardResult: {:?}", e);
-                        }
-
-                        break; // This is a heavy command, yield after running this
-                    }
-                    IncomingRpcPacket::Bootloader => {
-                        board.enter_bootloader();
-                    }
-                },
-                Ok(None) => {
-                    break;
-                }
-                Err(e) => {
-                    warn!("Failed to read from host: {:?}", e);
-                    if e.is_broken_input() {
-                        error!("Emitting state before rebooting...");
-                        let _ = bulk_emit_state(&mut board, seqs, 0xFFFF, true).await;
-
-                        Timer::after_secs(5).await;
-                        board.reset();
-                    }
-                }
-            }
-        }
-
-        let stopped = bulk_endstop_check(&mut board, seqs, &mut state);
-        let finished = bulk_push_pull_state(&mut board, seqs, &mut state);
-
-        // Emit state due to interruption or completion
-        bulk_emit_state(&mut board, seqs, finished | stopped, true).await;
-        bulk_emit_state(&mut board, seqs, request_pos & !(finished | stopped), false).await;
-
-        #[cfg(feature = "stallguard")]
-        if option_env!("LOG_SG_RESULT").is_some() {
-            let _ =
-                print_sg_result(
-                    &mut board,
-                    seqs.iter().enumerate().fold(0u16, |x, (i, opt)| {
-                        if opt.is_some() {
-                            x | (1 << i)
-                        } else {
-                            x
-                        }
-                    }),
-                )
-                .await;
-        }
-
-        ticker.next().await;
+    /// Programs the channel's StallGuard threshold again, which configuring its driver resets
+    #[cfg(feature = "stallguard")]
+    async fn restore_sg_threshold<S, const N: usize>(&mut self, channel: u8)
+    where
+        D: StallGuard<S, N>,
+    {
+        let sgthrs = self.sg_threshold[channel as usize];
+        self.bus.set_sg_threshold(channel, sgthrs).await;
     }
 }
 
-#[cfg(feature = "stallguard")]
-async fn print_sg_result<B, S, const N: usize>(board: &mut B, channels: u16)
-where
-    B: StepStickHost + StallGuard<S, N>,
-{
-    let mut sgresult2 = [None; N];
+/// Boards spawn the [`motion`], [`host`] and [`diagnostics`] tasks over the parts of a [`SplitBoard`],
+/// with the drivers shared between the latter two through this.
+pub type DriverMutex<'a, D> = Mutex<CriticalSectionRawMutex, SharedDrivers<'a, D>>;
 
-    // I do incur a bit of performance penalty querying all channels (used or not)
-    // over a single UART and waiting for a response for every single one.
-    // But at least I'm not creating a race condition in async.
-    //
-    // Also, this class doesn't discriminate for the underlying write protocol.
-    // This is actually the worst case assumption.
-    // I know the Manta doesn't have a single shared serial bus, the octopus doesn't even use UART!
-    //
-    // According to my own measurements this function takes 200-300ms.
-    // But I don't think it would be safe to offload to another task within the runtime.
-    for i in 0..N {
-        if (channels >> i) & 0b1 == 1 {
-            sgresult2[i] = board.get_sg_result_halved(i as u8).await;
-        }
+/// Queues a packet for the host task, dropping it rather than holding up the caller should the host fall behind
+fn emit(packet: OutgoingRpcPacket) {
+    if OUTGOING.try_send(packet).is_err() {
+        warn!("Outgoing queue is full, dropping packet");
     }
-
-    defmt::debug!("SG_RESULT/2 = {}", sgresult2);
 }
 
-fn bulk_endstop_check<B, Q, const N: usize>(
-    board: &mut B,
-    seqs: &mut [Option<Q>; N],
-    state: &mut RunState<N, Q::Instruction>,
-) -> u16
-where
-    B: StepStickHost,
-    Q: SensingWindowDressingSequencer,
-{
-    let mut flagged = 0u16;
-    let stops = STOPS.swap(0, Ordering::AcqRel);
-
-    for i in 0..DRIVERS {
-        if (stops >> i) & 0b1 == 1 {
-            let seq = if let Some(ref mut seq) = seqs[i] {
-                seq
-            } else {
-                continue;
-            };
-
-            debug!(
-                "Endstop trigger received for channel {} at {:?}",
-                i,
-                seq.get_current_state()
-            );
-            seq.trig_endstop();
-            board.clear_steps(i);
-            debug!("Channel {} is now at {:?}", i, seq.get_current_state());
-
-            state.next_buf[i] = seq.get_next_instruction();
-
-            flagged |= 1 << i;
-        }
-    }
-
-    flagged
+/// Packets of each channel which are coalesced rather than queued, so only the latest is written out.
+///
+/// The host only cares for where a channel is now, so an older position can be replaced,
+/// though its notification is carried over. Motion faults stay latched until a homing clears them,
+/// so there's only ever one to report.
+struct Latest {
+    positions: [Option<OutgoingRpcPacket>; DRIVERS],
+    faults: [Option<MotionFault>; DRIVERS],
 }
 
-fn bulk_push_pull_state<const N: usize, B, Q>(
-    board: &mut B,
-    seqs: &mut [Option<Q>; N],
-    state: &mut RunState<N, Q::Instruction>,
-) -> u16
-where
-    B: StepStickHost,
-    Q: WindowDressingSequencer,
-{
-    let mut stopped = 0u16;
-
-    let now = Instant::now();
-    for i in 0..DRIVERS {
-        let seq = if let Some(ref mut seq) = seqs[i] {
-            seq
-        } else {
-            continue;
-        };
-
-        if !board.get_ready_for_steps(i) {
-            continue;
-        }
-
-        if let Some(instr) = mem::replace(&mut state.next_buf[i], None) {
-            if !board.get_enabled(i) {
-                cfg_select! {
-                    // Thinking of buying this: https://www.digikey.com.au/en/products/detail/tecate-group/SCAP-PBLS-1-0-27/9929729
-                    feature = "brownout-protection" => {
-                        if state.brownout_protection + BROWNOUT_PROTECTION <= now {
-                            board.set_enabled(i, true);
-                            state.brownout_protection = now;
-                        } else {
-                            // If we're at risk of brownout, undo popping the instruction and move on
-                            //
-                            // From my experience, 3x1.65A steppers starting up are enough to brown a laptop
-                            // charger enough that the last stepper to start up will stall with StallGuard.
-                            let _ = mem::replace(&mut state.next_buf[i], Some(instr));
-                            continue;
-                        }
-                    },
-                    _ => {
-                        board.set_enabled(i, true);
-                    }
-                }
-            }
-
-            if *instr.get_direction() == state.cur_direction[i] {
-                // Downcast is safe unless it takes 6e6 steps to open the blinds fully
-                // - 15 minutes at 1kHz steps
-                // - It is also further clamped by [`get_next_instruction_grouped(LIMIT)`]
-
-                let _ = board.add_steps(i, *instr.get_quantity());
-            } else if board.get_stopped(i) && state.next_resume[i] < now {
-                state.cur_direction[i] = *instr.get_direction();
-
-                stopped |= 1 << i;
-
-                match instr.get_direction() {
-                    Direction::Hold => {
-                        let offset = Duration::from_micros(
-                            (*instr.get_direction() as u64 * 1_000_000) / FREQUENCY as u64,
-                        );
-                        state.next_resume[i] = now + offset;
-
-                        // Stop further commands on the PIO SMs & move on to the next channel
-                        // Also stops the instruction being placed back into the buffer (as this block handles it)
-                        continue;
-                    }
-                    Direction::Retract => {
-                        board.set_direction(i, (REVERSALS.load(Ordering::Acquire) >> i) & 0b1 == 1)
-                    }
-                    Direction::Extend => {
-                        board.set_direction(i, (REVERSALS.load(Ordering::Acquire) >> i) & 0b1 == 0)
-                    }
-                }
-                // Pick up the moving the next cycle
-                let _ = mem::replace(&mut state.next_buf[i], Some(instr));
-            } else {
-                let _ = mem::replace(&mut state.next_buf[i], Some(instr));
-            }
-        } else if let Some(next) = seq.get_next_instruction_grouped(FREQUENCY as u32) {
-            state.next_buf[i] = Some(next);
-        } else if board.get_stopped(i) {
-            board.set_enabled(i, false);
+impl Latest {
+    const fn new() -> Self {
+        Latest {
+            positions: [const { None }; DRIVERS],
+            faults: [None; DRIVERS],
         }
     }
-
-    stopped
 }
 
-async fn bulk_emit_state<B, Q, const N: usize>(
-    board: &mut B,
-    seqs: &[Option<Q>; N],
-    channels: u16,
-    notify: bool,
-) where
-    B: ControllableBoard,
-    Q: WindowDressingSequencer,
-{
-    let mut packets = Vec::<_, N>::new();
-
-    for i in 0..DRIVERS {
-        if (channels >> i) & 0b1 == 1 {
-            let seq = if let Some(ref seq) = seqs[i] {
-                seq
-            } else {
-                continue;
-            };
-
-            let _ = packets.push(OutgoingRpcPacket::Position {
-                channel: i as u8,
-                notify,
-                current: *seq.get_current_state(),
-                desired: *seq.get_desired_state(),
-            });
+/// Replaces the position pending for the channel, which is never dropped for the host falling behind
+fn emit_position(channel: u8, mut packet: OutgoingRpcPacket) {
+    LATEST.lock(|latest| {
+        let slot = &mut latest.borrow_mut().positions[channel as usize];
+        if let (
+            Some(OutgoingRpcPacket::Position {
+                notify: pending, ..
+            }),
+            OutgoingRpcPacket::Position { notify, .. },
+        ) = (&*slot, &mut packet)
+        {
+            *notify |= *pending;
         }
-    }
+        *slot = Some(packet);
+    });
+    LATEST_READY.signal(());
+}
 
-    if let Err(e) = board.get_host_rpc().write_bulk(packets.iter()).await {
-        error!("Failed to bulk write packet: {}", e);
+/// Raises a motion fault for the channel, which is never dropped for the host falling behind
+fn emit_fault(channel: u8, fault: MotionFault) {
+    LATEST.lock(|latest| latest.borrow_mut().faults[channel as usize] = Some(fault));
+    LATEST_READY.signal(());
+}
 
-        let _ = AsyncRpc::write_bulk(board.get_host_rpc(), packets.iter())
-            .await
-            .map_err(|e| error!("Failed to individually bulk write packet: {}", e));
-    }
+fn is_flagged(flags: &AtomicU16, channel: usize) -> bool {
+    (flags.load(Ordering::Acquire) >> channel) & 0b1 == 1
 }
 
-async fn emit_absence<B>(board: &mut B, channel: u8)
-where
-    B: ControllableBoard,
-{
-    let _ = board
-        .get_host_rpc()
-        .write(&OutgoingRpcPacket::Absent { channel })
-        .await;
+/// Whether the channel must not be run, due to a latched fault or an unverified driver
+fn is_halted(channel: usize) -> bool {
+    is_flagged(&FAULTS, channel)
+        || is_flagged(&UNCONFIGURED, channel)
+        || is_flagged(&MOTION_FAULTS, channel)
 }
@@ -0,0 +1,936 @@
+use crate::board::{ControllableBoard, StepStickHost};
+use crate::group::{group_of, Group, GroupSetup, GroupTarget, MAX_GROUPS};
+#[cfg(feature = "brownout-protection")]
+use crate::board::PowerBudget;
+#[cfg(feature = "brownout-protection")]
+use crate::power::{Admission, PowerScheduler};
+use crate::rpc::OutgoingRpcPacket;
+use crate::supply::{supply_state, SupplyState};
+use crate::thermal::{thermal_state, ThermalState};
+use crate::*;
+use core::mem;
+use embassy_futures::select::select3;
+use sequencer::{
+    Direction, HaltingWindowDressingInstruction, SensingWindowDressingSequencer,
+    WindowDressingInstruction, WindowDressingSequencer, WindowDressingState,
+};
+
+/// Requests from the host task, which the motion task applies between steps
+pub(crate) enum MotionCommand {
+    Home {
+        channel: u8,
+    },
+    /// Replaces the channel's sequencer, with step counts already scaled by the microsteps
+    Setup {
+        channel: u8,
+        init: Option<WindowDressingState>,
+        full_cycle_steps: u32,
+        full_tilt_steps: Option<u32>,
+        /// Steps per second, i.e. [`FREQUENCY`] scaled by the driver's microstepping
+        step_frequency: u32,
+        back_off: u8,
+        travel_limit_tolerance: u8,
+        #[cfg(feature = "brownout-protection")]
+        run_draw_ma: Option<u16>,
+        #[cfg(feature = "brownout-protection")]
+        inrush_draw_ma: Option<u16>,
+    },
+    Set {
+        channel: u8,
+        position: Option<u8>,
+        tilt: Option<i8>,
+    },
+    Get {
+        channel: u8,
+    },
+    SetupGroup {
+        group: u8,
+        kind: Option<GroupSetup>,
+    },
+    SetGroup {
+        group: u8,
+        target: GroupTarget,
+    },
+    /// Emits the state of every channel, then resets the board once the host task has had time to write it out
+    Reset,
+    Bootloader,
+}
+
+struct RunState<const N: usize, I> {
+    #[cfg(feature = "brownout-protection")]
+    power: PowerScheduler<N>,
+    next_buf: [Option<I>; N],
+    next_resume: [Instant; N],
+    cur_direction: [Direction; N],
+    /// When the word queued behind the running one is pulled by the state machine, freeing the FIFO
+    refill_at: [Instant; N],
+    /// When every step pushed to the state machine will have been run
+    chunk_end: [Instant; N],
+    /// Steps per second each channel's state machine is clocked at
+    step_frequency: [u32; N],
+    /// Percentage of travel to reverse by after an obstruction
+    back_off: [u8; N],
+    /// Stops further than this many percent from the end of travel are treated as obstructions
+    travel_limit_tolerance: [u8; N],
+    endstop_release_by: [Option<Instant>; N],
+    /// Supply state last acted on
+    supply: SupplyState,
+    /// When motion was held for an undervoltage
+    held_at: Option<Instant>,
+    /// Channels whose state machine was held mid-move
+    held: u16,
+    /// Channels which started a move from rest, and haven't been supervised moving since
+    starting: u16,
+    /// Whether motion was stopped for the board overheating
+    overheated: bool,
+    groups: [Option<Group>; MAX_GROUPS],
+}
+
+impl<const N: usize, I> Default for RunState<N, I> {
+    fn default() -> Self {
+        RunState {
+            #[cfg(feature = "brownout-protection")]
+            power: PowerScheduler::new(PowerBudget::default()),
+            next_buf: [const { None }; N],
+            next_resume: [Instant::now(); N],
+            cur_direction: [Direction::Hold; N],
+            refill_at: [Instant::now(); N],
+            chunk_end: [Instant::now(); N],
+            step_frequency: [FREQUENCY as u32; N],
+            back_off: [0; N],
+            travel_limit_tolerance: [TRAVEL_LIMIT_TOLERANCE; N],
+            endstop_release_by: [None; N],
+            supply: SupplyState::Normal,
+            held_at: None,
+            held: 0,
+            starting: 0,
+            overheated: false,
+            groups: [const { None }; MAX_GROUPS],
+        }
+    }
+}
+
+/// Runs the sequencers and feeds their steps to the board.
+///
+/// This task never touches the driver bus nor the host link, so step timing doesn't depend on either,
+/// and it's the one to feed the watchdog, as it's the one that must never stall.
+///
+/// It only shares atomics and channels with the other tasks, so it may run under an executor of its own,
+/// e.g. on the RP2040's second core alongside the endstop detectors.
+pub async fn run<M>(motion: &mut M)
+where
+    M: StepStickHost + ControllableBoard,
+{
+    let seqs = SEQUENCERS.init([const { None }; DRIVERS]);
+    let mut state = RunState::<DRIVERS, HaltingWindowDressingInstruction>::default();
+    #[cfg(feature = "brownout-protection")]
+    {
+        state.power = PowerScheduler::new(motion.power_budget());
+    }
+
+    loop {
+        motion.watchdog_feed();
+        let mut request_pos = 0u16;
+
+        while let Ok(command) = MOTION_COMMANDS.try_receive() {
+            request_pos |= apply_command(motion, seqs, &mut state, command).await;
+        }
+
+        let mut stopped = apply_supply(motion, seqs, &mut state)
+            | apply_thermal(motion, seqs, &mut state)
+            | bulk_endstop_check(motion, seqs, &mut state)
+            | halt_faulted(motion, seqs, &mut state);
+        let mut finished = 0;
+        // Held channels look stopped, so they're neither supervised nor fed until the supply recovers
+        if state.supply == SupplyState::Normal {
+            stopped |= supervise_motion(motion, seqs, &mut state);
+            advance_groups(motion, seqs, &mut state);
+            apply_tugs(motion, seqs, &mut state);
+            finished = bulk_push_pull_state(motion, seqs, &mut state);
+        }
+        publish_moving(motion);
+
+        // Emit state due to interruption, completion or waiting for power
+        let waiting = waiting_for_power(&state);
+        bulk_emit_state(seqs, &state.groups, finished | stopped, true, waiting);
+        bulk_emit_state(
+            seqs,
+            &state.groups,
+            request_pos & !(finished | stopped),
+            false,
+            waiting,
+        );
+
+        // Sleep until the next deadline, or until an endstop, a fault or the host needs attention
+        let wake_at = next_wake(motion, &state);
+        select3(
+            WAKE.wait(),
+            MOTION_COMMANDS.ready_to_receive(),
+            Timer::at(wake_at),
+        )
+        .await;
+    }
+}
+
+/// Applies a command from the host, returning the channels whose position was requested
+async fn apply_command<M, const N: usize>(
+    motion: &mut M,
+    seqs: &mut [Option<HaltingSequencer<1024>>; N],
+    state: &mut RunState<N, HaltingWindowDressingInstruction>,
+    command: MotionCommand,
+) -> u16
+where
+    M: StepStickHost + ControllableBoard,
+{
+    match command {
+        MotionCommand::Home { channel } | MotionCommand::Set { channel, .. }
+            if thermal_state() == ThermalState::Shutdown =>
+        {
+            warn!("Refusing to move channel {} while overheated", channel);
+            return 0b1 << channel;
+        }
+        MotionCommand::SetGroup { group, .. } if thermal_state() == ThermalState::Shutdown => {
+            warn!("Refusing to move group {} while overheated", group);
+            return group_channels(&state.groups, group);
+        }
+        MotionCommand::Set { channel, .. } if group_of(&state.groups, channel).is_some() => {
+            warn!("Channel {} is grouped, it can only be moved with its group", channel);
+            return 0b1 << channel;
+        }
+        MotionCommand::Home { channel } => {
+            if seqs[channel as usize].is_none() {
+                emit_absence(channel);
+                return 0;
+            }
+
+            // Grouped channels home the way their group allows
+            let group =
+                group_of(&state.groups, channel).and_then(|group| state.groups[group].as_mut());
+            if let Some(group) = group {
+                if !group.home(channel, seqs) {
+                    warn!("Refusing to home channel {} until its vanes are stacked", channel);
+                    return 0b1 << channel;
+                }
+            } else if let Some(ref mut seq) = seqs[channel as usize] {
+                seq.home_fully_opened();
+            }
+
+            if MOTION_FAULTS.bit_clear(channel as u32, Ordering::AcqRel) {
+                info!("Clearing latched motion fault on channel {}", channel);
+            }
+            state.endstop_release_by[channel as usize] = None;
+        }
+        MotionCommand::Setup {
+            channel,
+            init,
+            full_cycle_steps,
+            full_tilt_steps,
+            step_frequency,
+            back_off,
+            travel_limit_tolerance,
+            #[cfg(feature = "brownout-protection")]
+            run_draw_ma,
+            #[cfg(feature = "brownout-protection")]
+            inrush_draw_ma,
+        } => {
+            let mut seq = HaltingSequencer::new(full_cycle_steps, full_tilt_steps);
+
+            if let Some(init) = init {
+                seq.load_state(&init);
+            } else if let Some(ref old_seq) = seqs[channel as usize] {
+                seq.load_state(old_seq.get_current_state());
+            } else if let Some(persisted) = motion.take_persisted_position(channel as usize) {
+                info!("Restoring channel {} to its persisted position", channel);
+                seq.load_state(&persisted);
+            }
+
+            state.back_off[channel as usize] = back_off;
+            state.travel_limit_tolerance[channel as usize] = travel_limit_tolerance;
+            state.step_frequency[channel as usize] = step_frequency;
+            motion.set_step_frequency(channel as usize, step_frequency);
+            #[cfg(feature = "brownout-protection")]
+            state
+                .power
+                .set_draw(channel as usize, run_draw_ma, inrush_draw_ma);
+            seqs[channel as usize] = Some(seq);
+            if let Some(group) = group_of(&state.groups, channel) {
+                if let Some(ref mut group) = state.groups[group] {
+                    group.sync(seqs);
+                }
+            }
+            apply_reversals(&state.groups);
+            SET_UP.bit_set(channel as u32, Ordering::Release);
+            info!("Driver set up on channel {}", channel);
+        }
+        MotionCommand::Set {
+            channel,
+            position,
+            tilt,
+        } => {
+            if let Some(ref mut seq) = seqs[channel as usize] {
+                position.map(|p| seq.set_position(p));
+                tilt.map(|t| seq.set_tilt(t));
+            } else {
+                emit_absence(channel);
+            }
+        }
+        MotionCommand::Get { channel } => return 0b1 << channel,
+        MotionCommand::SetupGroup { group, kind } => {
+            let Some(slot) = state.groups.get_mut(group as usize) else {
+                warn!("There's no group {}", group);
+                return 0;
+            };
+            let ungrouped = slot.take().map_or(0, |group| group.channels());
+
+            let mut grouped = 0;
+            if let Some(kind) = kind {
+                match Group::new(kind, seqs) {
+                    Some(new) if group_channels(&state.groups, u8::MAX) & new.channels() == 0 => {
+                        info!("Group {} set up", group);
+                        grouped = new.channels();
+                        state.groups[group as usize] = Some(new);
+                    }
+                    Some(_) => warn!("Group {} overlaps another group", group),
+                    None => warn!("Group {} needs distinct channels which are set up", group),
+                }
+            }
+            apply_reversals(&state.groups);
+
+            return ungrouped | grouped;
+        }
+        MotionCommand::SetGroup { group, target } => {
+            if let Some(Some(grouped)) = state.groups.get_mut(group as usize) {
+                if !grouped.set(target, seqs) {
+                    warn!("Group {} is of another kind", group);
+                }
+            } else {
+                warn!("Group {} is not set up", group);
+            }
+        }
+        MotionCommand::Reset => {
+            error!("Emitting state before rebooting...");
+            bulk_emit_state(seqs, &state.groups, 0xFFFF, true, waiting_for_power(state));
+
+            Timer::after_secs(5).await;
+            motion.reset();
+        }
+        MotionCommand::Bootloader => motion.enter_bootloader(),
+    }
+
+    0
+}
+
+/// Earliest instant the motion task has to act on, which is when a state machine can take more steps,
+/// runs out of steps, or a hold ends.
+fn next_wake<M, I, const N: usize>(motion: &mut M, state: &RunState<N, I>) -> Instant
+where
+    M: StepStickHost,
+    I: WindowDressingInstruction,
+{
+    let now = Instant::now();
+    let mut wake = now + IDLE_WAKE;
+    // The supply monitor wakes the task once motion can carry on
+    if state.supply != SupplyState::Normal {
+        return wake;
+    }
+    #[cfg(feature = "brownout-protection")]
+    if let Some(change) = state.power.next_change(now) {
+        wake = wake.min(change);
+    }
+
+    for i in 0..N {
+        // Channels waiting for power are picked up once the supply's load changes
+        if is_halted(i) || (waiting_for_power(state) >> i) & 0b1 == 1 {
+            continue;
+        }
+
+        let due = if motion.get_stopped(i) {
+            if state.next_buf[i].is_none() && state.next_resume[i] <= now {
+                continue;
+            }
+            state.next_resume[i]
+        } else if let Some(ref instr) = state.next_buf[i] {
+            if *instr.get_direction() == state.cur_direction[i] {
+                state.refill_at[i]
+            } else {
+                state.chunk_end[i]
+            }
+        } else {
+            state.chunk_end[i]
+        };
+        wake = wake.min(due);
+    }
+
+    wake.max(now + MIN_WAKE)
+}
+
+/// Acts on a change in the supply, returning the channels whose position should be reported.
+///
+/// An undervoltage freezes the running state machines with their steps still queued, and they carry on
+/// once the supply recovers. Ahead of a loss of power, every channel is stopped and its sequencer wound back
+/// by the steps which were queued but never run, so the reported positions can be restored on the next boot.
+fn apply_supply<M, const N: usize>(
+    motion: &mut M,
+    seqs: &mut [Option<HaltingSequencer<1024>>; N],
+    state: &mut RunState<N, HaltingWindowDressingInstruction>,
+) -> u16
+where
+    M: StepStickHost,
+{
+    let supply = supply_state();
+    if supply == state.supply {
+        return 0;
+    }
+
+    let now = Instant::now();
+    let mut flagged = 0u16;
+
+    match supply {
+        SupplyState::Undervoltage => {
+            warn!("Supply undervoltage, holding motion");
+            for i in 0..N {
+                if !motion.get_stopped(i) {
+                    motion.hold_steps(i, true);
+                    state.held |= 1 << i;
+                }
+            }
+            state.held_at = Some(now);
+        }
+        SupplyState::PowerFail => {
+            error!("Losing power, stopping all motion");
+            flagged = halt_all(motion, seqs, state, state.held_at.unwrap_or(now));
+        }
+        SupplyState::Normal => {
+            info!("Supply recovered, resuming motion");
+            if let Some(held_at) = state.held_at.take() {
+                let held_for = now - held_at;
+                for i in 0..N {
+                    if (state.held >> i) & 0b1 == 1 {
+                        state.refill_at[i] += held_for;
+                        state.chunk_end[i] += held_for;
+                        motion.hold_steps(i, false);
+                    }
+                }
+            }
+            state.held = 0;
+        }
+    }
+
+    state.supply = supply;
+    flagged
+}
+
+/// Stops every channel once the board overheats, returning the channels whose position should be reported
+fn apply_thermal<M, const N: usize>(
+    motion: &mut M,
+    seqs: &mut [Option<HaltingSequencer<1024>>; N],
+    state: &mut RunState<N, HaltingWindowDressingInstruction>,
+) -> u16
+where
+    M: StepStickHost,
+{
+    let overheated = thermal_state() == ThermalState::Shutdown;
+    if overheated == state.overheated {
+        return 0;
+    }
+    state.overheated = overheated;
+
+    if overheated {
+        error!("Board overheated, stopping all motion");
+        // Held channels stopped stepping when they were held, not now
+        let stopped_at = state.held_at.unwrap_or(Instant::now());
+        halt_all(motion, seqs, state, stopped_at)
+    } else {
+        0
+    }
+}
+
+/// Stops and disables every channel, winding each sequencer back by the steps which were queued but never run,
+/// and has the board persist where they stopped
+fn halt_all<M, const N: usize>(
+    motion: &mut M,
+    seqs: &mut [Option<HaltingSequencer<1024>>; N],
+    state: &mut RunState<N, HaltingWindowDressingInstruction>,
+    stopped_at: Instant,
+) -> u16
+where
+    M: StepStickHost,
+{
+    let mut flagged = 0u16;
+    state.held_at = None;
+
+    for i in 0..N {
+        halt_channel(motion, seqs[i].as_mut(), state, i, stopped_at);
+        if seqs[i].is_some() {
+            flagged |= 1 << i;
+        }
+    }
+    state.held = 0;
+
+    let mut positions = [None; N];
+    for (position, seq) in positions.iter_mut().zip(seqs.iter()) {
+        *position = seq.as_ref().map(|seq| *seq.get_current_state());
+    }
+    motion.persist_positions(&positions);
+
+    flagged
+}
+
+/// Stops and disables the channel, winding its sequencer back by the steps which were queued but never run
+/// as of `stopped_at`, so the reported position matches where the motor stopped and nothing is left pending
+fn halt_channel<M, const N: usize>(
+    motion: &mut M,
+    seq: Option<&mut HaltingSequencer<1024>>,
+    state: &mut RunState<N, HaltingWindowDressingInstruction>,
+    i: usize,
+    stopped_at: Instant,
+) where
+    M: StepStickHost,
+{
+    let running = !motion.get_stopped(i) || (state.held >> i) & 0b1 == 1;
+    let next = state.next_buf[i].take();
+    motion.clear_steps(i);
+    motion.set_enabled(i, false);
+    #[cfg(feature = "brownout-protection")]
+    state.power.release(i);
+
+    let seq = if let Some(seq) = seq {
+        seq
+    } else {
+        return;
+    };
+
+    // Undo the instructions in the reverse order they were popped
+    if let Some(next) = next {
+        seq.trig_halt(next.direction, next.quantity);
+    }
+    if running {
+        seq.trig_halt(state.cur_direction[i], unrun_steps(state, i, stopped_at));
+    }
+}
+
+/// Steps pushed to the channel's state machine which won't have been run by `at`
+fn unrun_steps<const N: usize, I>(state: &RunState<N, I>, i: usize, at: Instant) -> u32 {
+    if state.cur_direction[i] == Direction::Hold || state.chunk_end[i] <= at {
+        return 0;
+    }
+
+    ((state.chunk_end[i] - at).as_micros() * state.step_frequency[i] as u64 / 1_000_000) as u32
+}
+
+/// Publishes which state machines are running, for the diagnostics task
+fn publish_moving<M: StepStickHost>(motion: &mut M) {
+    let mut moving = 0u16;
+    for i in 0..DRIVERS {
+        if !motion.get_stopped(i) {
+            moving |= 1 << i;
+        }
+    }
+    MOVING.store(moving, Ordering::Release);
+}
+
+/// Stops and disables channels the diagnostics task has found faulted or unconfigured,
+/// returning the ones which were still running.
+///
+/// Their sequencers are halted like ahead of a loss of power, so they don't carry on once the channel recovers.
+fn halt_faulted<M, const N: usize>(
+    motion: &mut M,
+    seqs: &mut [Option<HaltingSequencer<1024>>; N],
+    state: &mut RunState<N, HaltingWindowDressingInstruction>,
+) -> u16
+where
+    M: StepStickHost,
+{
+    let mut flagged = 0u16;
+    let halted = FAULTS.load(Ordering::Acquire) | UNCONFIGURED.load(Ordering::Acquire);
+    // Faulted while held for undervoltage, the channel's last step was run when the supply sagged
+    let stopped_at = state.held_at.unwrap_or(Instant::now());
+
+    for i in 0..N {
+        if (halted >> i) & 0b1 == 0 {
+            continue;
+        }
+
+        if motion.get_enabled(i) || state.next_buf[i].is_some() {
+            halt_channel(motion, seqs[i].as_mut(), state, i, stopped_at);
+            state.held &= !(1 << i);
+            flagged |= 1 << i;
+        }
+    }
+
+    flagged
+}
+
+/// Watches for endstops that never trigger, or never release, latching a [`MotionFault`] on the channel.
+///
+/// Channels without an endstop wired in never raise `ENDSTOPS`, so only the homing check applies to them.
+fn supervise_motion<M, Q, const N: usize>(
+    motion: &mut M,
+    seqs: &[Option<Q>; N],
+    state: &mut RunState<N, Q::Instruction>,
+) -> u16
+where
+    M: StepStickHost,
+    Q: SensingWindowDressingSequencer,
+{
+    let now = Instant::now();
+    let mut flagged = 0u16;
+
+    for i in 0..DRIVERS {
+        let seq = if let Some(ref seq) = seqs[i] {
+            seq
+        } else {
+            continue;
+        };
+
+        if is_halted(i) {
+            continue;
+        }
+
+        let stopped = motion.get_stopped(i);
+        let active = is_flagged(&ENDSTOPS, i);
+        let moving = !stopped && state.cur_direction[i] != Direction::Hold;
+        let starting = (state.starting >> i) & 0b1 == 1;
+        if moving {
+            state.starting &= !(1 << i);
+        }
+        let position = seq.get_current_state().position;
+        let tolerance = state.travel_limit_tolerance[i];
+        let at_limit = position <= tolerance || position >= 100 - tolerance;
+
+        let fault = if seq.is_homing()
+            && stopped
+            && state.next_buf[i].is_none()
+            && seq.get_current_state() == seq.get_desired_state()
+        {
+            Some(MotionFault::HomingOvertravel)
+        } else if !active || !moving {
+            state.endstop_release_by[i] = None;
+            None
+        } else if let Some(deadline) = state.endstop_release_by[i] {
+            (now > deadline).then_some(MotionFault::EndstopNotReleased)
+        } else if at_limit || seq.is_obstructed() {
+            // Moving away from the limit, or backing off whatever the endstop ran into
+            state.endstop_release_by[i] = Some(now + ENDSTOP_RELEASE_TIMEOUT);
+            None
+        } else if starting {
+            Some(MotionFault::EndstopActive)
+        } else {
+            // Tripped mid-travel, which the endstop check handles as an obstruction
+            None
+        };
+
+        if let Some(fault) = fault {
+            error!("Motion fault on channel {}: {}", i, fault);
+            MOTION_FAULTS.bit_set(i as u32, Ordering::AcqRel);
+            motion.clear_steps(i);
+            motion.set_enabled(i, false);
+            state.next_buf[i] = None;
+            state.endstop_release_by[i] = None;
+            state.starting &= !(1 << i);
+            #[cfg(feature = "brownout-protection")]
+            state.power.release(i);
+
+            emit_fault(i as u8, fault);
+
+            flagged |= 1 << i;
+        }
+    }
+
+    flagged
+}
+
+fn bulk_endstop_check<M, Q, const N: usize>(
+    motion: &mut M,
+    seqs: &mut [Option<Q>; N],
+    state: &mut RunState<N, Q::Instruction>,
+) -> u16
+where
+    M: StepStickHost,
+    Q: SensingWindowDressingSequencer,
+{
+    let mut flagged = 0u16;
+    let stops = STOPS.swap(0, Ordering::AcqRel);
+    let now = Instant::now();
+
+    for i in 0..DRIVERS {
+        if (stops >> i) & 0b1 == 1 {
+            let seq = if let Some(ref mut seq) = seqs[i] {
+                seq
+            } else {
+                continue;
+            };
+
+            debug!(
+                "Endstop trigger received for channel {} at {:?}",
+                i,
+                seq.get_current_state()
+            );
+            if seq.is_travel_limit(state.travel_limit_tolerance[i]) {
+                seq.trig_endstop();
+            } else {
+                warn!("Obstruction detected on channel {}", i);
+
+                // The state machine was stopped at the endstop, so neither the rest of its steps
+                // nor the instruction buffered behind them were run
+                let direction = state.cur_direction[i];
+                let mut unrun = unrun_steps(state, i, now);
+                if let Some(next) = state.next_buf[i].take() {
+                    if *next.get_direction() == direction {
+                        unrun += *next.get_quantity();
+                    }
+                }
+                seq.trig_obstruction(state.back_off[i], direction, unrun);
+            }
+            motion.clear_steps(i);
+            debug!("Channel {} is now at {:?}", i, seq.get_current_state());
+
+            state.next_buf[i] = seq.get_next_instruction();
+
+            flagged |= 1 << i;
+        }
+    }
+
+    flagged
+}
+
+fn bulk_push_pull_state<const N: usize, M, Q>(
+    motion: &mut M,
+    seqs: &mut [Option<Q>; N],
+    state: &mut RunState<N, Q::Instruction>,
+) -> u16
+where
+    M: StepStickHost,
+    Q: WindowDressingSequencer,
+{
+    let mut stopped = 0u16;
+
+    let now = Instant::now();
+    for i in 0..DRIVERS {
+        let seq = if let Some(ref mut seq) = seqs[i] {
+            seq
+        } else {
+            continue;
+        };
+
+        if is_halted(i) {
+            continue;
+        }
+
+        if !motion.get_ready_for_steps(i) {
+            continue;
+        }
+
+        if let Some(instr) = mem::replace(&mut state.next_buf[i], None) {
+            if !motion.get_enabled(i) {
+                // Thinking of buying this: https://www.digikey.com.au/en/products/detail/tecate-group/SCAP-PBLS-1-0-27/9929729
+                #[cfg(feature = "brownout-protection")]
+                match state.power.request(i, now) {
+                    Admission::Admitted => {}
+                    admission => {
+                        if let Admission::Queued = admission {
+                            // Let the host know why the channel isn't moving
+                            stopped |= 1 << i;
+                        }
+
+                        // If we're at risk of brownout, undo popping the instruction and move on
+                        let _ = mem::replace(&mut state.next_buf[i], Some(instr));
+                        continue;
+                    }
+                }
+                motion.set_enabled(i, true);
+            }
+
+            if *instr.get_direction() == state.cur_direction[i] {
+                if motion.get_stopped(i) {
+                    state.starting |= 1 << i;
+                }
+
+                // Downcast is safe unless it takes 6e6 steps to open the blinds fully
+                // - 15 minutes at 1kHz steps
+                // - It is also further clamped by [`get_next_instruction_grouped(LIMIT)`]
+
+                if motion.add_steps(i, *instr.get_quantity()).unwrap_or(false) {
+                    let duration = Duration::from_micros(
+                        (*instr.get_quantity() as u64 * 1_000_000) / state.step_frequency[i] as u64,
+                    );
+                    state.refill_at[i] = state.chunk_end[i].max(now);
+                    state.chunk_end[i] = state.refill_at[i] + duration;
+                }
+            } else if motion.get_stopped(i) && state.next_resume[i] < now {
+                state.cur_direction[i] = *instr.get_direction();
+
+                stopped |= 1 << i;
+
+                match instr.get_direction() {
+                    Direction::Hold => {
+                        // Holds are timed in full steps, regardless of microstepping
+                        let offset = Duration::from_micros(
+                            (*instr.get_quantity() as u64 * 1_000_000) / FREQUENCY as u64,
+                        );
+                        state.next_resume[i] = now + offset;
+
+                        // Stop further commands on the PIO SMs & move on to the next channel
+                        // Also stops the instruction being placed back into the buffer (as this block handles it)
+                        continue;
+                    }
+                    Direction::Retract => {
+                        motion.set_direction(i, (REVERSALS.load(Ordering::Acquire) >> i) & 0b1 == 1)
+                    }
+                    Direction::Extend => {
+                        motion.set_direction(i, (REVERSALS.load(Ordering::Acquire) >> i) & 0b1 == 0)
+                    }
+                }
+                // Pick up the moving the next cycle
+                let _ = mem::replace(&mut state.next_buf[i], Some(instr));
+            } else {
+                let _ = mem::replace(&mut state.next_buf[i], Some(instr));
+            }
+        } else if let Some(next) = seq.get_next_instruction_grouped(state.step_frequency[i]) {
+            state.next_buf[i] = Some(next);
+        } else if motion.get_stopped(i) {
+            motion.set_enabled(i, false);
+            #[cfg(feature = "brownout-protection")]
+            state.power.release(i);
+        }
+    }
+
+    stopped
+}
+
+fn bulk_emit_state<Q, const N: usize>(
+    seqs: &[Option<Q>; N],
+    groups: &[Option<Group>],
+    channels: u16,
+    notify: bool,
+    waiting: u16,
+) where
+    Q: SensingWindowDressingSequencer,
+{
+    for i in 0..DRIVERS {
+        if (channels >> i) & 0b1 == 1 {
+            let seq = if let Some(ref seq) = seqs[i] {
+                seq
+            } else {
+                continue;
+            };
+
+            let packet = OutgoingRpcPacket::Position {
+                channel: i as u8,
+                notify,
+                current: *seq.get_current_state(),
+                desired: *seq.get_desired_state(),
+                obstructed: seq.is_obstructed(),
+                waiting_for_power: (waiting >> i) & 0b1 == 1,
+                group: group_of(groups, i as u8).and_then(|group| {
+                    groups[group].as_ref()?.state(group as u8, seqs)
+                }),
+            };
+            emit_position(i as u8, packet);
+        }
+    }
+}
+
+/// Channels of `group`, or of every group when out of range, as a bitmask
+fn group_channels(groups: &[Option<Group>], group: u8) -> u16 {
+    groups
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| group as usize >= groups.len() || *i == group as usize)
+        .filter_map(|(_, group)| group.as_ref())
+        .fold(0, |channels, group| channels | group.channels())
+}
+
+/// Applies the channels' own reversals, except where their group sets the reversal
+fn apply_reversals(groups: &[Option<Group>]) {
+    let (grouped, reversed) = groups.iter().flatten().fold((0, 0), |(grouped, reversed), group| {
+        let (channels, group_reversed) = group.reversals();
+        (grouped | channels, reversed | group_reversed)
+    });
+    let own = SETUP_REVERSALS.load(Ordering::Relaxed);
+    REVERSALS.store((own & !grouped) | reversed, Ordering::Release);
+}
+
+/// Sends on the grouped channels which had to wait for the rest of their group to stop
+fn advance_groups<M, Q, const N: usize>(
+    motion: &mut M,
+    seqs: &mut [Option<Q>; N],
+    state: &mut RunState<N, Q::Instruction>,
+) where
+    M: StepStickHost,
+    Q: SensingWindowDressingSequencer,
+{
+    let idle = idle_channels(motion, seqs, state);
+    for (i, group) in state.groups.iter_mut().enumerate() {
+        if let Some(group) = group {
+            if group.advance(seqs, idle) {
+                debug!("Group {} carries on with its held back moves", i);
+            }
+        }
+    }
+}
+
+/// Draws the touch to move curtains tugged off an endstop at rest, unless the board is too hot to move them
+fn apply_tugs<M, Q, const N: usize>(
+    motion: &mut M,
+    seqs: &mut [Option<Q>; N],
+    state: &mut RunState<N, Q::Instruction>,
+) where
+    M: StepStickHost,
+    Q: SensingWindowDressingSequencer,
+{
+    let released = RELEASES.swap(0, Ordering::AcqRel);
+    if released == 0 || thermal_state() == ThermalState::Shutdown {
+        return;
+    }
+
+    let idle = idle_channels(motion, seqs, state);
+    for (i, group) in state.groups.iter_mut().enumerate() {
+        if let Some(group) = group {
+            let tugged = group.touch() & released != 0;
+            if tugged && group.channels() & !idle == 0 && group.tug(seqs) {
+                info!("Group {} was tugged", i);
+            }
+        }
+    }
+}
+
+/// Channels which are neither halted, running nor left with anywhere to go, as a bitmask
+fn idle_channels<M, Q, const N: usize>(
+    motion: &mut M,
+    seqs: &[Option<Q>; N],
+    state: &RunState<N, Q::Instruction>,
+) -> u16
+where
+    M: StepStickHost,
+    Q: SensingWindowDressingSequencer,
+{
+    let mut idle = 0u16;
+    for (i, seq) in seqs.iter().enumerate() {
+        if let Some(seq) = seq {
+            if !is_halted(i)
+                && motion.get_stopped(i)
+                && state.next_buf[i].is_none()
+                && seq.get_current_state() == seq.get_desired_state()
+            {
+                idle |= 1 << i;
+            }
+        }
+    }
+    idle
+}
+
+/// Channels held off by the power scheduler, as a bitmask
+#[cfg(feature = "brownout-protection")]
+fn waiting_for_power<const N: usize, I>(state: &RunState<N, I>) -> u16 {
+    state.power.waiting()
+}
+
+#[cfg(not(feature = "brownout-protection"))]
+fn waiting_for_power<const N: usize, I>(_state: &RunState<N, I>) -> u16 {
+    0
+}
+
+fn emit_absence(channel: u8) {
+    emit(OutgoingRpcPacket::Absent { channel });
+}
@@ -0,0 +1,119 @@
+use crate::board::PowerBudget;
+use embassy_time::Instant;
+use heapless::Deque;
+
+/// Admits motor starts while the predicted draw on the supply stays within its [`PowerBudget`].
+///
+/// Channels which can't start yet are queued, and only the head of the queue may start,
+/// so a channel with a large inrush isn't starved by smaller ones slipping in ahead of it.
+pub(crate) struct PowerScheduler<const N: usize> {
+    budget: PowerBudget,
+    /// Draw while running, in mA
+    run_ma: [u32; N],
+    /// Draw while starting, in mA
+    inrush_ma: [u32; N],
+    /// When each powered channel was enabled
+    started: [Option<Instant>; N],
+    queue: Deque<u8, N>,
+}
+
+pub(crate) enum Admission {
+    Admitted,
+    /// Not admitted, and newly queued
+    Queued,
+    /// Not admitted, and already queued
+    Waiting,
+}
+
+impl<const N: usize> PowerScheduler<N> {
+    pub fn new(budget: PowerBudget) -> Self {
+        PowerScheduler {
+            budget,
+            run_ma: [0; N],
+            inrush_ma: [u32::MAX; N],
+            started: [None; N],
+            queue: Deque::new(),
+        }
+    }
+
+    /// Declares the channel's draw, undeclared inrush is assumed to take the whole supply
+    pub fn set_draw(&mut self, channel: usize, run_ma: Option<u16>, inrush_ma: Option<u16>) {
+        self.run_ma[channel] = run_ma.unwrap_or(0) as u32;
+        self.inrush_ma[channel] = inrush_ma.map(|ma| ma as u32).unwrap_or(u32::MAX);
+    }
+
+    /// Asks to enable the channel's driver, queueing it if the supply can't take its inrush yet.
+    ///
+    /// A channel is always admitted onto an idle supply, even if its inrush alone exceeds the budget.
+    pub fn request(&mut self, channel: usize, now: Instant) -> Admission {
+        if self.started[channel].is_some() {
+            return Admission::Admitted;
+        }
+
+        let queued = self.queue.iter().any(|&c| c as usize == channel);
+        let turn = self.queue.front().is_none_or(|&c| c as usize == channel);
+        let load = self.load(now);
+        let fits = load.saturating_add(self.inrush_ma[channel]) <= self.budget.supply_ma;
+
+        if turn && (load == 0 || fits) {
+            if queued {
+                self.queue.pop_front();
+            }
+            self.started[channel] = Some(now);
+            Admission::Admitted
+        } else if queued {
+            Admission::Waiting
+        } else {
+            let _ = self.queue.push_back(channel as u8);
+            Admission::Queued
+        }
+    }
+
+    /// Releases the channel's share of the supply once its driver is disabled, or gives up its place in the queue
+    pub fn release(&mut self, channel: usize) {
+        self.started[channel] = None;
+        if self.queue.iter().any(|&c| c as usize == channel) {
+            let mut queue = Deque::new();
+            for c in self.queue.iter().filter(|&&c| c as usize != channel) {
+                let _ = queue.push_back(*c);
+            }
+            self.queue = queue;
+        }
+    }
+
+    /// Channels waiting for power, as a bitmask
+    pub fn waiting(&self) -> u16 {
+        self.queue.iter().fold(0, |mask, &c| mask | (1 << c))
+    }
+
+    /// When the predicted load next drops, as a channel's inrush ends, should anything be waiting on it
+    pub fn next_change(&self, now: Instant) -> Option<Instant> {
+        if self.queue.is_empty() {
+            return None;
+        }
+
+        self.started
+            .iter()
+            .flatten()
+            .map(|&started| started + self.budget.inrush)
+            .filter(|&end| end > now)
+            .min()
+    }
+
+    /// Predicted draw of the powered channels, in mA
+    fn load(&self, now: Instant) -> u32 {
+        self.started
+            .iter()
+            .enumerate()
+            .filter_map(|(i, started)| {
+                started.map(|started| {
+                    if now < started + self.budget.inrush {
+                        self.inrush_ma[i]
+                    } else {
+                        self.run_ma[i]
+                    }
+                })
+            })
+            .fold(0, u32::saturating_add)
+    }
+}
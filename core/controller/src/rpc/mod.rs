@@ -3,6 +3,16 @@ mod serial;
 #[cfg(feature = "host-usb")]
 mod usb_cdc_acm;
 
+#[cfg(feature = "configurable_driver")]
+use crate::board::{DriverError, DriverStatus};
+use crate::board::EndstopConfig;
+use crate::board::{SensorReading, ThermalThresholds, MAX_SENSORS, MAX_SENSOR_NAME};
+use crate::group::{GroupSetup, GroupState, GroupTarget};
+use crate::supply::SupplyState;
+use crate::thermal::ThermalState;
+use crate::MotionFault;
+use embassy_time::Timer;
+use heapless::Vec;
 use sequencer::WindowDressingState;
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "host-uart")]
@@ -21,6 +31,13 @@ pub trait AsyncRpc {
     async fn peek(&mut self) -> Result<Option<&IncomingRpcPacket>, Self::Error>;
     async fn read(&mut self) -> Result<Option<IncomingRpcPacket>, Self::Error>;
     async fn write(&mut self, packet: &OutgoingRpcPacket) -> Result<(), Self::Error>;
+    /// Resolves once a packet may be ready to read, without consuming anything.
+    ///
+    /// The default implementation polls at the rate the control loop used to tick at,
+    /// implementers should override it where the hardware can signal incoming data.
+    async fn wait_readable(&mut self) {
+        Timer::after_millis(250).await
+    }
     /// The default implementation is to call write repeatedly,
     /// but implementers can choose to optimize the calls
     async fn write_bulk(
@@ -43,12 +60,40 @@ pub enum IncomingRpcPacket {
     },
     Setup {
         channel: u8,
+        /// Position to start from, otherwise the channel keeps its own, or the one the board persisted
         init: Option<WindowDressingState>,
         full_cycle_steps: u32,
         reverse: Option<bool>,
         full_tilt_steps: Option<u32>,
+        /// Percentage of travel to reverse by after running into an obstruction, none by default
+        back_off: Option<u8>,
+        /// Stops within this many percent of either end of travel are taken as reaching it, 5 by default
+        travel_limit_tolerance: Option<u8>,
+        /// Replaces the endstop configuration given by the board, fields left out take their defaults
+        endstop: Option<EndstopConfig>,
         #[cfg(feature = "stallguard")]
         sgthrs: Option<u8>,
+        /// Home and detect the end of travel by StallGuard, for channels without endstops
+        #[cfg(feature = "stallguard")]
+        sensorless: Option<bool>,
+        /// In 32nds of the driver's full scale current
+        #[cfg(feature = "configurable_driver")]
+        run_current: Option<u8>,
+        /// In 32nds of the driver's full scale current
+        #[cfg(feature = "configurable_driver")]
+        hold_current: Option<u8>,
+        /// Step quantities and the step frequency are given in full steps and scaled by this,
+        /// so positions and speeds stay consistent.
+        #[cfg(feature = "configurable_driver")]
+        microsteps: Option<u16>,
+        #[cfg(feature = "configurable_driver")]
+        stealthchop: Option<bool>,
+        /// Current drawn from the supply while running, in mA, none if left out
+        #[cfg(feature = "brownout-protection")]
+        run_draw_ma: Option<u16>,
+        /// Current drawn from the supply while starting, in mA, the whole supply if left out
+        #[cfg(feature = "brownout-protection")]
+        inrush_draw_ma: Option<u16>,
     },
     Set {
         channel: u8,
@@ -58,10 +103,43 @@ pub enum IncomingRpcPacket {
     Get {
         channel: u8,
     },
+    /// Drives set up channels together from now on, or on their own again when `kind` is left out.
+    /// Channels of a group are only moved through `SetGroup`, though they may still be homed on their own.
+    SetupGroup {
+        group: u8,
+        kind: Option<GroupSetup>,
+    },
+    SetGroup {
+        group: u8,
+        target: GroupTarget,
+    },
+    /// Replies with a `Sensors` packet
+    GetSensors {},
+    /// Sends a `Sensors` packet every `interval_s` seconds, or stops doing so when zero
+    SensorReporting {
+        interval_s: u16,
+    },
+    /// Replaces the thermal thresholds given by the board
+    ThermalLimits {
+        limits: ThermalThresholds,
+    },
     #[cfg(feature = "stallguard")]
     GetStallGuardResult {
         channel: u8,
     },
+    #[cfg(feature = "configurable_driver")]
+    ReadRegister {
+        channel: u8,
+        reg: u8,
+    },
+    #[cfg(feature = "configurable_driver")]
+    WriteRegister {
+        channel: u8,
+        reg: u8,
+        value: u32,
+        /// Required to write registers which could damage the hardware
+        force: Option<bool>,
+    },
     // This is not normally available to a generic Serial RPC caller,
     // it could be triggered by a side-channel flag like
     // - Lowering the baud rate below 1200Hz per Arduino / pico-sdk convention
@@ -69,6 +147,17 @@ pub enum IncomingRpcPacket {
     Bootloader,
 }
 
+/// Longest a `Sensors` packet serializes to, line ending included, which host handles must fit in a single write
+pub const MAX_SENSORS_PACKET: usize = {
+    // {"sensors":{"readings":[...]}}\r\n
+    let envelope = 29;
+    // {"name":"...","value":...,"unit":"..."}, with the longest float ryu prints and the longest unit
+    let reading = 31 + MAX_SENSOR_NAME + 16 + "millivolts".len();
+    envelope + (MAX_SENSORS + 1) * reading
+};
+
+// `Sensors` is the odd one out, but packets are queued by value as there's no allocator
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum OutgoingRpcPacket {
@@ -76,18 +165,77 @@ pub enum OutgoingRpcPacket {
         channel: u8,
     },
     Ready {},
+    /// The `Setup` was refused, so the channel keeps whatever it was set up with before
+    SetupError {
+        channel: u8,
+        error: SetupError,
+    },
     Position {
         channel: u8,
         #[serde(skip_serializing_if = "is_false")]
         notify: bool,
         current: WindowDressingState,
         desired: WindowDressingState,
+        /// Stopped short of the desired state by something in the way, until the next command
+        #[serde(skip_serializing_if = "is_false")]
+        obstructed: bool,
+        /// Held back from starting until the supply can take the motor's inrush current
+        #[serde(skip_serializing_if = "is_false")]
+        waiting_for_power: bool,
+        /// Combined state of the group driving the channel
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group: Option<GroupState>,
+    },
+    /// Latched until the channel is homed again
+    MotionFault {
+        channel: u8,
+        fault: MotionFault,
+    },
+    /// The supply sagged or recovered, as confirmed by the board's supply monitor
+    Supply {
+        state: SupplyState,
+        millivolts: u16,
+    },
+    /// The supply voltage, should the board monitor it, followed by the board's own readings
+    Sensors {
+        readings: Vec<SensorReading, { MAX_SENSORS + 1 }>,
+    },
+    /// The board's temperature crossed a threshold, sent on every change of state
+    Thermal {
+        state: ThermalState,
+        /// Left out once the sensor has failed, with the state escalated to at least `derate`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        celsius: Option<f32>,
     },
     #[cfg(feature = "stallguard")]
     StallGuardResult {
         channel: u8,
         sg_result: u8,
     },
+    #[cfg(feature = "configurable_driver")]
+    DriverStatus {
+        channel: u8,
+        status: DriverStatus,
+    },
+    #[cfg(feature = "configurable_driver")]
+    Register {
+        channel: u8,
+        reg: u8,
+        value: u32,
+    },
+    #[cfg(feature = "configurable_driver")]
+    RegisterError {
+        channel: u8,
+        reg: u8,
+        error: DriverError,
+    },
+}
+
+#[derive(Clone, Copy, Debug, defmt::Format, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SetupError {
+    /// The travel, in microsteps, doesn't fit in a step count
+    TravelOverflow,
 }
 
 fn is_false(b: &bool) -> bool {
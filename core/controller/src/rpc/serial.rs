@@ -1,8 +1,10 @@
-use crate::rpc::{AsyncRpc, AsyncRpcError, IncomingRpcPacket, OutgoingRpcPacket};
+use crate::rpc::{
+    AsyncRpc, AsyncRpcError, IncomingRpcPacket, OutgoingRpcPacket, MAX_SENSORS_PACKET,
+};
 use cortex_m::peripheral::SCB;
 use defmt::{debug, error, info, trace, write, Format, Formatter};
 use embassy_time::{Duration, Instant};
-use embedded_io_async::{ErrorType, Read, ReadExactError, ReadReady, Write};
+use embedded_io_async::{BufRead, ErrorType, Read, ReadExactError, ReadReady, Write};
 
 /// Trait implementer and wrapper of a text-based port over any simple hardware protocol implementing [`embedded_io_async`]
 ///
@@ -60,6 +62,13 @@ impl<E: embedded_io_async::Error> AsyncRpcError for SerialRpcError<E> {
 impl<const N: usize, IO> SerialRpcHandle<N, IO> {
     #[allow(unused)]
     pub fn new(serial: IO) -> Self {
+        const {
+            assert!(
+                N >= MAX_SENSORS_PACKET,
+                "N must fit a full `Sensors` packet"
+            );
+        }
+
         Self {
             last_read_success: Instant::now(),
             read_buf: None,
@@ -70,7 +79,7 @@ impl<const N: usize, IO> SerialRpcHandle<N, IO> {
 
 impl<const N: usize, IO> AsyncRpc for SerialRpcHandle<N, IO>
 where
-    IO: Read + ReadReady + Write,
+    IO: Read + ReadReady + BufRead + Write,
     <IO as ErrorType>::Error: defmt::Format,
 {
     type Error = SerialRpcError<IO::Error>;
@@ -151,6 +160,15 @@ where
         Ok(None)
     }
 
+    async fn wait_readable(&mut self) {
+        if self.read_buf.is_some() {
+            return;
+        }
+
+        // Errors are left for `read` to report
+        let _ = self.serial.fill_buf().await;
+    }
+
     async fn write(&mut self, resp: &OutgoingRpcPacket) -> Result<(), Self::Error> {
         let mut outgoing_packet_buf = [b'\n'; N];
 
@@ -1,4 +1,6 @@
-use crate::rpc::{AsyncRpc, AsyncRpcError, IncomingRpcPacket, OutgoingRpcPacket};
+use crate::rpc::{
+    AsyncRpc, AsyncRpcError, IncomingRpcPacket, OutgoingRpcPacket, MAX_SENSORS_PACKET,
+};
 use circ_buffer::RingBuffer;
 use core::cmp::min;
 use defmt::{Format, Formatter};
@@ -89,6 +91,13 @@ pub struct UsbRpcHandle<const N: usize, D: UsbDriver<'static>> {
 
 impl<const N: usize, D: UsbDriver<'static>> UsbRpcHandle<N, D> {
     pub fn new(driver: D) -> (UsbDevice<'static, D>, Self) {
+        const {
+            assert!(
+                N >= MAX_SENSORS_PACKET,
+                "N must fit a full `Sensors` packet"
+            );
+        }
+
         let (device, stream) = UsbCdcAcmStream::init(driver);
 
         (
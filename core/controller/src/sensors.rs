@@ -0,0 +1,65 @@
+use crate::board::{BoardSensors, SensorReading, SensorUnit, MAX_SENSORS, MAX_SENSOR_NAME};
+use crate::rpc::OutgoingRpcPacket;
+use crate::supply::supply_mv;
+use crate::*;
+use heapless::Vec;
+use portable_atomic::AtomicBool;
+
+/// Set by `GetSensors`, cleared once the diagnostics task replies
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Seconds between unsolicited `Sensors` packets, none when zero
+static INTERVAL_S: AtomicU16 = AtomicU16::new(0);
+
+pub(crate) fn request_sensors() {
+    REQUESTED.store(true, Ordering::Release);
+}
+
+pub(crate) fn set_sensor_interval(interval_s: u16) {
+    INTERVAL_S.store(interval_s, Ordering::Release);
+}
+
+/// Reports the board's sensors for the diagnostics task, on request and periodically
+pub(crate) struct SensorReporter {
+    last_report: Option<Instant>,
+}
+
+impl SensorReporter {
+    pub fn new() -> Self {
+        SensorReporter { last_report: None }
+    }
+
+    pub async fn poll<T: BoardSensors>(&mut self, board_state: &mut T) {
+        let now = Instant::now();
+        let interval_s = INTERVAL_S.load(Ordering::Acquire);
+        let due = interval_s != 0
+            && self
+                .last_report
+                .is_none_or(|last| now >= last + Duration::from_secs(interval_s as u64));
+
+        if !REQUESTED.swap(false, Ordering::AcqRel) && !due {
+            return;
+        }
+        self.last_report = Some(now);
+
+        let mut readings = Vec::new();
+        if let Some(mv) = supply_mv() {
+            let _ = readings.push(SensorReading {
+                name: "supply",
+                value: mv as f32,
+                unit: SensorUnit::Millivolts,
+            });
+        }
+
+        let mut board_readings = Vec::<_, MAX_SENSORS>::new();
+        board_state.read_sensors(&mut board_readings);
+        for reading in board_readings {
+            if reading.name.len() > MAX_SENSOR_NAME {
+                warn!("Leaving out sensor {}, its name is too long", reading.name);
+                continue;
+            }
+            let _ = readings.push(reading);
+        }
+
+        OUTGOING.send(OutgoingRpcPacket::Sensors { readings }).await;
+    }
+}
@@ -0,0 +1,93 @@
+use crate::board::{SupplyThresholds, SupplyVoltage};
+use crate::rpc::OutgoingRpcPacket;
+use crate::*;
+use portable_atomic::{AtomicU16, AtomicU8};
+
+/// Supply state as last confirmed by the monitor, acted on by the motion task
+static SUPPLY: AtomicU8 = AtomicU8::new(SupplyState::Normal as u8);
+/// Last reading of the supply voltage in mV, zero if the board doesn't monitor it
+static SUPPLY_MV: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Clone, Copy, Eq, PartialEq, Format, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum SupplyState {
+    Normal,
+    /// Motion is held with its steps still queued, until the supply recovers
+    Undervoltage,
+    /// Every channel was stopped and its position reported, latched until the supply recovers
+    PowerFail,
+}
+
+impl SupplyState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            1 => SupplyState::Undervoltage,
+            2 => SupplyState::PowerFail,
+            _ => SupplyState::Normal,
+        }
+    }
+
+    /// The state after a reading of `mv`, with the band between the undervoltage and recovery thresholds
+    /// keeping the current state
+    fn next(self, mv: u16, thresholds: &SupplyThresholds) -> Self {
+        if mv < thresholds.power_fail_mv {
+            SupplyState::PowerFail
+        } else if mv >= thresholds.recovery_mv {
+            SupplyState::Normal
+        } else if mv < thresholds.undervoltage_mv && self == SupplyState::Normal {
+            SupplyState::Undervoltage
+        } else {
+            self
+        }
+    }
+}
+
+pub(crate) fn supply_state() -> SupplyState {
+    SupplyState::from_u8(SUPPLY.load(Ordering::Acquire))
+}
+
+/// Last reading of the supply voltage in mV, if the board monitors it
+pub(crate) fn supply_mv() -> Option<u16> {
+    Some(SUPPLY_MV.load(Ordering::Relaxed)).filter(|&mv| mv != 0)
+}
+
+/// Watches the supply voltage, waking the motion task to hold or abandon motion as it sags.
+///
+/// Boards with their supply wired to an ADC channel spawn this alongside the other tasks.
+/// A state change has to be seen in consecutive readings before it's acted on, so ADC noise can't stop motion.
+pub async fn run<V: SupplyVoltage>(sensor: &mut V) {
+    let thresholds = sensor.supply_thresholds();
+    let mut state = SupplyState::Normal;
+    let mut confirmations = 0u8;
+
+    loop {
+        match sensor.read_supply_mv() {
+            Some(mv) => {
+                SUPPLY_MV.store(mv, Ordering::Relaxed);
+                let next = state.next(mv, &thresholds);
+                if next == state {
+                    confirmations = 0;
+                } else {
+                    confirmations += 1;
+                }
+
+                if confirmations >= SUPPLY_CONFIRMATIONS {
+                    confirmations = 0;
+                    state = next;
+                    warn!("Supply is now {} at {}mV", state, mv);
+
+                    SUPPLY.store(state as u8, Ordering::Release);
+                    WAKE.signal(());
+                    emit(OutgoingRpcPacket::Supply {
+                        state,
+                        millivolts: mv,
+                    });
+                }
+            }
+            None => error!("Failed to read the supply voltage"),
+        }
+
+        Timer::after(SUPPLY_POLL_INTERVAL).await;
+    }
+}
@@ -0,0 +1,148 @@
+use crate::board::{DriverConfig, ThermalThresholds, Thermometer};
+use crate::rpc::OutgoingRpcPacket;
+use crate::*;
+use portable_atomic::AtomicU8;
+
+/// Thermal state as last read by the diagnostics task
+static THERMAL: AtomicU8 = AtomicU8::new(ThermalState::Normal as u8);
+/// Percentage of the run current the drivers are configured with
+static DERATE_PERCENT: AtomicU8 = AtomicU8::new(100);
+/// Threshold changes from the host, picked up by the diagnostics task
+pub(crate) static THERMAL_LIMITS: Signal<CriticalSectionRawMutex, ThermalThresholds> =
+    Signal::new();
+
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Format, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum ThermalState {
+    Normal,
+    Warn,
+    /// Run current is scaled down
+    Derate,
+    /// Motion is stopped, and new moves are refused
+    Shutdown,
+}
+
+impl ThermalState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            1 => ThermalState::Warn,
+            2 => ThermalState::Derate,
+            3 => ThermalState::Shutdown,
+            _ => ThermalState::Normal,
+        }
+    }
+
+    /// Escalates straight to the highest threshold reached, but only drops back
+    /// as far as the temperature is below each threshold by the hysteresis
+    fn next(self, celsius: f32, thresholds: &ThermalThresholds) -> Self {
+        let level = |offset: f32| {
+            if celsius >= thresholds.shutdown_c as f32 - offset {
+                ThermalState::Shutdown
+            } else if celsius >= thresholds.derate_c as f32 - offset {
+                ThermalState::Derate
+            } else if celsius >= thresholds.warn_c as f32 - offset {
+                ThermalState::Warn
+            } else {
+                ThermalState::Normal
+            }
+        };
+
+        let rising = level(0.0);
+        if rising >= self {
+            rising
+        } else {
+            level(thresholds.hysteresis_c as f32).min(self)
+        }
+    }
+}
+
+pub(crate) fn thermal_state() -> ThermalState {
+    ThermalState::from_u8(THERMAL.load(Ordering::Acquire))
+}
+
+/// The channel's configuration as it should be programmed, with the run current derated while warm
+pub(crate) fn derated(config: &DriverConfig) -> DriverConfig {
+    config.derated(DERATE_PERCENT.load(Ordering::Acquire))
+}
+
+/// Tracks the board's temperature for the diagnostics task, which derates the drivers as it changes
+pub(crate) struct ThermalMonitor {
+    thresholds: ThermalThresholds,
+    state: ThermalState,
+    next_read: Instant,
+    /// Consecutive readings which failed, or which the board rejected as implausible
+    failures: u8,
+}
+
+impl ThermalMonitor {
+    pub fn new<T: Thermometer>(thermometer: &T) -> Self {
+        ThermalMonitor {
+            thresholds: thermometer.thermal_thresholds(),
+            state: ThermalState::Normal,
+            next_read: Instant::now(),
+            failures: 0,
+        }
+    }
+
+    /// Reads the temperature when due, returning whether the drivers have to be reconfigured for a change in derating
+    pub fn poll<T: Thermometer>(&mut self, thermometer: &mut T) -> bool {
+        if let Some(thresholds) = THERMAL_LIMITS.try_take() {
+            info!("Thermal thresholds replaced by the host: {}", thresholds);
+            self.thresholds = thresholds;
+            self.next_read = Instant::now();
+        }
+
+        let now = Instant::now();
+        if now < self.next_read {
+            return false;
+        }
+        self.next_read = now + THERMAL_INTERVAL;
+
+        let celsius = if let Some(celsius) = thermometer.read_temp_celsius() {
+            self.failures = 0;
+            celsius
+        } else {
+            // Holding the last state through a glitch,
+            // but a sensor which stays out could miss the board overheating
+            error!("Failed to read the board temperature");
+            self.failures = self.failures.saturating_add(1);
+            if self.failures != THERMAL_FAILURES_TOLERATED {
+                return false;
+            }
+
+            let next = self.state.max(ThermalState::Derate);
+            warn!("Board temperature is unknown, falling back to {}", next);
+            return self.enter(next, None);
+        };
+
+        let next = self.state.next(celsius, &self.thresholds);
+        if next == self.state {
+            return false;
+        }
+
+        warn!("Board is now {} at {}C", next, celsius);
+        self.enter(next, Some(celsius))
+    }
+
+    /// Acts on the state and reports it to the host, returning whether the derating changed
+    fn enter(&mut self, next: ThermalState, celsius: Option<f32>) -> bool {
+        let percent = if next >= ThermalState::Derate {
+            self.thresholds.derate_percent
+        } else {
+            100
+        };
+        self.state = next;
+
+        THERMAL.store(next as u8, Ordering::Release);
+        if next == ThermalState::Shutdown {
+            WAKE.signal(());
+        }
+        emit(OutgoingRpcPacket::Thermal {
+            state: next,
+            celsius,
+        });
+
+        DERATE_PERCENT.swap(percent, Ordering::AcqRel) != percent
+    }
+}
@@ -27,6 +27,27 @@ impl<T: SensingWindowDressingSequencer> SensingWindowDressingSequencer for Rampi
         self.inner.trig_endstop()
     }
 
+    fn trig_obstruction(&mut self, back_off: u8, direction: Direction, unrun: u32) {
+        self.inner.trig_obstruction(back_off, direction, unrun)
+    }
+
+    fn trig_halt(&mut self, direction: Direction, unrun: u32) {
+        self.last_direction = Direction::Hold;
+        self.inner.trig_halt(direction, unrun)
+    }
+
+    fn is_travel_limit(&self, tolerance: u8) -> bool {
+        self.inner.is_travel_limit(tolerance)
+    }
+
+    fn is_obstructed(&self) -> bool {
+        self.inner.is_obstructed()
+    }
+
+    fn is_homing(&self) -> bool {
+        self.inner.is_homing()
+    }
+
     fn home_fully_opened(&mut self) {
         self.inner.home_fully_opened()
     }
@@ -112,6 +133,14 @@ impl<T: WindowDressingSequencer> WindowDressingSequencer for Ramping<T> {
     fn set_tilt(&mut self, tilt: i8) {
         self.inner.set_tilt(tilt)
     }
+
+    fn get_queued_quantity(&self) -> u32 {
+        self.inner.get_queued_quantity()
+    }
+
+    fn delay(&mut self, quantity: u32) -> bool {
+        self.inner.delay(quantity)
+    }
 }
 
 impl<T> Deref for RampingInstruction<T> {
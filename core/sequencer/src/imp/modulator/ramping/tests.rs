@@ -53,6 +53,14 @@ impl WindowDressingSequencer for Emitter {
     fn set_tilt(&mut self, _tilt: i8) {
         unimplemented!()
     }
+
+    fn get_queued_quantity(&self) -> u32 {
+        unimplemented!()
+    }
+
+    fn delay(&mut self, _quantity: u32) -> bool {
+        unimplemented!()
+    }
 }
 impl<const N: usize> WindowDressingSequencer for Emitters<N> {
     type Instruction = Emitter;
@@ -95,6 +103,14 @@ impl<const N: usize> WindowDressingSequencer for Emitters<N> {
     fn set_tilt(&mut self, _tilt: i8) {
         unimplemented!()
     }
+
+    fn get_queued_quantity(&self) -> u32 {
+        unimplemented!()
+    }
+
+    fn delay(&mut self, _quantity: u32) -> bool {
+        unimplemented!()
+    }
 }
 
 #[test]
@@ -0,0 +1,103 @@
+use crate::{CurtainSequencer, Direction, WindowDressingSequencer};
+
+#[cfg(test)]
+mod tests;
+
+impl CurtainSequencer {
+    /// Plans for sides resting where their sequencers are, with the right side's motor mirroring the left's.
+    pub fn new<T: WindowDressingSequencer>(left: &T, right: Option<&T>) -> Self {
+        let mut seq = Self {
+            open_directions: [Direction::Retract, Direction::Extend],
+            touch_to_move: false,
+            desired_position: 0,
+        };
+        seq.sync(left, right);
+        seq
+    }
+
+    /// Sets the direction each side's motor turns to open, where holding counts as the usual direction.
+    pub fn with_open_directions(mut self, left: Direction, right: Direction) -> Self {
+        self.open_directions = [left, right].map(|direction| match direction {
+            Direction::Extend => Direction::Extend,
+            Direction::Retract | Direction::Hold => Direction::Retract,
+        });
+        self
+    }
+
+    pub fn with_touch_to_move(mut self, touch_to_move: bool) -> Self {
+        self.touch_to_move = touch_to_move;
+        self
+    }
+
+    /// Direction each side's motor turns to open, left then right.
+    pub fn get_open_directions(&self) -> &[Direction; 2] {
+        &self.open_directions
+    }
+
+    pub fn is_touch_to_move(&self) -> bool {
+        self.touch_to_move
+    }
+
+    /// Get the position of the curtain, which is only as opened as its least opened side.
+    pub fn get_current_state<T: WindowDressingSequencer>(&self, left: &T, right: Option<&T>) -> u8 {
+        let position = left.get_current_state().position;
+        right.map_or(position, |right| {
+            position.min(right.get_current_state().position)
+        })
+    }
+
+    pub fn get_desired_state(&self) -> u8 {
+        self.desired_position
+    }
+
+    /// Where a tug on the curtain at rest draws it, towards whichever end is further away,
+    /// or `None` unless it's touch to move.
+    pub fn get_tug_target<T: WindowDressingSequencer>(
+        &self,
+        left: &T,
+        right: Option<&T>,
+    ) -> Option<u8> {
+        if !self.touch_to_move {
+            return None;
+        }
+
+        if self.get_current_state(left, right) < 50 {
+            Some(100)
+        } else {
+            Some(0)
+        }
+    }
+
+    /// Takes the sides as they are, e.g. after one of them was homed.
+    pub fn sync<T: WindowDressingSequencer>(&mut self, left: &T, right: Option<&T>) {
+        self.desired_position = self.get_current_state(left, right);
+    }
+
+    /// Command to draw the curtain to `position`, with both sides of a split curtain arriving together.
+    ///
+    /// The steps are run at a fixed frequency, so whichever side has less to run is held back by the difference.
+    /// Returns false if that side had no room left to queue the hold, so the sides won't arrive together.
+    pub fn set_position<T: WindowDressingSequencer>(
+        &mut self,
+        position: u8,
+        left: &mut T,
+        right: Option<&mut T>,
+    ) -> bool {
+        let position = position.min(100);
+        self.desired_position = position;
+        left.set_position(position);
+
+        let Some(right) = right else {
+            return true;
+        };
+        right.set_position(position);
+
+        let left_quantity = left.get_queued_quantity();
+        let right_quantity = right.get_queued_quantity();
+        if left_quantity > right_quantity {
+            right.delay(left_quantity - right_quantity)
+        } else {
+            left.delay(right_quantity - left_quantity)
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use crate::imp::sequencer::tests::{roller_at, HaltingSequencer};
+use crate::model::sequencer::CurtainSequencer;
+use crate::{Direction, WindowDressingSequencer};
+
+/// Steps until the side has run its last move
+fn arrival(seq: &mut HaltingSequencer) -> u32 {
+    let mut elapsed = 0;
+    let mut arrived = 0;
+    while let Some(instr) = seq.get_next_instruction() {
+        elapsed += instr.quantity;
+        if instr.direction != Direction::Hold {
+            arrived = elapsed;
+        }
+    }
+    arrived
+}
+
+#[test]
+fn takes_least_opened_side() {
+    let (left, right) = (roller_at(10_000, 60), roller_at(10_000, 40));
+    let seq = CurtainSequencer::new(&left, Some(&right));
+
+    assert_eq!(seq.get_current_state(&left, Some(&right)), 40);
+    assert_eq!(seq.get_desired_state(), 40);
+}
+
+#[test]
+fn mirrors_by_default() {
+    let left = roller_at(10_000, 0);
+    let seq = CurtainSequencer::new(&left, None);
+
+    assert_eq!(
+        *seq.get_open_directions(),
+        [Direction::Retract, Direction::Extend]
+    );
+    assert!(!seq.is_touch_to_move());
+}
+
+#[test]
+fn open_directions_per_side() {
+    let left = roller_at(10_000, 0);
+    let seq =
+        CurtainSequencer::new(&left, None).with_open_directions(Direction::Extend, Direction::Hold);
+
+    assert_eq!(
+        *seq.get_open_directions(),
+        [Direction::Extend, Direction::Retract]
+    );
+}
+
+#[test]
+fn single_side() {
+    let mut left = roller_at(10_000, 0);
+    let mut seq = CurtainSequencer::new(&left, None);
+    seq.set_position(70, &mut left, None);
+
+    assert_eq!(left.desired_state.position, 70);
+    assert_eq!(arrival(&mut left), 7_000);
+    assert_eq!(seq.get_current_state(&left, None), 70);
+}
+
+#[test]
+fn split_sides_share_a_position() {
+    let (mut left, mut right) = (roller_at(10_000, 0), roller_at(10_000, 0));
+    let mut seq = CurtainSequencer::new(&left, Some(&right));
+    seq.set_position(100, &mut left, Some(&mut right));
+
+    assert_eq!(left.desired_state.position, 100);
+    assert_eq!(right.desired_state.position, 100);
+    assert_eq!(arrival(&mut left), arrival(&mut right));
+}
+
+#[test]
+fn shorter_side_is_held_back() {
+    let (mut left, mut right) = (roller_at(10_000, 0), roller_at(6_000, 0));
+    let mut seq = CurtainSequencer::new(&left, Some(&right));
+    seq.set_position(50, &mut left, Some(&mut right));
+
+    let hold = right.instructions.front().unwrap();
+    assert_eq!((hold.direction, hold.quantity), (Direction::Hold, 2_000));
+    assert_eq!(arrival(&mut left), 5_000);
+    assert_eq!(arrival(&mut right), 5_000);
+}
+
+#[test]
+fn uneven_sides_arrive_together() {
+    let (mut left, mut right) = (roller_at(10_000, 30), roller_at(10_000, 10));
+    let mut seq = CurtainSequencer::new(&left, Some(&right));
+    seq.set_position(80, &mut left, Some(&mut right));
+
+    assert_eq!(arrival(&mut left), 7_000);
+    assert_eq!(arrival(&mut right), 7_000);
+}
+
+#[test]
+fn tug_ignored_without_touch_to_move() {
+    let left = roller_at(10_000, 100);
+    let seq = CurtainSequencer::new(&left, None);
+
+    assert_eq!(seq.get_tug_target(&left, None), None);
+}
+
+#[test]
+fn tug_opens_closed_curtain() {
+    let (left, right) = (roller_at(10_000, 10), roller_at(10_000, 10));
+    let seq = CurtainSequencer::new(&left, Some(&right)).with_touch_to_move(true);
+
+    assert_eq!(seq.get_tug_target(&left, Some(&right)), Some(100));
+}
+
+#[test]
+fn tug_closes_opened_curtain() {
+    let left = roller_at(10_000, 100);
+    let seq = CurtainSequencer::new(&left, None).with_touch_to_move(true);
+
+    assert_eq!(seq.get_tug_target(&left, None), Some(0));
+}
@@ -6,6 +6,9 @@ use core::ops::AddAssign;
 mod tests;
 
 const HOLD_QUANTITY: u32 = 500;
+/// Homing runs this many percent past the full travel, so a travel which has crept longer
+/// than configured still reaches its endstop before the move runs out
+const HOMING_OVERTRAVEL: u32 = 10;
 
 impl<const N: usize> HaltingSequencer<N> {
     pub fn new(full_cycle_quantity: u32, full_tilt_quantity: Option<u32>) -> Self {
@@ -38,6 +41,35 @@ impl<const N: usize> HaltingSequencer<N> {
             .map_or(self.current_state, |i| i.completed_state)
     }
 
+    /// Position short of the current state by `unrun` steps issued in `direction`, rounded to the nearest percent
+    fn wound_back(&self, direction: Direction, unrun: u32) -> u8 {
+        let per_percent = (self.full_cycle_quantity / 100).max(1);
+        let percent = ((unrun + per_percent / 2) / per_percent).min(100) as u8;
+
+        let position = self.current_state.position;
+        match direction {
+            Direction::Retract => position.saturating_sub(percent),
+            Direction::Extend => position.saturating_add(percent).min(100),
+            Direction::Hold => position,
+        }
+    }
+
+    /// Runs from `from` until the endstop at `to`, with the full travel stretched by the overtravel allowance
+    fn home(&mut self, from: WindowDressingState, to: WindowDressingState) {
+        let nominal = self.full_cycle_quantity;
+        // The travel is run a percent at a time, so short travels still need a step for every percent
+        self.full_cycle_quantity = nominal
+            .saturating_add(nominal / 100 * HOMING_OVERTRAVEL)
+            .max(100);
+
+        self.current_state = from;
+        self.desired_state = from;
+        self.set_position(to.position);
+        self.homing = true;
+
+        self.full_cycle_quantity = nominal;
+    }
+
     /// Schedules the command necessary to tilt the window dressing.
     fn add_tilt(&mut self, from_angle: i8, to_angle: i8) {
         let opening = to_angle < from_angle;
@@ -159,6 +191,8 @@ impl<const N: usize> WindowDressingSequencer for HaltingSequencer<N> {
     fn load_state(&mut self, state: &WindowDressingState) {
         self.current_state = *state;
         self.desired_state = *state;
+        self.homing = false;
+        self.obstructed = false;
     }
 
     /// Command from HAP to set both the position and tilt of the window dressing
@@ -169,6 +203,8 @@ impl<const N: usize> WindowDressingSequencer for HaltingSequencer<N> {
 
     /// Command from HAP to set the position of the window dressing.
     fn set_position(&mut self, opened: u8) {
+        self.homing = false;
+        self.obstructed = false;
         self.desired_state.position = opened;
         let tail = self.instructions.pop_back();
         self.instructions.clear();
@@ -227,14 +263,35 @@ impl<const N: usize> WindowDressingSequencer for HaltingSequencer<N> {
 
     /// Command from HAP to set the tilt of the window dressing.
     fn set_tilt(&mut self, angle: i8) {
+        self.obstructed = false;
         self.add_tilt(self.get_tail_state().tilt, angle);
     }
+
+    fn get_queued_quantity(&self) -> u32 {
+        self.instructions.iter().map(|i| i.quantity).sum()
+    }
+
+    fn delay(&mut self, quantity: u32) -> bool {
+        if quantity == 0 || self.instructions.is_empty() {
+            return true;
+        }
+
+        self.instructions
+            .push_front(HaltingWindowDressingInstruction {
+                direction: Direction::Hold,
+                quantity,
+                completed_state: self.current_state,
+            })
+            .is_ok()
+    }
 }
 
 impl<const N: usize> SensingWindowDressingSequencer for HaltingSequencer<N> {
     /// Feedback from hardware that the endstop has been triggered.
     fn trig_endstop(&mut self) {
         self.instructions.clear();
+        self.homing = false;
+        self.obstructed = false;
 
         // Offload logic hell to comparator implementation
         //
@@ -270,16 +327,91 @@ impl<const N: usize> SensingWindowDressingSequencer for HaltingSequencer<N> {
             .expect("Endstop should've cleared the instructions queue");
     }
 
+    /// Feedback from hardware that the motion was stopped short of the travel limit.
+    ///
+    /// The estimated state is kept, and the window dressing backs off by `back_off` percent
+    /// in the opposite direction to release whatever is in the way.
+    fn trig_obstruction(&mut self, back_off: u8, direction: Direction, unrun: u32) {
+        // Only travel is backed off, there's nothing to release when an obstruction stops a tilt
+        let travel = match self.desired_state.position.cmp(&self.current_state.position) {
+            Ordering::Greater => Direction::Retract,
+            Ordering::Less => Direction::Extend,
+            // The last of the travel was already popped, which is all a roller could've been running
+            Ordering::Equal if self.full_tilt_quantity.is_none() => direction,
+            Ordering::Equal => Direction::Hold,
+        };
+
+        self.instructions.clear();
+        self.current_state.position = self.wound_back(travel, unrun);
+        self.desired_state = self.current_state;
+
+        let position = self.current_state.position;
+        let back_off_position = match travel {
+            Direction::Retract => position.saturating_sub(back_off),
+            Direction::Extend => position.saturating_add(back_off).min(100),
+            Direction::Hold => position,
+        };
+
+        if back_off_position != position {
+            // It's safe to eat the error because the state will not be corrupted
+            let _ = self.instructions.push_back(HaltingWindowDressingInstruction {
+                direction: Direction::Hold,
+                quantity: HOLD_QUANTITY,
+                completed_state: self.current_state,
+            });
+            self.set_position(back_off_position);
+        }
+
+        self.homing = false;
+        self.obstructed = true;
+    }
+
+    /// Feedback from hardware that the motion was cut short, e.g. ahead of a loss of power.
+    ///
+    /// The current state is wound back by the steps which were never run, rounded to the nearest percent,
+    /// and the window dressing is held there. Only travel is wound back, tilts are too short to matter.
+    fn trig_halt(&mut self, direction: Direction, unrun: u32) {
+        self.current_state.position = self.wound_back(direction, unrun);
+        self.desired_state = self.current_state;
+        self.instructions.clear();
+        self.instructions
+            .push_back(HaltingWindowDressingInstruction {
+                direction: Direction::Hold,
+                quantity: HOLD_QUANTITY,
+                completed_state: self.current_state,
+            })
+            .expect("Halt should've cleared the instructions queue");
+        self.homing = false;
+    }
+
+    fn is_travel_limit(&self, tolerance: u8) -> bool {
+        if self.homing && !self.instructions.is_empty() {
+            return true;
+        }
+
+        let position = self.current_state.position;
+        match self.desired_state.cmp(&self.current_state) {
+            Ordering::Greater => position >= 100u8.saturating_sub(tolerance),
+            Ordering::Less => position <= tolerance,
+            // Not moving, so it can't have run into anything
+            Ordering::Equal => true,
+        }
+    }
+
+    fn is_obstructed(&self) -> bool {
+        self.obstructed
+    }
+
+    fn is_homing(&self) -> bool {
+        self.homing
+    }
+
     fn home_fully_opened(&mut self) {
-        self.current_state = WindowDressingState::closed();
-        self.desired_state = WindowDressingState::closed();
-        self.set_position(WindowDressingState::opened().position);
+        self.home(WindowDressingState::closed(), WindowDressingState::opened());
     }
 
     fn home_fully_closed(&mut self) {
-        self.current_state = WindowDressingState::opened();
-        self.desired_state = WindowDressingState::opened();
-        self.set_position(WindowDressingState::closed().position);
+        self.home(WindowDressingState::opened(), WindowDressingState::closed());
     }
 }
 
@@ -0,0 +1,70 @@
+use crate::model::sequencer::{HaltingWindowDressingInstruction, WindowDressingState};
+use crate::{Direction, WindowDressingSequencer};
+type HaltingSequencer = crate::model::sequencer::HaltingSequencer<1024>;
+
+#[test]
+fn nothing_queued_at_rest() {
+    let seq = HaltingSequencer::new_roller(100_000);
+    assert_eq!(seq.get_queued_quantity(), 0);
+}
+
+#[test]
+fn queued_quantity_covers_travel() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.set_position(40);
+    assert_eq!(seq.get_queued_quantity(), 40_000);
+
+    seq.get_next_instruction();
+    assert_eq!(seq.get_queued_quantity(), 39_000);
+}
+
+#[test]
+fn queued_quantity_includes_holds() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.set_position(40);
+    seq.get_next_instruction();
+    seq.set_position(0);
+
+    // Reversing is preceded by a hold
+    assert_eq!(seq.get_queued_quantity(), 500 + 1_000);
+}
+
+#[test]
+fn delay_holds_ahead_of_travel() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.set_position(40);
+    assert!(seq.delay(2_500));
+
+    assert_eq!(
+        seq.get_next_instruction(),
+        Some(HaltingWindowDressingInstruction {
+            direction: Direction::Hold,
+            quantity: 2_500,
+            completed_state: WindowDressingState::default(),
+        })
+    );
+    assert_eq!(
+        seq.get_next_instruction().unwrap().direction,
+        Direction::Retract
+    );
+    assert_eq!(seq.desired_state.position, 40);
+}
+
+#[test]
+fn no_delay_at_rest() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    assert!(seq.delay(2_500));
+    assert_eq!(seq.get_next_instruction(), None);
+}
+
+#[test]
+fn delay_refused_when_queue_is_full() {
+    let mut seq = crate::model::sequencer::HaltingSequencer::<4>::new_roller(100_000);
+    seq.set_position(40);
+
+    assert!(!seq.delay(2_500));
+    assert_eq!(
+        seq.get_next_instruction().unwrap().direction,
+        Direction::Retract
+    );
+}
@@ -0,0 +1,82 @@
+use crate::model::sequencer::{HaltingWindowDressingInstruction, WindowDressingState};
+use crate::{Direction, SensingWindowDressingSequencer, WindowDressingSequencer};
+type HaltingSequencer = crate::model::sequencer::HaltingSequencer<1024>;
+
+#[test]
+fn open_halt_winds_back() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.current_state.position = 0;
+    seq.set_position(100);
+
+    let grouped = seq.get_next_instruction_grouped(20_000).unwrap();
+    assert_eq!(grouped.quantity, 20_000);
+    assert_eq!(seq.current_state.position, 20);
+
+    seq.trig_halt(Direction::Retract, 7_400);
+    assert_eq!(
+        seq.current_state,
+        WindowDressingState {
+            position: 13,
+            tilt: 0
+        }
+    );
+    assert_eq!(seq.desired_state, seq.current_state);
+    assert_eq!(
+        seq.get_next_instruction(),
+        Some(HaltingWindowDressingInstruction {
+            direction: Direction::Hold,
+            quantity: 500,
+            completed_state: seq.current_state,
+        })
+    );
+    assert_eq!(seq.get_next_instruction(), None);
+}
+
+#[test]
+fn close_halt_winds_back() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.current_state.position = 100;
+    seq.set_position(0);
+
+    for _ in 1..=30 {
+        seq.get_next_instruction();
+    }
+
+    seq.trig_halt(Direction::Extend, 4_600);
+    assert_eq!(seq.current_state.position, 75);
+    assert_eq!(seq.desired_state, seq.current_state);
+}
+
+#[test]
+fn halt_is_clamped_to_travel() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.current_state.position = 0;
+    seq.set_position(100);
+    seq.get_next_instruction();
+
+    seq.trig_halt(Direction::Retract, 50_000);
+    assert_eq!(seq.current_state.position, 0);
+}
+
+#[test]
+fn halt_at_rest_keeps_state() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.load_state(&WindowDressingState {
+        position: 40,
+        tilt: 0,
+    });
+
+    seq.trig_halt(Direction::Hold, 500);
+    assert_eq!(seq.current_state.position, 40);
+    assert_eq!(seq.desired_state, seq.current_state);
+}
+
+#[test]
+fn halt_ends_homing() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.home_fully_opened();
+    seq.get_next_instruction();
+
+    seq.trig_halt(Direction::Retract, 1_000);
+    assert!(!seq.is_homing());
+}
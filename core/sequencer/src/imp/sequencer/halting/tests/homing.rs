@@ -0,0 +1,79 @@
+use crate::{
+    Direction, SensingWindowDressingSequencer, WindowDressingInstruction, WindowDressingSequencer,
+};
+type HaltingSequencer = crate::model::sequencer::HaltingSequencer<1024>;
+
+#[test]
+fn homing_until_endstop() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.home_fully_opened();
+    assert!(seq.is_homing());
+
+    while seq.get_next_instruction().is_some() {}
+    assert!(seq.is_homing());
+
+    seq.trig_endstop();
+    assert!(!seq.is_homing());
+}
+
+#[test]
+fn homing_runs_past_full_travel() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.home_fully_closed();
+
+    let mut quantity = 0;
+    while let Some(instr) = seq.get_next_instruction() {
+        if *instr.get_direction() != Direction::Hold {
+            quantity += instr.get_quantity();
+        }
+    }
+    assert_eq!(quantity, 110_000);
+    assert!(seq.is_homing());
+}
+
+#[test]
+fn homing_short_travel_steps_every_percent() {
+    let mut seq = HaltingSequencer::new_roller(50);
+    seq.home_fully_closed();
+
+    let mut quantity = 0;
+    while let Some(instr) = seq.get_next_instruction() {
+        if *instr.get_direction() != Direction::Hold {
+            quantity += instr.get_quantity();
+        }
+    }
+    assert_eq!(quantity, 100);
+    assert!(seq.is_homing());
+}
+
+#[test]
+fn homing_long_travel_saturates() {
+    let mut seq = HaltingSequencer::new_roller(4_000_000_000);
+    seq.home_fully_closed();
+
+    let mut quantity = 0u64;
+    while let Some(instr) = seq.get_next_instruction() {
+        if *instr.get_direction() != Direction::Hold {
+            quantity += *instr.get_quantity() as u64;
+        }
+    }
+    assert!(quantity >= 4_000_000_000);
+    assert!(quantity <= u32::MAX as u64);
+    assert!(seq.is_homing());
+}
+
+#[test]
+fn homing_keeps_configured_travel() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.home_fully_opened();
+    seq.trig_endstop();
+
+    seq.set_position(50);
+    let mut quantity = 0;
+    while let Some(instr) = seq.get_next_instruction() {
+        if *instr.get_direction() != Direction::Hold {
+            quantity += instr.get_quantity();
+        }
+    }
+    assert_eq!(quantity, 50_000);
+}
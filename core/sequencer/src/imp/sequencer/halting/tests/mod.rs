@@ -1,6 +1,10 @@
 use crate::{HaltingSequencer, WindowDressingState};
 
 mod comparator;
+mod delay;
+mod halt;
+mod homing;
+mod obstruction;
 mod roller;
 mod roller_grouped;
 mod roller_ramming;
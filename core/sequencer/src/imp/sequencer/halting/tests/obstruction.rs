@@ -0,0 +1,186 @@
+use crate::model::sequencer::{HaltingWindowDressingInstruction, WindowDressingState};
+use crate::{Direction, SensingWindowDressingSequencer, WindowDressingSequencer};
+type HaltingSequencer = crate::model::sequencer::HaltingSequencer<1024>;
+
+#[test]
+fn travel_limit_near_end() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.current_state.position = 0;
+    seq.set_position(100);
+
+    for _ in 1..=50 {
+        seq.get_next_instruction();
+    }
+    assert!(!seq.is_travel_limit(5));
+
+    for _ in 51..=96 {
+        seq.get_next_instruction();
+    }
+    assert!(seq.is_travel_limit(5));
+}
+
+#[test]
+fn travel_limit_while_homing() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.home_fully_opened();
+
+    for _ in 1..=10 {
+        seq.get_next_instruction();
+    }
+    assert!(seq.is_travel_limit(5));
+}
+
+#[test]
+fn travel_limit_at_rest() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.load_state(&WindowDressingState {
+        position: 50,
+        tilt: 0,
+    });
+
+    assert!(seq.is_travel_limit(5));
+}
+
+#[test]
+fn close_obstruction_keeps_position() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.current_state.position = 100;
+    seq.set_position(0);
+
+    for _ in 1..=40 {
+        seq.get_next_instruction();
+    }
+
+    seq.trig_obstruction(0, Direction::Extend, 0);
+    assert!(seq.is_obstructed());
+    assert_eq!(
+        seq.current_state,
+        WindowDressingState {
+            position: 60,
+            tilt: 0
+        }
+    );
+    assert_eq!(seq.desired_state, seq.current_state);
+    assert_eq!(seq.get_next_instruction(), None);
+}
+
+#[test]
+fn close_obstruction_backs_off() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.current_state.position = 100;
+    seq.set_position(0);
+
+    for _ in 1..=40 {
+        seq.get_next_instruction();
+    }
+
+    seq.trig_obstruction(5, Direction::Extend, 0);
+    assert!(seq.is_obstructed());
+    assert_eq!(
+        seq.desired_state,
+        WindowDressingState {
+            position: 65,
+            tilt: 0
+        }
+    );
+    assert_eq!(
+        seq.get_next_instruction(),
+        Some(HaltingWindowDressingInstruction {
+            direction: Direction::Hold,
+            quantity: 500,
+            completed_state: WindowDressingState {
+                position: 60,
+                tilt: 0
+            },
+        })
+    );
+    for i in 61..=65 {
+        assert_eq!(
+            seq.get_next_instruction(),
+            Some(HaltingWindowDressingInstruction {
+                direction: Direction::Retract,
+                quantity: 1000,
+                completed_state: WindowDressingState {
+                    position: i,
+                    tilt: 0
+                },
+            })
+        );
+    }
+    assert!(seq.is_obstructed());
+}
+
+#[test]
+fn obstruction_winds_back_unrun_steps() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.current_state.position = 100;
+    seq.set_position(0);
+
+    for _ in 1..=40 {
+        seq.get_next_instruction();
+    }
+
+    // 60% reported, but 2.6% of travel was still queued when the obstruction stopped the motor
+    seq.trig_obstruction(0, Direction::Extend, 2_600);
+    assert_eq!(
+        seq.current_state,
+        WindowDressingState {
+            position: 63,
+            tilt: 0
+        }
+    );
+    assert_eq!(seq.desired_state, seq.current_state);
+}
+
+#[test]
+fn obstruction_after_last_travel_popped() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.current_state.position = 0;
+    seq.set_position(20);
+
+    while seq.get_next_instruction().is_some() {}
+
+    seq.trig_obstruction(5, Direction::Retract, 4_000);
+    assert_eq!(
+        seq.current_state,
+        WindowDressingState {
+            position: 16,
+            tilt: 0
+        }
+    );
+    assert_eq!(seq.desired_state.position, 11);
+}
+
+#[test]
+fn open_obstruction_back_off_is_clamped() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.current_state.position = 0;
+    seq.set_position(100);
+
+    for _ in 1..=3 {
+        seq.get_next_instruction();
+    }
+
+    seq.trig_obstruction(10, Direction::Retract, 0);
+    assert_eq!(
+        seq.desired_state,
+        WindowDressingState {
+            position: 0,
+            tilt: 0
+        }
+    );
+}
+
+#[test]
+fn obstruction_cleared_by_next_command() {
+    let mut seq = HaltingSequencer::new_roller(100_000);
+    seq.current_state.position = 100;
+    seq.set_position(0);
+    seq.get_next_instruction();
+
+    seq.trig_obstruction(0, Direction::Extend, 0);
+    assert!(seq.is_obstructed());
+
+    seq.set_position(100);
+    assert!(!seq.is_obstructed());
+}
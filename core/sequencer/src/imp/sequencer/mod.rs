@@ -1,6 +1,9 @@
 use crate::Direction;
 
+mod curtain;
 mod halting;
+mod tdbu;
+mod vertical;
 #[cfg(test)]
 mod tests;
 
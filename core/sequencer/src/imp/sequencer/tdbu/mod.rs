@@ -0,0 +1,147 @@
+use crate::{SensingWindowDressingSequencer, TdbuSequencer, TdbuState, WindowDressingSequencer};
+
+#[cfg(test)]
+mod tests;
+
+/// How far a rail is lowered from the head rail, as the rail's sequencer counts fully raised as opened.
+fn lowered<T: WindowDressingSequencer>(rail: &T) -> u8 {
+    100 - rail.get_current_state().position.min(100)
+}
+
+/// Sends a rail to `lowered` percent below the head rail, unless it's already headed there.
+fn send_rail<T: WindowDressingSequencer>(rail: &mut T, lowered: u8) -> bool {
+    let position = 100 - lowered;
+    if rail.get_desired_state().position == position {
+        return false;
+    }
+
+    rail.set_position(position);
+    true
+}
+
+impl TdbuSequencer {
+    /// Plans for rails resting where their sequencers are, kept at least `min_gap` percent apart.
+    pub fn new<T: WindowDressingSequencer>(min_gap: u8, top: &T, bottom: &T) -> Self {
+        let mut seq = Self {
+            min_gap: min_gap.min(100),
+            ..Default::default()
+        };
+        seq.sync(top, bottom);
+        seq
+    }
+
+    /// Get the combined state of the rails, as far as their sequencers have issued instructions.
+    pub fn get_current_state<T: WindowDressingSequencer>(&self, top: &T, bottom: &T) -> TdbuState {
+        TdbuState {
+            top: lowered(top),
+            bottom: lowered(bottom),
+        }
+    }
+
+    pub fn get_desired_state(&self) -> &TdbuState {
+        &self.desired_state
+    }
+
+    /// Drops any held back moves and takes the rails as they are, e.g. after one of them was homed.
+    pub fn sync<T: WindowDressingSequencer>(&mut self, top: &T, bottom: &T) {
+        self.desired_state = self.get_current_state(top, bottom);
+        self.deferred_top = false;
+        self.deferred_bottom = false;
+    }
+
+    /// Homes the top rail on its own, raised into the head rail with nothing in its way.
+    ///
+    /// Moves held back for the other rail are dropped, and the group should sync once the rail is homed.
+    pub fn home_top<T: SensingWindowDressingSequencer>(&mut self, top: &mut T) {
+        self.deferred_top = false;
+        self.deferred_bottom = false;
+        top.home_fully_opened();
+    }
+
+    /// Homes the bottom rail on its own, fully lowered and away from the top rail,
+    /// as raising it to the head rail would drive it through the top rail.
+    ///
+    /// Moves held back for the other rail are dropped, and the group should sync once the rail is homed.
+    pub fn home_bottom<T: SensingWindowDressingSequencer>(&mut self, bottom: &mut T) {
+        self.deferred_top = false;
+        self.deferred_bottom = false;
+        bottom.home_fully_closed();
+    }
+
+    /// Command to lower either or both rails, in percent from the head rail.
+    ///
+    /// A commanded rail stops short of the other rather than pushing it along, and when both are
+    /// commanded the top rail gives way.
+    pub fn set_state<T: WindowDressingSequencer>(
+        &mut self,
+        top_lowered: Option<u8>,
+        bottom_lowered: Option<u8>,
+        top: &mut T,
+        bottom: &mut T,
+    ) {
+        let gap = self.min_gap;
+        let mut desired = self.desired_state;
+
+        if let Some(lowered) = bottom_lowered {
+            desired.bottom = lowered.clamp(gap, 100);
+        }
+        if let Some(lowered) = top_lowered {
+            desired.top = lowered.min(100 - gap);
+        }
+
+        if top_lowered.is_some() {
+            desired.top = desired.top.min(desired.bottom.saturating_sub(gap));
+        } else {
+            desired.bottom = desired.bottom.max(desired.top.saturating_add(gap).min(100));
+        }
+
+        self.desired_state = desired;
+        self.plan(true, true, top, bottom);
+    }
+
+    /// Sends on the rails which were held back, once both rails have come to a stop.
+    ///
+    /// Returns whether any rail was sent, which won't happen again for a rail stuck behind an obstructed one.
+    pub fn advance<T: WindowDressingSequencer>(
+        &mut self,
+        top: &mut T,
+        top_idle: bool,
+        bottom: &mut T,
+        bottom_idle: bool,
+    ) -> bool {
+        if !top_idle || !bottom_idle {
+            return false;
+        }
+
+        self.plan(self.deferred_top, self.deferred_bottom, top, bottom)
+    }
+
+    /// Sends the rails as far towards the desired state as they can go without closing the gap to
+    /// where the other rail is now. Both rails can then move at once, whatever their speeds, and at most
+    /// one of them is left to finish its move after the other has stopped.
+    fn plan<T: WindowDressingSequencer>(
+        &mut self,
+        plan_top: bool,
+        plan_bottom: bool,
+        top: &mut T,
+        bottom: &mut T,
+    ) -> bool {
+        let current = self.get_current_state(top, bottom);
+        let desired = self.desired_state;
+        let gap = self.min_gap;
+        let mut sent = false;
+
+        if plan_top {
+            let to = desired.top.min(current.bottom.saturating_sub(gap));
+            self.deferred_top = to != desired.top;
+            sent |= send_rail(top, to);
+        }
+        if plan_bottom {
+            let to = desired.bottom.max(current.top.saturating_add(gap).min(100));
+            self.deferred_bottom = to != desired.bottom;
+            sent |= send_rail(bottom, to);
+        }
+
+        sent
+    }
+}
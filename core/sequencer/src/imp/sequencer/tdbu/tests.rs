@@ -0,0 +1,236 @@
+use crate::imp::sequencer::tests::{drain, roller_at, HaltingSequencer};
+use crate::model::sequencer::{TdbuSequencer, TdbuState};
+use crate::{Direction, SensingWindowDressingSequencer, WindowDressingSequencer};
+
+/// Rails resting at `top` and `bottom` percent lowered from the head rail.
+fn rails(top: u8, bottom: u8) -> (HaltingSequencer, HaltingSequencer) {
+    (
+        roller_at(10_000, 100 - top),
+        roller_at(10_000, 100 - bottom),
+    )
+}
+
+#[test]
+fn takes_state_from_rails() {
+    let (top, bottom) = rails(10, 60);
+    let seq = TdbuSequencer::new(5, &top, &bottom);
+
+    let expect = TdbuState {
+        top: 10,
+        bottom: 60,
+    };
+    assert_eq!(seq.get_current_state(&top, &bottom), expect);
+    assert_eq!(*seq.get_desired_state(), expect);
+}
+
+#[test]
+fn converging_rails_move_together() {
+    let (mut top, mut bottom) = rails(0, 100);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.set_state(Some(30), Some(50), &mut top, &mut bottom);
+
+    assert_eq!(top.desired_state.position, 70);
+    assert_eq!(bottom.desired_state.position, 50);
+    assert!(!seq.deferred_top);
+    assert!(!seq.deferred_bottom);
+}
+
+#[test]
+fn lowering_holds_top_rail_back() {
+    let (mut top, mut bottom) = rails(0, 20);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.set_state(Some(60), Some(90), &mut top, &mut bottom);
+
+    // The top rail may only follow as far as where the bottom rail starts from
+    assert_eq!(top.desired_state.position, 85);
+    assert_eq!(bottom.desired_state.position, 10);
+    assert!(seq.deferred_top);
+
+    drain(&mut top);
+    drain(&mut bottom);
+    assert!(seq.advance(&mut top, true, &mut bottom, true));
+    assert_eq!(top.desired_state.position, 40);
+    assert!(!seq.deferred_top);
+
+    drain(&mut top);
+    assert_eq!(
+        seq.get_current_state(&top, &bottom),
+        TdbuState {
+            top: 60,
+            bottom: 90
+        }
+    );
+}
+
+#[test]
+fn raising_holds_bottom_rail_back() {
+    let (mut top, mut bottom) = rails(60, 90);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.set_state(Some(0), Some(20), &mut top, &mut bottom);
+
+    assert_eq!(top.desired_state.position, 100);
+    assert_eq!(bottom.desired_state.position, 35);
+    assert!(seq.deferred_bottom);
+
+    drain(&mut top);
+    drain(&mut bottom);
+    assert!(seq.advance(&mut top, true, &mut bottom, true));
+    assert_eq!(bottom.desired_state.position, 80);
+}
+
+#[test]
+fn waits_for_both_rails_to_stop() {
+    let (mut top, mut bottom) = rails(0, 20);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.set_state(Some(60), Some(90), &mut top, &mut bottom);
+
+    assert!(!seq.advance(&mut top, true, &mut bottom, false));
+    assert!(!seq.advance(&mut top, false, &mut bottom, true));
+    assert_eq!(top.desired_state.position, 85);
+}
+
+#[test]
+fn nothing_to_advance_without_deferral() {
+    let (mut top, mut bottom) = rails(0, 100);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.set_state(Some(10), None, &mut top, &mut bottom);
+    drain(&mut top);
+
+    assert!(!seq.advance(&mut top, true, &mut bottom, true));
+}
+
+#[test]
+fn commanded_top_stops_short_of_bottom() {
+    let (mut top, mut bottom) = rails(0, 50);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.set_state(Some(80), None, &mut top, &mut bottom);
+
+    assert_eq!(
+        *seq.get_desired_state(),
+        TdbuState {
+            top: 45,
+            bottom: 50
+        }
+    );
+    assert_eq!(bottom.desired_state.position, 50);
+}
+
+#[test]
+fn commanded_bottom_stops_short_of_top() {
+    let (mut top, mut bottom) = rails(40, 80);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.set_state(None, Some(10), &mut top, &mut bottom);
+
+    assert_eq!(
+        *seq.get_desired_state(),
+        TdbuState {
+            top: 40,
+            bottom: 45
+        }
+    );
+    assert_eq!(top.desired_state.position, 60);
+}
+
+#[test]
+fn top_gives_way_when_both_commanded() {
+    let (mut top, mut bottom) = rails(0, 100);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.set_state(Some(70), Some(30), &mut top, &mut bottom);
+
+    assert_eq!(
+        *seq.get_desired_state(),
+        TdbuState {
+            top: 25,
+            bottom: 30
+        }
+    );
+}
+
+#[test]
+fn keeps_gap_at_the_travel_limits() {
+    let (mut top, mut bottom) = rails(0, 100);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.set_state(Some(100), Some(0), &mut top, &mut bottom);
+
+    assert_eq!(*seq.get_desired_state(), TdbuState { top: 0, bottom: 5 });
+    seq.set_state(Some(100), None, &mut top, &mut bottom);
+    assert_eq!(*seq.get_desired_state(), TdbuState { top: 0, bottom: 5 });
+}
+
+#[test]
+fn gap_holds_whatever_the_rail_speeds() {
+    for (top_speed, bottom_speed) in [(1, 1), (3, 1), (1, 3)] {
+        for (from, to) in [
+            ((0, 20), (60, 90)),
+            ((60, 90), (0, 20)),
+            ((10, 90), (40, 50)),
+        ] {
+            let (mut top, mut bottom) = rails(from.0, from.1);
+            let mut seq = TdbuSequencer::new(5, &top, &bottom);
+            seq.set_state(Some(to.0), Some(to.1), &mut top, &mut bottom);
+
+            loop {
+                let mut moved = false;
+                for _ in 0..top_speed {
+                    moved |= top.get_next_instruction().is_some();
+                }
+                for _ in 0..bottom_speed {
+                    moved |= bottom.get_next_instruction().is_some();
+                }
+
+                let state = seq.get_current_state(&top, &bottom);
+                assert!(state.top + 5 <= state.bottom, "{state:?}");
+
+                if !moved && !seq.advance(&mut top, true, &mut bottom, true) {
+                    break;
+                }
+            }
+
+            assert_eq!(
+                seq.get_current_state(&top, &bottom),
+                TdbuState {
+                    top: to.0,
+                    bottom: to.1
+                }
+            );
+        }
+    }
+}
+
+#[test]
+fn homes_bottom_rail_away_from_top() {
+    let (top, mut bottom) = rails(30, 60);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.home_bottom(&mut bottom);
+
+    // Raising the bottom rail to the head rail would drive it through the top rail
+    while let Some(instr) = bottom.get_next_instruction() {
+        assert_ne!(instr.direction, Direction::Retract);
+    }
+    bottom.trig_endstop();
+    seq.sync(&top, &bottom);
+    assert_eq!(
+        seq.get_current_state(&top, &bottom),
+        TdbuState {
+            top: 30,
+            bottom: 100
+        }
+    );
+}
+
+#[test]
+fn homes_top_rail_into_head_rail() {
+    let (mut top, bottom) = rails(30, 60);
+    let mut seq = TdbuSequencer::new(5, &top, &bottom);
+    seq.home_top(&mut top);
+
+    for _ in 0..50 {
+        top.get_next_instruction();
+    }
+    top.trig_endstop();
+    seq.sync(&top, &bottom);
+    assert_eq!(
+        seq.get_current_state(&top, &bottom),
+        TdbuState { top: 0, bottom: 60 }
+    );
+}
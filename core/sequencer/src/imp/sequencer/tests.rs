@@ -1,4 +1,19 @@
-use crate::{Direction, WindowDressingState};
+use crate::{Direction, WindowDressingSequencer, WindowDressingState};
+
+/// Sequencer of a single channel, as grouped by the window dressings driving several channels
+pub(super) type HaltingSequencer = crate::model::sequencer::HaltingSequencer<1024>;
+
+/// A channel taking `full_cycle_quantity` steps for its full travel, resting at `position`
+pub(super) fn roller_at(full_cycle_quantity: u32, position: u8) -> HaltingSequencer {
+    let mut seq = HaltingSequencer::new_roller(full_cycle_quantity);
+    seq.load_state(&WindowDressingState { position, tilt: 0 });
+    seq
+}
+
+/// Runs every instruction the channel has queued
+pub(super) fn drain(seq: &mut HaltingSequencer) {
+    while seq.get_next_instruction().is_some() {}
+}
 
 #[test]
 fn reverse_direction() {
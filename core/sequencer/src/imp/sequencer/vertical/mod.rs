@@ -0,0 +1,134 @@
+use crate::model::sequencer::VerticalStage;
+use crate::{VerticalSequencer, WindowDressingSequencer, WindowDressingState};
+
+#[cfg(test)]
+mod tests;
+
+impl VerticalSequencer {
+    /// Plans for channels resting where their sequencers are, with the vanes stacking at `stack_tilt`.
+    pub fn new<T: WindowDressingSequencer>(stack_tilt: i8, traverse: &T, rotation: &mut T) -> Self {
+        let mut seq = Self {
+            stack_tilt: stack_tilt.clamp(-90, 90),
+            ..Default::default()
+        };
+        seq.sync(traverse, rotation);
+        seq
+    }
+
+    /// Get the combined state of the channels, as far as their sequencers have issued instructions.
+    pub fn get_current_state<T: WindowDressingSequencer>(
+        &self,
+        traverse: &T,
+        rotation: &T,
+    ) -> WindowDressingState {
+        WindowDressingState {
+            position: traverse.get_current_state().position,
+            tilt: rotation.get_current_state().tilt,
+        }
+    }
+
+    pub fn get_desired_state(&self) -> &WindowDressingState {
+        &self.desired_state
+    }
+
+    /// Whether the vanes are turned to stack, so the traverse channel may move on its own, e.g. to home.
+    pub fn is_stacked<T: WindowDressingSequencer>(&self, rotation: &T) -> bool {
+        rotation.get_current_state().tilt == self.stack_tilt
+            && rotation.get_desired_state().tilt == self.stack_tilt
+    }
+
+    /// Drops any staged moves and takes the channels as they are, e.g. after one of them was homed.
+    ///
+    /// The rotation channel never travels, yet a tilting sequencer refuses to tilt while fully opened,
+    /// so its travel is kept at fully closed.
+    pub fn sync<T: WindowDressingSequencer>(&mut self, traverse: &T, rotation: &mut T) {
+        let tilt = rotation.get_current_state().tilt;
+        if rotation.get_current_state().position != 0 {
+            rotation.load_state(&WindowDressingState { position: 0, tilt });
+        }
+
+        self.desired_state = self.get_current_state(traverse, rotation);
+        self.stage = VerticalStage::Idle;
+    }
+
+    /// Command to traverse and rotate the vanes, either of which may be left out.
+    ///
+    /// A change in position turns the vanes to stack first, and back to the desired tilt once traversed.
+    pub fn set_state<T: WindowDressingSequencer>(
+        &mut self,
+        position: Option<u8>,
+        tilt: Option<i8>,
+        traverse: &mut T,
+        rotation: &mut T,
+    ) {
+        if let Some(position) = position {
+            self.desired_state.position = position.min(100);
+        }
+        if let Some(tilt) = tilt {
+            self.desired_state.tilt = tilt.clamp(-90, 90);
+        }
+
+        let position = self.desired_state.position;
+        match self.stage {
+            // The vanes are already stacked, so the traverse can be sent straight on
+            VerticalStage::Traversing => {
+                if traverse.get_desired_state().position != position {
+                    traverse.set_position(position);
+                }
+            }
+            VerticalStage::Stacking => {}
+            VerticalStage::Idle if traverse.get_desired_state().position != position => {
+                self.stage = VerticalStage::Stacking;
+                if rotation.get_desired_state().tilt != self.stack_tilt {
+                    rotation.set_tilt(self.stack_tilt);
+                }
+            }
+            VerticalStage::Idle => {
+                if rotation.get_desired_state().tilt != self.desired_state.tilt {
+                    rotation.set_tilt(self.desired_state.tilt);
+                }
+            }
+        }
+    }
+
+    /// Moves on to the next stage once both channels have come to a stop. Returns whether anything was sent.
+    ///
+    /// Should the vanes be stopped short of the stack angle, e.g. by an obstruction, the traverse is abandoned.
+    pub fn advance<T: WindowDressingSequencer>(
+        &mut self,
+        traverse: &mut T,
+        traverse_idle: bool,
+        rotation: &mut T,
+        rotation_idle: bool,
+    ) -> bool {
+        if !traverse_idle || !rotation_idle {
+            return false;
+        }
+
+        match self.stage {
+            VerticalStage::Idle => false,
+            VerticalStage::Stacking if !self.is_stacked(rotation) => {
+                self.stage = VerticalStage::Idle;
+                false
+            }
+            VerticalStage::Stacking => {
+                self.stage = VerticalStage::Traversing;
+                let position = self.desired_state.position;
+                let sent = traverse.get_desired_state().position != position;
+                if sent {
+                    traverse.set_position(position);
+                }
+                sent
+            }
+            VerticalStage::Traversing => {
+                self.stage = VerticalStage::Idle;
+                let tilt = self.desired_state.tilt;
+                let sent = rotation.get_desired_state().tilt != tilt;
+                if sent {
+                    rotation.set_tilt(tilt);
+                }
+                sent
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::imp::sequencer::tests::{drain, roller_at, HaltingSequencer};
+use crate::model::sequencer::{VerticalSequencer, VerticalStage, WindowDressingState};
+use crate::{Direction, SensingWindowDressingSequencer, WindowDressingSequencer};
+
+/// Vanes traversed to `position` and rotated to `tilt`
+fn channels(position: u8, tilt: i8) -> (HaltingSequencer, HaltingSequencer) {
+    let mut rotation = HaltingSequencer::new_venetian(0, 1_800);
+    rotation.load_state(&WindowDressingState { position: 0, tilt });
+    (roller_at(10_000, position), rotation)
+}
+
+#[test]
+fn takes_state_from_channels() {
+    let (traverse, mut rotation) = channels(30, 45);
+    let seq = VerticalSequencer::new(0, &traverse, &mut rotation);
+
+    let expect = WindowDressingState {
+        position: 30,
+        tilt: 45,
+    };
+    assert_eq!(seq.get_current_state(&traverse, &rotation), expect);
+    assert_eq!(*seq.get_desired_state(), expect);
+}
+
+#[test]
+fn keeps_rotation_from_travelling() {
+    let (traverse, mut rotation) = channels(30, 45);
+    rotation.current_state.position = 100;
+    VerticalSequencer::new(0, &traverse, &mut rotation);
+
+    assert_eq!(rotation.current_state.position, 0);
+    assert_eq!(rotation.current_state.tilt, 45);
+}
+
+#[test]
+fn stacks_before_traversing() {
+    let (mut traverse, mut rotation) = channels(0, 90);
+    let mut seq = VerticalSequencer::new(0, &traverse, &mut rotation);
+    seq.set_state(Some(100), None, &mut traverse, &mut rotation);
+
+    assert_eq!(seq.stage, VerticalStage::Stacking);
+    assert_eq!(rotation.desired_state.tilt, 0);
+    assert_eq!(traverse.get_next_instruction(), None);
+
+    // The traverse waits for the rotation to stop
+    assert!(!seq.advance(&mut traverse, true, &mut rotation, false));
+    drain(&mut rotation);
+    assert!(seq.advance(&mut traverse, true, &mut rotation, true));
+    assert_eq!(seq.stage, VerticalStage::Traversing);
+    assert_eq!(traverse.desired_state.position, 100);
+}
+
+#[test]
+fn restores_tilt_after_traversing() {
+    let (mut traverse, mut rotation) = channels(0, 90);
+    let mut seq = VerticalSequencer::new(0, &traverse, &mut rotation);
+    seq.set_state(Some(60), Some(-30), &mut traverse, &mut rotation);
+
+    drain(&mut rotation);
+    seq.advance(&mut traverse, true, &mut rotation, true);
+    assert!(!seq.advance(&mut traverse, false, &mut rotation, true));
+
+    drain(&mut traverse);
+    assert!(seq.advance(&mut traverse, true, &mut rotation, true));
+    assert_eq!(seq.stage, VerticalStage::Idle);
+    drain(&mut rotation);
+
+    let expect = WindowDressingState {
+        position: 60,
+        tilt: -30,
+    };
+    assert_eq!(seq.get_current_state(&traverse, &rotation), expect);
+    assert_eq!(*seq.get_desired_state(), expect);
+}
+
+#[test]
+fn rotates_in_place_without_traversing() {
+    let (mut traverse, mut rotation) = channels(40, 0);
+    let mut seq = VerticalSequencer::new(0, &traverse, &mut rotation);
+    seq.set_state(None, Some(60), &mut traverse, &mut rotation);
+
+    assert_eq!(seq.stage, VerticalStage::Idle);
+    assert_eq!(rotation.desired_state.tilt, 60);
+    assert_eq!(traverse.get_next_instruction(), None);
+}
+
+#[test]
+fn traverses_straight_away_when_stacked() {
+    let (mut traverse, mut rotation) = channels(40, 0);
+    let mut seq = VerticalSequencer::new(0, &traverse, &mut rotation);
+    seq.set_state(Some(80), None, &mut traverse, &mut rotation);
+
+    assert_eq!(rotation.get_next_instruction(), None);
+    assert!(seq.advance(&mut traverse, true, &mut rotation, true));
+    assert_eq!(traverse.desired_state.position, 80);
+}
+
+#[test]
+fn retargets_traverse_while_traversing() {
+    let (mut traverse, mut rotation) = channels(0, 0);
+    let mut seq = VerticalSequencer::new(0, &traverse, &mut rotation);
+    seq.set_state(Some(80), None, &mut traverse, &mut rotation);
+    seq.advance(&mut traverse, true, &mut rotation, true);
+
+    seq.set_state(Some(50), Some(20), &mut traverse, &mut rotation);
+    assert_eq!(seq.stage, VerticalStage::Traversing);
+    assert_eq!(traverse.desired_state.position, 50);
+    assert_eq!(rotation.desired_state.tilt, 0);
+}
+
+#[test]
+fn abandons_traverse_unless_stacked() {
+    let (mut traverse, mut rotation) = channels(0, 90);
+    let mut seq = VerticalSequencer::new(0, &traverse, &mut rotation);
+    seq.set_state(Some(100), None, &mut traverse, &mut rotation);
+
+    for _ in 0..30 {
+        rotation.get_next_instruction();
+    }
+    rotation.trig_obstruction(0, Direction::Extend, 0);
+
+    assert!(!seq.advance(&mut traverse, true, &mut rotation, true));
+    assert_eq!(seq.stage, VerticalStage::Idle);
+    assert_eq!(traverse.get_next_instruction(), None);
+}
+
+#[test]
+fn only_traverses_while_stacked() {
+    let (mut traverse, mut rotation) = channels(100, -60);
+    let mut seq = VerticalSequencer::new(0, &traverse, &mut rotation);
+    seq.set_state(Some(0), Some(45), &mut traverse, &mut rotation);
+
+    loop {
+        let traversed = traverse.get_next_instruction().is_some();
+        if traversed {
+            assert_eq!(rotation.current_state.tilt, 0);
+        }
+        let rotated = rotation.get_next_instruction().is_some();
+
+        if !traversed && !rotated && !seq.advance(&mut traverse, true, &mut rotation, true) {
+            break;
+        }
+    }
+
+    assert_eq!(
+        seq.get_current_state(&traverse, &rotation),
+        WindowDressingState {
+            position: 0,
+            tilt: 45
+        }
+    );
+}
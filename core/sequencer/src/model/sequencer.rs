@@ -15,6 +15,9 @@ pub struct HaltingSequencer<const N: usize> {
     pub(crate) desired_state: WindowDressingState,
     pub(crate) current_state: WindowDressingState,
     pub(crate) instructions: Deque<HaltingWindowDressingInstruction, N>,
+    /// Set while a homing move is in progress, as the position is unknown until the endstop is reached
+    pub(crate) homing: bool,
+    pub(crate) obstructed: bool,
 }
 
 pub trait WindowDressingSequencer {
@@ -28,10 +31,27 @@ pub trait WindowDressingSequencer {
     fn set_state(&mut self, state: &WindowDressingState);
     fn set_position(&mut self, position: u8);
     fn set_tilt(&mut self, tilt: i8);
+    /// Steps queued ahead of reaching the desired state, holds included.
+    fn get_queued_quantity(&self) -> u32;
+    /// Holds for `quantity` steps before running the queued instructions, if there are any.
+    ///
+    /// Returns false if the queue has no room left for the hold.
+    fn delay(&mut self, quantity: u32) -> bool;
 }
 
 pub trait SensingWindowDressingSequencer: WindowDressingSequencer {
     fn trig_endstop(&mut self);
+    /// Feedback from hardware that the motion was stopped short of the travel limit,
+    /// with `unrun` steps in `direction` issued but never run.
+    fn trig_obstruction(&mut self, back_off: u8, direction: Direction, unrun: u32);
+    /// Feedback from hardware that the motion was cut short, with `unrun` steps in `direction`
+    /// issued but never run.
+    fn trig_halt(&mut self, direction: Direction, unrun: u32);
+    /// Whether a stop at the current state would be the end of travel rather than an obstruction.
+    fn is_travel_limit(&self, tolerance: u8) -> bool;
+    fn is_obstructed(&self) -> bool;
+    /// Whether a homing move was started and its endstop hasn't been reached yet.
+    fn is_homing(&self) -> bool;
     fn home_fully_opened(&mut self);
     fn home_fully_closed(&mut self);
 }
@@ -65,3 +85,59 @@ impl defmt::Format for WindowDressingState {
         )
     }
 }
+
+/// Plans the moves of the two rails of a top-down/bottom-up shade so the top rail never passes below
+/// the bottom rail. Each rail is sequenced on its own channel, this only decides where and when they go.
+#[derive(Debug, Default)]
+pub struct TdbuSequencer {
+    pub(crate) min_gap: u8,
+    pub(crate) desired_state: TdbuState,
+    /// Set for a rail whose move was cut short until the other rail has cleared the way
+    pub(crate) deferred_top: bool,
+    pub(crate) deferred_bottom: bool,
+}
+
+/// Rail positions of a top-down/bottom-up shade, in percent lowered from the head rail.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct TdbuState {
+    pub top: u8,
+    pub bottom: u8,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for TdbuState {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{{ top: {}, bottom: {} }}", self.top, self.bottom)
+    }
+}
+
+/// Plans the moves of a vertical blind, whose vanes are traversed by one channel and rotated by another,
+/// so the vanes are only ever traversed while turned to stack. The traverse channel's position is the
+/// blind's position, and the rotation channel's tilt is the blind's tilt.
+#[derive(Debug, Default)]
+pub struct VerticalSequencer {
+    pub(crate) stack_tilt: i8,
+    pub(crate) desired_state: WindowDressingState,
+    pub(crate) stage: VerticalStage,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) enum VerticalStage {
+    #[default]
+    Idle,
+    /// Turning the vanes to the stack angle, ahead of traversing them
+    Stacking,
+    /// Traversing the vanes, ahead of turning them back to the desired tilt
+    Traversing,
+}
+
+/// Plans the moves of a curtain, drawn to one side by a single channel, or split in the centre and drawn
+/// to both sides by a channel each. Split curtains mirror one position on both sides, and arrive together.
+#[derive(Debug)]
+pub struct CurtainSequencer {
+    /// Direction each side's motor turns to open, left then right, as mirrored motors turn opposite ways
+    pub(crate) open_directions: [Direction; 2],
+    /// Whether a tug on the curtain at rest draws it
+    pub(crate) touch_to_move: bool,
+    pub(crate) desired_position: u8,
+}
@@ -0,0 +1,114 @@
+use crate::TemperatureModel;
+
+/// Which side of the divider the sensor sits on
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Topology {
+    /// Fixed resistor from the reference to the ADC pin, sensor from the pin to ground
+    PullUp,
+    /// Sensor from the reference to the ADC pin, fixed resistor from the pin to ground
+    PullDown,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DividerError {
+    /// The sensor is disconnected, or its wire is broken
+    OpenCircuit,
+    /// The sensor's leads are shorted together
+    ShortCircuit,
+}
+
+/// Voltage divider between a resistive sensor and an ADC, referenced to the same voltage as the ADC.
+///
+/// The standard voltage divider formula is $V_{out} = \frac{V_{src}R_2}{R_1 + R_2}$. As the ADC is ratiometric,
+/// $V_{src}$ is its full scale, and with the sensor as $R_2$ in a pull-up,
+/// $$
+/// R_2 = \frac{V_{out}R_1}{V_{src} - V_{out}}
+/// $$
+/// Or with the sensor as $R_1$ in a pull-down,
+/// $$
+/// R_1 = \frac{(V_{src} - V_{out})R_2}{V_{out}}
+/// $$
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Divider {
+    pub topology: Topology,
+    /// The divider's fixed resistor, in ohms
+    pub fixed_resistance: f32,
+    /// Resistance in series with the sensor, e.g. a protection resistor or long leads, in ohms
+    pub series_resistance: f32,
+    /// Resolution of the ADC, from 1 to 16 bits as readings are `u16`
+    pub adc_bits: u8,
+    /// Reading with the ADC input grounded, subtracted from every reading
+    pub adc_offset: i16,
+}
+
+impl Divider {
+    pub const fn pull_up(fixed_resistance: f32, adc_bits: u8) -> Divider {
+        assert!(
+            adc_bits >= 1 && adc_bits <= 16,
+            "ADC resolution must be 1 to 16 bits"
+        );
+        Divider {
+            topology: Topology::PullUp,
+            fixed_resistance,
+            series_resistance: 0.,
+            adc_bits,
+            adc_offset: 0,
+        }
+    }
+
+    pub const fn pull_down(fixed_resistance: f32, adc_bits: u8) -> Divider {
+        Divider {
+            topology: Topology::PullDown,
+            ..Divider::pull_up(fixed_resistance, adc_bits)
+        }
+    }
+
+    pub const fn with_series_resistance(self, series_resistance: f32) -> Divider {
+        Divider {
+            series_resistance,
+            ..self
+        }
+    }
+
+    pub const fn with_adc_offset(self, adc_offset: i16) -> Divider {
+        Divider { adc_offset, ..self }
+    }
+
+    /// Highest reading of the ADC, i.e. its full scale
+    pub const fn full_scale(&self) -> u32 {
+        (1 << self.adc_bits) - 1
+    }
+
+    /// Resistance of the sensor, detecting a disconnected or shorted sensor from readings at the rails
+    pub fn resistance(&self, reading: u16) -> Result<f32, DividerError> {
+        let full_scale = self.full_scale() as i32;
+        let reading = (reading as i32 - self.adc_offset as i32).clamp(0, full_scale);
+
+        // The sensor takes the whole of the reference when open in a pull-up, and none of it in a pull-down
+        let (towards_sensor, away) = match self.topology {
+            Topology::PullUp => (reading, full_scale - reading),
+            Topology::PullDown => (full_scale - reading, reading),
+        };
+        if away == 0 {
+            return Err(DividerError::OpenCircuit);
+        }
+
+        let resistance =
+            self.fixed_resistance * towards_sensor as f32 / away as f32 - self.series_resistance;
+        if resistance <= 0. {
+            return Err(DividerError::ShortCircuit);
+        }
+
+        Ok(resistance)
+    }
+
+    pub fn get_temp_celsius<M: TemperatureModel>(
+        &self,
+        model: &M,
+        reading: u16,
+    ) -> Result<f32, DividerError> {
+        self.resistance(reading)
+            .map(|resistance| model.get_temp_celsius(resistance))
+    }
+}
@@ -0,0 +1,173 @@
+use crate::DividerError;
+
+/// Averages `samples` reads of the ADC, leaving out those which failed, or `None` if they all did
+pub fn oversample(samples: u8, mut read: impl FnMut() -> Option<u16>) -> Option<u16> {
+    let (sum, count) = (0..samples)
+        .filter_map(|_| read())
+        .fold((0u32, 0u32), |(sum, count), reading| {
+            (sum + reading as u32, count + 1)
+        });
+
+    (count != 0).then(|| ((sum + count / 2) / count) as u16)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Smoothing {
+    None,
+    /// Median of the last samples, which rejects spikes outright
+    Median,
+    /// Exponential moving average, with each new sample weighted by `alpha` in $(0, 1]$
+    Ema {
+        alpha: f32,
+    },
+}
+
+/// Bounds on what a sensor can plausibly read, e.g. from its datasheet and the thermal mass it's glued to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Plausibility {
+    pub min_celsius: f32,
+    pub max_celsius: f32,
+    /// Samples further from the last accepted one than this allows for are rejected as noise
+    pub max_rate_celsius_per_s: f32,
+    /// Change allowed on top of the rate, for the noise between samples taken in quick succession
+    pub noise_celsius: f32,
+    /// Consecutive rejections before the filter faults, rather than holding its last value
+    pub fault_after: u8,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FilterFault {
+    /// The sensor's readings were outside its plausible range
+    OutOfRange,
+    /// The sensor's readings changed faster than it plausibly could
+    TooFast,
+    Sensor(DividerError),
+    /// Nothing was accepted yet
+    NoReading,
+}
+
+/// Smooths temperatures, holding the last good value through the odd implausible sample
+/// and only faulting once they keep coming.
+///
+/// Keeps a window of `N` accepted samples for the median.
+pub struct TemperatureFilter<const N: usize> {
+    pub smoothing: Smoothing,
+    pub plausibility: Plausibility,
+    window: [f32; N],
+    len: usize,
+    next: usize,
+    output: Option<f32>,
+    /// Last accepted sample, and the time since it, in ms
+    last: Option<(f32, u32)>,
+    rejections: u8,
+    fault: Option<FilterFault>,
+}
+
+impl<const N: usize> TemperatureFilter<N> {
+    pub const fn new(smoothing: Smoothing, plausibility: Plausibility) -> TemperatureFilter<N> {
+        assert!(N >= 1);
+
+        TemperatureFilter {
+            smoothing,
+            plausibility,
+            window: [0.; N],
+            len: 0,
+            next: 0,
+            output: None,
+            last: None,
+            rejections: 0,
+            fault: None,
+        }
+    }
+
+    /// Feeds a sample taken `elapsed_ms` after the previous one, returning the filtered temperature
+    pub fn update(
+        &mut self,
+        sample: Result<f32, DividerError>,
+        elapsed_ms: u32,
+    ) -> Result<f32, FilterFault> {
+        if let Some((_, ref mut since)) = self.last {
+            *since = since.saturating_add(elapsed_ms);
+        }
+
+        match self.check(sample) {
+            Ok(celsius) => self.accept(celsius),
+            Err(fault) => self.reject(fault),
+        }
+
+        match (self.fault, self.output) {
+            (Some(fault), _) => Err(fault),
+            (None, Some(output)) => Ok(output),
+            (None, None) => Err(FilterFault::NoReading),
+        }
+    }
+
+    /// Drops the samples so far, e.g. after the sensor was replaced
+    pub fn reset(&mut self) {
+        *self = TemperatureFilter::new(self.smoothing, self.plausibility);
+    }
+
+    fn check(&self, sample: Result<f32, DividerError>) -> Result<f32, FilterFault> {
+        let celsius = sample.map_err(FilterFault::Sensor)?;
+        let bounds = &self.plausibility;
+
+        if !(bounds.min_celsius..=bounds.max_celsius).contains(&celsius) {
+            return Err(FilterFault::OutOfRange);
+        }
+
+        // Rates aren't checked against a faulted sensor, so it recovers from a genuine step in temperature
+        if let (Some((last, since)), None) = (self.last, self.fault) {
+            let allowed =
+                bounds.noise_celsius + bounds.max_rate_celsius_per_s * since as f32 / 1000.;
+            if (celsius - last).abs() > allowed {
+                return Err(FilterFault::TooFast);
+            }
+        }
+
+        Ok(celsius)
+    }
+
+    fn accept(&mut self, celsius: f32) {
+        self.rejections = 0;
+        self.last = Some((celsius, 0));
+
+        // A recovered sensor starts afresh, rather than being smoothed against what it read before faulting
+        if self.fault.take().is_some() {
+            self.len = 0;
+            self.next = 0;
+            self.output = None;
+        }
+
+        self.window[self.next] = celsius;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+
+        self.output = Some(match (self.smoothing, self.output) {
+            (Smoothing::None, _) => celsius,
+            (Smoothing::Median, _) => self.median(),
+            (Smoothing::Ema { alpha }, Some(output)) => output + alpha * (celsius - output),
+            (Smoothing::Ema { .. }, None) => celsius,
+        });
+    }
+
+    fn reject(&mut self, fault: FilterFault) {
+        self.rejections = self.rejections.saturating_add(1);
+        if self.rejections >= self.plausibility.fault_after {
+            self.fault = Some(fault);
+        }
+    }
+
+    fn median(&self) -> f32 {
+        let mut sorted = self.window;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable_by(f32::total_cmp);
+
+        let mid = self.len / 2;
+        if self.len.is_multiple_of(2) {
+            (sorted[mid - 1] + sorted[mid]) / 2.
+        } else {
+            sorted[mid]
+        }
+    }
+}
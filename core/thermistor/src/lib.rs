@@ -1,8 +1,30 @@
 #![no_std]
 
+mod divider;
+mod filter;
+mod fixed;
+mod rtd;
+mod steinhart_hart;
+mod table;
 #[cfg(test)]
 mod tests;
 
+pub use divider::{Divider, DividerError, Topology};
+pub use filter::{oversample, FilterFault, Plausibility, Smoothing, TemperatureFilter};
+pub use fixed::{FixedTable, Q16_ONE};
+pub use rtd::{LinearPtc, Rtd, KTY81_210, KTY84_130, PT100, PT1000};
+pub use steinhart_hart::SteinhartHart;
+pub use table::{TablePoint, TableThermistor};
+
+/// Converts a sensor's resistance to its temperature, so boards can swap between models
+pub trait TemperatureModel {
+    fn get_temp_kelvin(&self, resistance: f32) -> f32;
+
+    fn get_temp_celsius(&self, resistance: f32) -> f32 {
+        self.get_temp_kelvin(resistance) - 273.15
+    }
+}
+
 /// Pre-filled for the ERT-J??G line with $\beta_{25/85}$
 /// https://industrial.panasonic.com/cdbs/www-data/pdf/AUA0000/AUA0000C8.pdf
 pub const ERT_J1VGXXA: NtcThermistor = NtcThermistor::new_celsius(1e4, 25., 3435.);
@@ -14,7 +36,9 @@ pub const ERT_J1VGXXA: NtcThermistor = NtcThermistor::new_celsius(1e4, 25., 3435
 // I added this for debugging because I have a handful of spares tinkering with an Ender 3 clone
 pub const EPCOS_100K: NtcThermistor = NtcThermistor::new_celsius(1e5, 25., 4072.);
 
-/// Soft-float compatible naive thermistor calculator
+/// Beta equation model, which calls `logf` on every conversion.
+///
+/// See [`FixedTable`] to evaluate it without floats, on MCUs without an FPU
 pub struct NtcThermistor {
     pub ref_resistance: f32,
     pub ref_temp_kelvin: f32,
@@ -29,13 +53,9 @@ impl NtcThermistor {
             beta,
         }
     }
-
-    pub fn get_temp_celsius(&self, resistance: f32) -> f32 {
-        self.get_temp_kelvin(resistance) - 273.15
-    }
 }
 
-impl NtcThermistor {
+impl TemperatureModel for NtcThermistor {
     /// $$
     /// \beta = \frac{ln(\frac{R_{ref}}{R_{measured}})}{T_{ref}^{-1} - T_{measured}^{-1}}
     /// $$
@@ -44,7 +64,7 @@ impl NtcThermistor {
     /// \therefore
     /// T =\frac{1}{\frac{1}{T_{ref}} + \frac{1}{\beta}ln(\frac{R_{ref}}{R})}
     /// $$
-    pub fn get_temp_kelvin(&self, resistance: f32) -> f32 {
+    fn get_temp_kelvin(&self, resistance: f32) -> f32 {
         // Written in prefix notation like the LaTeX
         let log_res = ln(self.ref_resistance / resistance);
         inv(inv(self.ref_temp_kelvin) + (inv(self.beta) * log_res))
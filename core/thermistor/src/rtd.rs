@@ -0,0 +1,129 @@
+use crate::TemperatureModel;
+
+/// Platinum RTDs to IEC 60751, with $R_0 = 100\Omega$
+pub const PT100: Rtd = Rtd::iec_60751(100.);
+
+/// Platinum RTDs to IEC 60751, with $R_0 = 1000\Omega$
+pub const PT1000: Rtd = Rtd::iec_60751(1000.);
+
+/// Silicon PTC with $R_{25} = 2000\Omega$
+/// https://www.nxp.com/docs/en/data-sheet/KTY81_SER.pdf
+pub const KTY81_210: LinearPtc = LinearPtc::new_celsius(2000., 25., 7.88e-3, 1.937e-5);
+
+/// Silicon PTC with $R_{100} = 1000\Omega$, common in motor windings
+/// https://www.nxp.com/docs/en/data-sheet/KTY84_SER.pdf
+pub const KTY84_130: LinearPtc = LinearPtc::new_celsius(1000., 100., 6.12e-3, 1.1e-5);
+
+/// Resistance temperature detector, following the Callendar–Van Dusen equation
+///
+/// $$
+/// R_T = R_0(1 + AT + BT^2 + C(T - 100)T^3)
+/// $$
+///
+/// Where $C$ only applies below 0°C
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rtd {
+    pub r0: f32,
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+}
+
+impl Rtd {
+    pub const fn iec_60751(r0: f32) -> Rtd {
+        Rtd {
+            r0,
+            a: 3.9083e-3,
+            b: -5.775e-7,
+            c: -4.183e-12,
+        }
+    }
+
+    pub fn get_resistance(&self, celsius: f32) -> f32 {
+        let t = celsius;
+        let c = if t < 0. { self.c } else { 0. };
+        self.r0 * (1. + self.a * t + self.b * t * t + c * (t - 100.) * t * t * t)
+    }
+}
+
+impl TemperatureModel for Rtd {
+    fn get_temp_kelvin(&self, resistance: f32) -> f32 {
+        self.get_temp_celsius(resistance) + 273.15
+    }
+
+    /// Above 0°C the equation is a quadratic with a closed form solution,
+    /// $$
+    /// T = \frac{-A + \sqrt{A^2 - 4B(1 - \frac{R}{R_0})}}{2B}
+    /// $$
+    /// Below, that's refined by Newton's method to account for $C$
+    fn get_temp_celsius(&self, resistance: f32) -> f32 {
+        let ratio = resistance / self.r0;
+        let mut t =
+            (-self.a + libm::sqrtf(self.a * self.a - 4. * self.b * (1. - ratio))) / (2. * self.b);
+
+        if ratio < 1. {
+            // Converges within a few iterations, as C is tiny
+            for _ in 0..4 {
+                let error = self.get_resistance(t) / self.r0 - ratio;
+                let slope = self.a + 2. * self.b * t + self.c * (4. * t - 300.) * t * t;
+                t -= error / slope;
+            }
+        }
+
+        t
+    }
+}
+
+/// Silicon PTC such as the KTY series, which is near linear over its range
+///
+/// $$
+/// R_T = R_{ref}(1 + \alpha(T - T_{ref}) + \beta(T - T_{ref})^2)
+/// $$
+///
+/// Sensors specified only by $\alpha$ take $\beta = 0$
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinearPtc {
+    pub ref_resistance: f32,
+    pub ref_temp_celsius: f32,
+    pub alpha: f32,
+    pub beta: f32,
+}
+
+impl LinearPtc {
+    pub const fn new_celsius(
+        ref_resistance: f32,
+        ref_temp_celsius: f32,
+        alpha: f32,
+        beta: f32,
+    ) -> LinearPtc {
+        LinearPtc {
+            ref_resistance,
+            ref_temp_celsius,
+            alpha,
+            beta,
+        }
+    }
+
+    pub fn get_resistance(&self, celsius: f32) -> f32 {
+        let dt = celsius - self.ref_temp_celsius;
+        self.ref_resistance * (1. + self.alpha * dt + self.beta * dt * dt)
+    }
+}
+
+impl TemperatureModel for LinearPtc {
+    fn get_temp_kelvin(&self, resistance: f32) -> f32 {
+        self.get_temp_celsius(resistance) + 273.15
+    }
+
+    fn get_temp_celsius(&self, resistance: f32) -> f32 {
+        let excess = resistance / self.ref_resistance - 1.;
+        let dt = if self.beta == 0. {
+            excess / self.alpha
+        } else {
+            (-self.alpha + libm::sqrtf(self.alpha * self.alpha + 4. * self.beta * excess))
+                / (2. * self.beta)
+        };
+
+        self.ref_temp_celsius + dt
+    }
+}
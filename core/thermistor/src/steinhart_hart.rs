@@ -0,0 +1,61 @@
+use crate::TemperatureModel;
+
+/// Steinhart–Hart equation, which holds over a much wider range than the beta equation
+///
+/// $$
+/// \frac{1}{T} = A + B\ln(R) + C\ln(R)^3
+/// $$
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SteinhartHart {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+}
+
+impl SteinhartHart {
+    pub const fn new(a: f32, b: f32, c: f32) -> SteinhartHart {
+        SteinhartHart { a, b, c }
+    }
+
+    /// Solves the coefficients from three `(resistance, celsius)` points, e.g. read off a datasheet's R/T table.
+    ///
+    /// Points should span the range of interest, such as its ends and middle. With
+    /// $L_i = \ln(R_i)$, $Y_i = T_i^{-1}$, $\gamma_2 = \frac{Y_2 - Y_1}{L_2 - L_1}$ and $\gamma_3 = \frac{Y_3 - Y_1}{L_3 - L_1}$,
+    /// $$
+    /// C = \frac{\gamma_3 - \gamma_2}{(L_3 - L_2)(L_1 + L_2 + L_3)}
+    /// \qquad
+    /// B = \gamma_2 - C(L_1^2 + L_1 L_2 + L_2^2)
+    /// \qquad
+    /// A = Y_1 - (B + C L_1^2) L_1
+    /// $$
+    // Solved in f64, as the cubic term cancels out badly in f32
+    pub fn from_calibration(points: [(f32, f32); 3]) -> SteinhartHart {
+        let [(r_1, t_1), (r_2, t_2), (r_3, t_3)] = points;
+        let (l_1, l_2, l_3) = (ln(r_1), ln(r_2), ln(r_3));
+        let (y_1, y_2, y_3) = (inv_kelvin(t_1), inv_kelvin(t_2), inv_kelvin(t_3));
+
+        let gamma_2 = (y_2 - y_1) / (l_2 - l_1);
+        let gamma_3 = (y_3 - y_1) / (l_3 - l_1);
+
+        let c = (gamma_3 - gamma_2) / ((l_3 - l_2) * (l_1 + l_2 + l_3));
+        let b = gamma_2 - c * (l_1 * l_1 + l_1 * l_2 + l_2 * l_2);
+        let a = y_1 - (b + c * l_1 * l_1) * l_1;
+
+        SteinhartHart::new(a as f32, b as f32, c as f32)
+    }
+}
+
+impl TemperatureModel for SteinhartHart {
+    fn get_temp_kelvin(&self, resistance: f32) -> f32 {
+        let log_res = libm::logf(resistance);
+        (self.a + self.b * log_res + self.c * log_res * log_res * log_res).recip()
+    }
+}
+
+fn ln(resistance: f32) -> f64 {
+    libm::log(resistance as f64)
+}
+
+fn inv_kelvin(celsius: f32) -> f64 {
+    (celsius as f64 + 273.15).recip()
+}
@@ -0,0 +1,63 @@
+use crate::TemperatureModel;
+
+/// A row of a datasheet's R/T table
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TablePoint {
+    pub resistance: f32,
+    pub celsius: f32,
+}
+
+impl TablePoint {
+    pub const fn new(resistance: f32, celsius: f32) -> TablePoint {
+        TablePoint {
+            resistance,
+            celsius,
+        }
+    }
+}
+
+/// Interpolates linearly between the rows of a datasheet's R/T table.
+///
+/// Rows must be ordered by temperature, either way, and resistances beyond the table are clamped to its ends
+/// rather than extrapolated. Works for PTCs as well as NTCs, as only monotonicity is assumed.
+#[derive(Clone, Copy, Debug)]
+pub struct TableThermistor<'a> {
+    pub points: &'a [TablePoint],
+}
+
+impl<'a> TableThermistor<'a> {
+    pub const fn new(points: &'a [TablePoint]) -> TableThermistor<'a> {
+        TableThermistor { points }
+    }
+}
+
+impl TemperatureModel for TableThermistor<'_> {
+    fn get_temp_kelvin(&self, resistance: f32) -> f32 {
+        self.get_temp_celsius(resistance) + 273.15
+    }
+
+    fn get_temp_celsius(&self, resistance: f32) -> f32 {
+        let (first, last) = match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return f32::NAN,
+        };
+
+        // Resistance rises along the table for PTCs, and falls for NTCs
+        let rising = last.resistance > first.resistance;
+        let before = |point: &TablePoint| (resistance < point.resistance) == rising;
+
+        if before(first) {
+            return first.celsius;
+        }
+
+        for pair in self.points.windows(2) {
+            let (lo, hi) = (pair[0], pair[1]);
+            if before(&hi) || resistance == hi.resistance {
+                let fraction = (resistance - lo.resistance) / (hi.resistance - lo.resistance);
+                return lo.celsius + fraction * (hi.celsius - lo.celsius);
+            }
+        }
+
+        last.celsius
+    }
+}
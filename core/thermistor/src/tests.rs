@@ -24,7 +24,7 @@ macro_rules! assert_f32_eq {
 // The datasheet's Page 5 table uses 3375 as $\beta_{25/50}$ and 3435 as $\beta_{25/85}$ but
 // Page 4 with part ID uses 3380 as $\beta_{25/50}$
 mod ert_j1vgxxa {
-    use crate::{NtcThermistor, ERT_J1VGXXA};
+    use crate::{NtcThermistor, TemperatureModel, ERT_J1VGXXA};
 
     #[test]
     fn beta_25_50() {
@@ -41,3 +41,377 @@ mod ert_j1vgxxa {
         assert_f32_eq!(0.1, 85., thermistor.get_temp_celsius(1e4 / 0.1451));
     }
 }
+
+mod steinhart_hart {
+    use crate::{SteinhartHart, TemperatureModel, EPCOS_100K};
+
+    #[test]
+    fn reproduces_calibration_points() {
+        let points = [(1e5 * 3.3, 0.), (1e5, 25.), (1e5 / 12.5, 100.)];
+        let thermistor = SteinhartHart::from_calibration(points);
+
+        for (resistance, celsius) in points {
+            assert_f32_eq!(0.05, celsius, thermistor.get_temp_celsius(resistance));
+        }
+    }
+
+    /// A beta curve has no cubic term, so Steinhart–Hart fitted to it should follow it between the points
+    #[test]
+    fn follows_beta_curve() {
+        let beta = EPCOS_100K;
+        let resistance = |celsius: f32| {
+            let kelvin = celsius + 273.15;
+            beta.ref_resistance * libm::expf(beta.beta * (1. / kelvin - 1. / beta.ref_temp_kelvin))
+        };
+        let thermistor = SteinhartHart::from_calibration([
+            (resistance(0.), 0.),
+            (resistance(50.), 50.),
+            (resistance(150.), 150.),
+        ]);
+
+        for celsius in [10., 25., 80., 120.] {
+            assert_f32_eq!(0.1, celsius, thermistor.get_temp_celsius(resistance(celsius)));
+        }
+    }
+}
+
+mod table {
+    use crate::{TablePoint, TableThermistor, TemperatureModel};
+
+    const NTC: TableThermistor = TableThermistor::new(&[
+        TablePoint::new(27_280., 0.),
+        TablePoint::new(10_000., 25.),
+        TablePoint::new(4_160., 50.),
+    ]);
+
+    const PTC: TableThermistor = TableThermistor::new(&[
+        TablePoint::new(1_000., 0.),
+        TablePoint::new(1_385., 100.),
+    ]);
+
+    #[test]
+    fn exact_rows() {
+        assert_f32_eq!(0.001, 0., NTC.get_temp_celsius(27_280.));
+        assert_f32_eq!(0.001, 25., NTC.get_temp_celsius(10_000.));
+        assert_f32_eq!(0.001, 50., NTC.get_temp_celsius(4_160.));
+    }
+
+    #[test]
+    fn interpolates_ntc() {
+        assert_f32_eq!(0.001, 37.5, NTC.get_temp_celsius(7_080.));
+        assert_f32_eq!(0.001, 310.65, NTC.get_temp_kelvin(7_080.));
+    }
+
+    #[test]
+    fn interpolates_ptc() {
+        assert_f32_eq!(0.001, 50., PTC.get_temp_celsius(1_192.5));
+    }
+
+    #[test]
+    fn clamps_beyond_table() {
+        assert_f32_eq!(0.001, 0., NTC.get_temp_celsius(40_000.));
+        assert_f32_eq!(0.001, 50., NTC.get_temp_celsius(1_000.));
+        assert_f32_eq!(0.001, 100., PTC.get_temp_celsius(2_000.));
+    }
+
+    #[test]
+    fn empty_table() {
+        assert!(TableThermistor::new(&[]).get_temp_celsius(1e4).is_nan());
+    }
+}
+
+mod divider {
+    use crate::{Divider, DividerError, ERT_J1VGXXA};
+
+    const PULL_UP: Divider = Divider::pull_up(4700., 12);
+
+    /// A count either side of the midpoint is worth 0.1% of the fixed resistor at 12 bits, and 0.4% at 10 bits
+    #[test]
+    fn pull_up_midpoint() {
+        assert_f32_eq!(5., 4700., PULL_UP.resistance(2048).unwrap());
+    }
+
+    #[test]
+    fn pull_down_midpoint() {
+        let divider = Divider::pull_down(1e4, 10);
+        assert_f32_eq!(40., 1e4, divider.resistance(512).unwrap());
+        assert!(divider.resistance(700).unwrap() < 1e4);
+    }
+
+    #[test]
+    fn full_scale_is_open() {
+        assert_eq!(PULL_UP.resistance(4095), Err(DividerError::OpenCircuit));
+        assert_eq!(
+            Divider::pull_down(1e4, 12).resistance(0),
+            Err(DividerError::OpenCircuit)
+        );
+    }
+
+    #[test]
+    fn zero_is_short() {
+        assert_eq!(PULL_UP.resistance(0), Err(DividerError::ShortCircuit));
+        assert_eq!(
+            Divider::pull_down(1e4, 12).resistance(4095),
+            Err(DividerError::ShortCircuit)
+        );
+    }
+
+    #[test]
+    fn series_resistance_is_removed() {
+        let divider = PULL_UP.with_series_resistance(100.);
+        assert_f32_eq!(5., 4600., divider.resistance(2048).unwrap());
+        // What's left is within the series resistor, so the sensor itself is shorted
+        assert_eq!(divider.resistance(80), Err(DividerError::ShortCircuit));
+    }
+
+    #[test]
+    fn offset_is_subtracted() {
+        let divider = PULL_UP.with_adc_offset(10);
+        assert_eq!(divider.resistance(2058), PULL_UP.resistance(2048));
+        assert_eq!(divider.resistance(5), Err(DividerError::ShortCircuit));
+    }
+
+    #[test]
+    #[should_panic]
+    fn adc_bits_beyond_readings() {
+        Divider::pull_down(1e4, 32);
+    }
+
+    #[test]
+    fn temperature_at_reference() {
+        // 10k against 4.7k reads 10/14.7 of full scale
+        let temp = PULL_UP.get_temp_celsius(&ERT_J1VGXXA, 2786).unwrap();
+        assert_f32_eq!(0.1, 25., temp);
+    }
+}
+
+mod fixed {
+    use crate::{Divider, DividerError, FixedTable, Q16_ONE, ERT_J1VGXXA};
+
+    const DIVIDER: Divider = Divider::pull_up(4700., 12);
+    const TABLE_64: FixedTable<64> = FixedTable::new(&ERT_J1VGXXA, &DIVIDER);
+    const TABLE_256: FixedTable<256> = FixedTable::new(&ERT_J1VGXXA, &DIVIDER);
+
+    /// Worst error in °C against the float path, over the readings between `lo_c` and `hi_c`
+    fn max_error<const N: usize>(table: &FixedTable<N>, lo_c: f32, hi_c: f32) -> f32 {
+        let mut worst = 0f32;
+        for reading in 1..4095 {
+            let expected = DIVIDER.get_temp_celsius(&ERT_J1VGXXA, reading).unwrap();
+            if expected < lo_c || expected > hi_c {
+                continue;
+            }
+
+            let actual = table.get_temp_celsius_q16(reading).unwrap() as f32 / Q16_ONE as f32;
+            worst = worst.max((actual - expected).abs());
+        }
+        worst
+    }
+
+    /// A reading is worth far more near the rails, where the curve is steepest, so the bounds
+    /// are much looser at the cold end of a pull-up
+    #[test]
+    fn error_bound_64() {
+        assert!(max_error(&TABLE_64, 0., 85.) < 0.3);
+        assert!(max_error(&TABLE_64, -20., 120.) < 2.5);
+    }
+
+    #[test]
+    fn error_bound_256() {
+        assert!(max_error(&TABLE_256, 0., 85.) < 0.025);
+        assert!(max_error(&TABLE_256, -20., 120.) < 0.15);
+        assert!(max_error(&TABLE_256, -40., 150.) < 0.4);
+    }
+
+    #[test]
+    fn temperature_at_reference() {
+        let temp = TABLE_256.get_temp_celsius_q16(2786).unwrap();
+        assert_f32_eq!(0.1, 25., temp as f32 / Q16_ONE as f32);
+    }
+
+    #[test]
+    fn faults_match_divider() {
+        assert_eq!(TABLE_64.get_temp_celsius_q16(4095), Err(DividerError::OpenCircuit));
+        assert_eq!(TABLE_64.get_temp_celsius_q16(0), Err(DividerError::ShortCircuit));
+
+        let divider = Divider::pull_down(1e4, 12).with_series_resistance(100.);
+        let table = FixedTable::<64>::new(&ERT_J1VGXXA, &divider);
+        for reading in [0, 1, 4000, 4060, 4095] {
+            assert_eq!(
+                table.get_temp_celsius_q16(reading).err(),
+                divider.resistance(reading).err()
+            );
+        }
+    }
+}
+
+mod filter {
+    use crate::{
+        oversample, DividerError, FilterFault, Plausibility, Smoothing, TemperatureFilter,
+    };
+
+    const PLAUSIBLE: Plausibility = Plausibility {
+        min_celsius: -20.,
+        max_celsius: 120.,
+        max_rate_celsius_per_s: 2.,
+        noise_celsius: 0.5,
+        fault_after: 3,
+    };
+
+    #[test]
+    fn oversample_averages() {
+        let mut readings = [100, 102, 104, 106].into_iter();
+        assert_eq!(oversample(4, || readings.next()), Some(103));
+    }
+
+    #[test]
+    fn oversample_skips_failures() {
+        let mut readings = [Some(100), None, Some(110), None].into_iter();
+        assert_eq!(oversample(4, || readings.next().flatten()), Some(105));
+        assert_eq!(oversample(4, || None), None);
+    }
+
+    #[test]
+    fn median_rejects_spike() {
+        let mut filter = TemperatureFilter::<5>::new(Smoothing::Median, PLAUSIBLE);
+        let relaxed = Plausibility {
+            max_rate_celsius_per_s: 1000.,
+            ..PLAUSIBLE
+        };
+        filter.plausibility = relaxed;
+
+        for celsius in [25., 25.5, 60., 25.2] {
+            filter.update(Ok(celsius), 1000).unwrap();
+        }
+        assert_f32_eq!(0.001, 25.4, filter.update(Ok(25.4), 1000).unwrap());
+    }
+
+    #[test]
+    fn ema_converges() {
+        let mut filter = TemperatureFilter::<1>::new(Smoothing::Ema { alpha: 0.5 }, PLAUSIBLE);
+
+        assert_f32_eq!(0.001, 20., filter.update(Ok(20.), 1000).unwrap());
+        assert_f32_eq!(0.001, 21., filter.update(Ok(22.), 1000).unwrap());
+        assert_f32_eq!(0.001, 21.5, filter.update(Ok(22.), 1000).unwrap());
+    }
+
+    #[test]
+    fn single_bad_sample_holds() {
+        let mut filter = TemperatureFilter::<1>::new(Smoothing::None, PLAUSIBLE);
+        filter.update(Ok(30.), 1000).unwrap();
+
+        assert_eq!(filter.update(Ok(90.), 1000), Ok(30.));
+        assert_eq!(filter.update(Err(DividerError::OpenCircuit), 1000), Ok(30.));
+        assert_eq!(filter.update(Ok(31.), 1000), Ok(31.));
+    }
+
+    #[test]
+    fn rate_allows_for_elapsed_time() {
+        let mut filter = TemperatureFilter::<1>::new(Smoothing::None, PLAUSIBLE);
+        filter.update(Ok(30.), 1000).unwrap();
+
+        // Rejected at first, but 4C is within the rate once 2s have passed since the last good sample
+        assert_eq!(filter.update(Ok(34.), 1000), Ok(30.));
+        assert_eq!(filter.update(Ok(34.), 1000), Ok(34.));
+        // Samples in quick succession are allowed the noise
+        assert_eq!(filter.update(Ok(34.4), 0), Ok(34.4));
+    }
+
+    #[test]
+    fn faults_after_consecutive_rejections() {
+        let mut filter = TemperatureFilter::<1>::new(Smoothing::None, PLAUSIBLE);
+        filter.update(Ok(30.), 1000).unwrap();
+
+        for _ in 0..2 {
+            assert!(filter.update(Ok(-40.), 1000).is_ok());
+        }
+        assert_eq!(filter.update(Ok(-40.), 1000), Err(FilterFault::OutOfRange));
+        // The latest cause is reported while faulted
+        assert_eq!(
+            filter.update(Err(DividerError::ShortCircuit), 1000),
+            Err(FilterFault::Sensor(DividerError::ShortCircuit))
+        );
+    }
+
+    #[test]
+    fn recovers_from_fault_afresh() {
+        let mut filter = TemperatureFilter::<1>::new(Smoothing::Ema { alpha: 0.1 }, PLAUSIBLE);
+        filter.update(Ok(30.), 1000).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(filter.update(Ok(80.), 10), Ok(30.));
+        }
+        assert_eq!(filter.update(Ok(80.), 10), Err(FilterFault::TooFast));
+        assert_eq!(filter.update(Ok(80.), 10), Ok(80.));
+    }
+
+    #[test]
+    fn no_reading_until_accepted() {
+        let mut filter = TemperatureFilter::<1>::new(Smoothing::None, PLAUSIBLE);
+        assert_eq!(filter.update(Ok(200.), 1000), Err(FilterFault::NoReading));
+    }
+}
+
+/// Reference values from the IEC 60751 tables
+mod rtd {
+    use crate::{Rtd, TemperatureModel, PT100, PT1000};
+
+    #[test]
+    fn pt100_table() {
+        for (celsius, resistance) in [
+            (-200., 18.52),
+            (-100., 60.26),
+            (-40., 84.27),
+            (0., 100.),
+            (100., 138.51),
+            (200., 175.86),
+            (500., 280.98),
+        ] {
+            assert_f32_eq!(0.01, resistance, PT100.get_resistance(celsius));
+            assert_f32_eq!(0.05, celsius, PT100.get_temp_celsius(resistance));
+        }
+    }
+
+    #[test]
+    fn pt1000_scales() {
+        assert_f32_eq!(0.05, 100., PT1000.get_temp_celsius(1385.1));
+        assert_f32_eq!(0.05, -100., PT1000.get_temp_celsius(602.6));
+    }
+
+    /// Without the C coefficient, the quadratic alone is off by about 1Ω at -200°C
+    #[test]
+    fn c_coefficient_applies_below_zero() {
+        let quadratic = Rtd { c: 0., ..PT100 };
+        assert!((quadratic.get_resistance(-200.) - PT100.get_resistance(-200.)).abs() > 0.1);
+        assert_eq!(quadratic.get_resistance(100.), PT100.get_resistance(100.));
+    }
+
+    #[test]
+    fn kelvin() {
+        assert_f32_eq!(0.05, 273.15, PT100.get_temp_kelvin(100.));
+    }
+}
+
+mod linear_ptc {
+    use crate::{LinearPtc, TemperatureModel, KTY81_210, KTY84_130};
+
+    #[test]
+    fn reference_point() {
+        assert_f32_eq!(0.01, 25., KTY81_210.get_temp_celsius(2000.));
+        assert_f32_eq!(0.01, 100., KTY84_130.get_temp_celsius(1000.));
+    }
+
+    #[test]
+    fn round_trip() {
+        for celsius in [-40., 0., 50., 100., 150.] {
+            let resistance = KTY81_210.get_resistance(celsius);
+            assert_f32_eq!(0.01, celsius, KTY81_210.get_temp_celsius(resistance));
+        }
+    }
+
+    #[test]
+    fn purely_linear() {
+        let ptc = LinearPtc::new_celsius(1000., 0., 4e-3, 0.);
+        assert_f32_eq!(0.01, 50., ptc.get_temp_celsius(1200.));
+        assert_f32_eq!(0.01, -25., ptc.get_temp_celsius(900.));
+    }
+}